@@ -1,16 +1,114 @@
-use std::time::Instant;
+use std::sync::Arc;
 
+use crossbeam::queue::SegQueue;
 use embedded_graphics::{
     framebuffer::{buffer_size, Framebuffer},
-    mono_font::{ascii::FONT_9X18, MonoTextStyle},
     pixelcolor::{
         raw::{LittleEndian, RawU16},
         Rgb565,
     },
     prelude::*,
-    primitives::{Circle, PrimitiveStyle, Rectangle},
-    text::Text,
+    primitives::Rectangle,
 };
+use fundsp::hacker::{shared, Shared};
+use log::{info, warn};
+use wmidi::{ControlFunction, Note, U7};
+
+use crate::clock::Transport;
+use crate::engine::{
+    effects::Effects,
+    filter::Filter,
+    lfo::{Lfo, NUM_LFOS},
+    tap::AudioTap,
+};
+use crate::midi::MidiPorts;
+use crate::preset::PresetSlot;
+use crate::recorder::Recorder;
+use crate::sample::SampleSlot;
+use crate::sequencer::Sequencer;
+use crate::state::{
+    error::ErrorScreen,
+    mode::{Mode, ModeScreen},
+    play::PlayScreen,
+    startup::StartupScreen,
+    Event, Machine,
+};
+
+/// Abstract input actions, these are produced by whatever is controlling the device (keyboard,
+/// buttons, MIDI, etc.) and consumed by the current screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionMessage {
+    X,
+    Y,
+    Up,
+    Down,
+    Left,
+    Right,
+    NoteOn(Note, U7),
+    NoteOff(Note),
+    ControlChange(ControlFunction, U7),
+}
+
+/// Envelope a new sound starts with.
+pub(crate) const DEFAULT_ATTACK: f64 = 0.1;
+pub(crate) const DEFAULT_DECAY: f64 = 0.2;
+pub(crate) const DEFAULT_SUSTAIN: f64 = 0.7;
+pub(crate) const DEFAULT_RELEASE: f64 = 0.5;
+
+/// State that is shared between the UI and the rest of the app.
+/// Each parameter is a `Shared` value so it can be read and written from any thread.
+#[derive(Clone)]
+pub struct State {
+    /// Attack time in seconds
+    pub attack: Shared<f64>,
+    /// Decay time in seconds
+    pub decay: Shared<f64>,
+    /// Sustain level from 0.0 to 1.0
+    pub sustain: Shared<f64>,
+    /// Release time in seconds
+    pub release: Shared<f64>,
+    /// Cutoff, resonance and mode of every voice's filter
+    pub filter: Filter,
+    /// Shape, rate and destination of each LFO
+    pub lfos: [Lfo; NUM_LFOS],
+    /// Order, bypass and settings of the effects after the voices
+    pub effects: Effects,
+    /// Tempo and transport position
+    pub transport: Transport,
+    /// MIDI ports chosen besides the keyboard
+    pub midi_ports: MidiPorts,
+    /// Step sequencer patterns and chain
+    pub sequencer: Sequencer,
+    /// Sample played by the voices in place of the oscillator
+    pub sample: SampleSlot,
+    /// The last moments of the engine's output, for the visualisers
+    pub tap: AudioTap,
+    /// Records the output, and the MIDI played into it, to files
+    pub recorder: Recorder,
+    /// The preset last loaded or saved, for comparing and reverting
+    pub preset: PresetSlot,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            attack: shared(DEFAULT_ATTACK),
+            decay: shared(DEFAULT_DECAY),
+            sustain: shared(DEFAULT_SUSTAIN),
+            release: shared(DEFAULT_RELEASE),
+            filter: Filter::default(),
+            lfos: Lfo::defaults(),
+            effects: Effects::default(),
+            transport: Transport::default(),
+            midi_ports: MidiPorts::default(),
+            sequencer: Sequencer::default(),
+            sample: SampleSlot::default(),
+            tap: AudioTap::default(),
+            recorder: Recorder::default(),
+            preset: PresetSlot::default(),
+        }
+    }
+}
 
 pub struct App {
     // TODO: Make this generic over the color type, and buffer size
@@ -18,10 +116,15 @@ pub struct App {
         Framebuffer<Rgb565, RawU16, LittleEndian, 320, 240, { buffer_size::<Rgb565>(320, 240) }>,
 
     bounding_box: Rectangle,
+
+    machine: Machine,
+    state: State,
+    actions: Arc<SegQueue<ActionMessage>>,
+    running: bool,
 }
 
 impl App {
-    pub fn new<'a, D>(display: &'a mut D) -> Self
+    pub fn new<D>(display: &mut D) -> Self
     where
         D: DrawTarget,
         D::Color: RgbColor + From<RawU16>,
@@ -38,15 +141,88 @@ impl App {
             { buffer_size::<Rgb565>(320, 240) },
         >::new();
 
+        let mut machine = Machine::Startup(StartupScreen::default());
+        machine.entry();
+
         Self {
             buffer,
             bounding_box,
+            machine,
+            state: State::default(),
+            actions: Arc::new(SegQueue::new()),
+            running: true,
+        }
+    }
+
+    /// Returns a handle to the shared state, cloning it shares the same underlying values.
+    pub fn state(&self) -> State {
+        self.state.clone()
+    }
+
+    /// Returns a handle to the input queue, so actions can be pushed from other threads.
+    pub fn actions(&self) -> Arc<SegQueue<ActionMessage>> {
+        Arc::clone(&self.actions)
+    }
+
+    /// Returns false once the state machine has received a `Quit` event.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Shows the error screen with the given message, e.g. when a subsystem fails to start.
+    pub fn fail(&mut self, message: impl Into<String>) {
+        self.handle(Event::Error(message.into()));
+    }
+
+    /// Updates the current screen with any queued actions and handles the event it returns.
+    pub fn update(&mut self) {
+        self.state.sample.drop_retired();
+        if let Some(event) = self.machine.update(&self.state, self.actions()) {
+            self.handle(event);
         }
     }
 
+    /// Transitions the state machine, calling the `exit` hook on the old screen and the `entry`
+    /// hook on the new one.
+    fn handle(&mut self, event: Event) {
+        let next = match (&self.machine, event) {
+            (_, Event::Quit) => {
+                self.running = false;
+                return;
+            }
+            (_, Event::Error(message)) => Machine::Error(ErrorScreen { message }),
+            (Machine::Startup(_), Event::Initialized) => Machine::Play(PlayScreen::default()),
+            (Machine::Play(_), Event::OpenModeMenu) => Machine::Mode(ModeScreen {
+                selected_mode: Mode::Play,
+            }),
+            (Machine::Compose(_), Event::OpenModeMenu) => Machine::Mode(ModeScreen {
+                selected_mode: Mode::Compose,
+            }),
+            (Machine::Edit(_), Event::OpenModeMenu) => Machine::Mode(ModeScreen {
+                selected_mode: Mode::Edit,
+            }),
+            (Machine::Visualise(_), Event::OpenModeMenu) => Machine::Mode(ModeScreen {
+                selected_mode: Mode::Visualise,
+            }),
+            (Machine::Presets(_), Event::OpenModeMenu) => Machine::Mode(ModeScreen {
+                selected_mode: Mode::Presets,
+            }),
+            (Machine::Mode(screen), Event::CloseModeMenu) => screen.selected_mode.into(),
+            (machine, event) => {
+                warn!("Ignoring event {:?} in state {}", event, machine);
+                return;
+            }
+        };
+
+        self.machine.exit();
+        info!("Transitioning from {} to {}", self.machine, next);
+        self.machine = next;
+        self.machine.entry();
+    }
+
     /// This function clears the buffer, draws to the buffer, and then fills the display with the buffer.
     /// This lets us draw everything at once to prevent flickering.
-    pub fn draw<'a, D>(&mut self, display: &'a mut D) -> Result<(), Box<dyn std::error::Error>>
+    pub fn draw<D>(&mut self, display: &mut D) -> Result<(), Box<dyn std::error::Error>>
     where
         D: DrawTarget,
         D::Color: RgbColor + From<RawU16>,
@@ -54,15 +230,8 @@ impl App {
         // Clear the buffer
         self.buffer.clear(Rgb565::BLACK).unwrap();
 
-        // Draw everything to the buffer
-
-        Circle::new(Point::new(100, 100), 30)
-            .into_styled(PrimitiveStyle::with_stroke(Rgb565::WHITE, 1))
-            .draw(&mut self.buffer)?;
-
-        let style = MonoTextStyle::new(&FONT_9X18, Rgb565::WHITE);
-
-        Text::new("Hello, world!", Point::new(10, 10), style).draw(&mut self.buffer)?;
+        // Draw the current screen to the buffer
+        self.machine.draw(&mut self.buffer, &self.state)?;
 
         // Convert the buffer to an iterator of colors
         let colors =
@@ -1,6 +1,15 @@
 use embedded_graphics::{geometry::Size, pixelcolor::Rgb565};
-use embedded_graphics_simulator::{OutputSettingsBuilder, SimulatorDisplay, Window};
-use synth_app::app::App;
+use embedded_graphics_simulator::{
+    sdl2::Keycode, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+};
+use synth_app::{
+    app::{ActionMessage, App},
+    clock::ClockService,
+    engine::Engine,
+    midi::MidiService,
+    recorder::RecorderService,
+    sequencer::SequencerService,
+};
 
 const WIDTH: u32 = 320;
 const HEIGHT: u32 = 240;
@@ -20,15 +29,65 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut app = App::new(&mut display);
 
-    loop {
+    // Keep the engine and MIDI service alive for as long as the app is running
+    let engine = match Engine::new(app.state()) {
+        Ok(engine) => Some(engine),
+        Err(e) => {
+            app.fail(format!("Audio error: {}", e));
+            None
+        }
+    };
+    let _midi = engine.as_ref().map(|engine| {
+        MidiService::start(
+            engine.voices(),
+            app.actions(),
+            app.state().transport,
+            app.state().recorder,
+            app.state().midi_ports,
+        )
+    });
+    let _clock = ClockService::start(app.state().transport, app.state().midi_ports);
+    let _recorder = RecorderService::start(app.state().recorder, app.state().transport);
+    let _sequencer = engine.as_ref().map(|engine| {
+        SequencerService::start(
+            app.state().sequencer,
+            app.state().transport,
+            engine.voices(),
+            app.state().midi_ports,
+        )
+    });
+    let actions = app.actions();
+
+    while app.is_running() {
         for e in window.events() {
             match e {
-                embedded_graphics_simulator::SimulatorEvent::Quit => return Ok(()),
+                SimulatorEvent::Quit => return Ok(()),
+                SimulatorEvent::KeyDown { keycode, .. } => {
+                    if let Some(action) = keycode_to_action(keycode) {
+                        actions.push(action);
+                    }
+                }
                 _ => {}
             }
         }
 
+        app.update();
         let _ = app.draw(&mut display);
         window.update(&display);
     }
+
+    Ok(())
+}
+
+/// Maps keys on the computer keyboard to the actions of the buttons on the device.
+fn keycode_to_action(keycode: Keycode) -> Option<ActionMessage> {
+    match keycode {
+        Keycode::X => Some(ActionMessage::X),
+        Keycode::Y | Keycode::Z => Some(ActionMessage::Y),
+        Keycode::Up => Some(ActionMessage::Up),
+        Keycode::Down => Some(ActionMessage::Down),
+        Keycode::Left => Some(ActionMessage::Left),
+        Keycode::Right => Some(ActionMessage::Right),
+        _ => None,
+    }
 }
@@ -1,17 +1,4 @@
 use display_interface_spi::SPIInterface;
-use embedded_graphics::{
-    framebuffer::{self, buffer_size},
-    mono_font::{ascii::FONT_9X18, MonoTextStyle},
-    pixelcolor::{
-        self,
-        raw::{LittleEndian, RawU16},
-        Rgb565,
-    },
-    prelude::*,
-    primitives::{Circle, PrimitiveStyle, Rectangle},
-    text::Text,
-    Drawable,
-};
 use ili9341::{DisplaySize240x320, Ili9341, Orientation};
 use rppal::gpio::Gpio;
 use rppal::spi::{Bus, SlaveSelect, Spi};
@@ -20,7 +7,10 @@ use std::sync::{
     Arc,
 };
 
-use synth_app::{app::App, spi::SpiWrapper};
+use synth_app::{
+    app::App, clock::ClockService, engine::Engine, midi::MidiService, recorder::RecorderService,
+    sequencer::SequencerService, spi::SpiWrapper,
+};
 
 use log::{info, warn};
 
@@ -60,7 +50,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut app = App::new(&mut display);
 
-    while !term.load(Ordering::Relaxed) {
+    // Keep the engine and MIDI service alive for as long as the app is running
+    let engine = match Engine::new(app.state()) {
+        Ok(engine) => Some(engine),
+        Err(e) => {
+            app.fail(format!("Audio error: {}", e));
+            None
+        }
+    };
+    let _midi = engine.as_ref().map(|engine| {
+        MidiService::start(
+            engine.voices(),
+            app.actions(),
+            app.state().transport,
+            app.state().recorder,
+            app.state().midi_ports,
+        )
+    });
+    let _clock = ClockService::start(app.state().transport, app.state().midi_ports);
+    let _recorder = RecorderService::start(app.state().recorder, app.state().transport);
+    let _sequencer = engine.as_ref().map(|engine| {
+        SequencerService::start(
+            app.state().sequencer,
+            app.state().transport,
+            engine.voices(),
+            app.state().midi_ports,
+        )
+    });
+
+    while !term.load(Ordering::Relaxed) && app.is_running() {
+        app.update();
         app.draw(&mut display)?;
     }
 
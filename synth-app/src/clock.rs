@@ -0,0 +1,353 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use fundsp::hacker::{shared, Shared};
+use wmidi::{MidiMessage, U14};
+
+use crate::midi::{MidiPorts, Output};
+
+/// MIDI clock pulses per quarter note.
+pub const PULSES_PER_BEAT: f64 = 24.0;
+pub const DEFAULT_TEMPO: f64 = 120.0;
+pub const MIN_TEMPO: f64 = 20.0;
+pub const MAX_TEMPO: f64 = 300.0;
+/// Song Position Pointer counts in sixteenth notes.
+const PULSES_PER_SIXTEENTH: u64 = 6;
+/// If no MIDI clock has been received for this long the internal clock takes over again.
+const EXTERNAL_TIMEOUT: Duration = Duration::from_millis(500);
+/// How much each pulse of an incoming clock moves the measured tempo, pulses are jittery so the
+/// tempo is averaged over a few of them.
+const TEMPO_SMOOTHING: f64 = 0.1;
+
+/// Tempo and transport, shared between the clock, the UI and anything that locks to the beat
+/// such as the sequencer, arpeggiator and LFOs.
+#[derive(Clone)]
+pub struct Transport {
+    /// Tempo in beats per minute, set from the UI or measured from incoming MIDI clock.
+    pub tempo: Shared<f64>,
+    /// Clock pulses since the start of the song, only moves while playing. Counting whole
+    /// pulses rather than adding up fractions of a beat keeps step boundaries exact.
+    pulses: Arc<AtomicU64>,
+    playing: Arc<AtomicBool>,
+    /// When the last MIDI clock pulse was received, while another device is sending clock the
+    /// internal clock stops and follows it instead.
+    last_external_pulse: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            tempo: shared(DEFAULT_TEMPO),
+            pulses: Arc::new(AtomicU64::new(0)),
+            playing: Arc::new(AtomicBool::new(false)),
+            last_external_pulse: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Transport {
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    /// Clock pulses since the start of the song.
+    pub fn pulses(&self) -> u64 {
+        self.pulses.load(Ordering::Relaxed)
+    }
+
+    /// Position in beats since the start of the song.
+    pub fn position(&self) -> f64 {
+        self.pulses() as f64 / PULSES_PER_BEAT
+    }
+
+    /// Goes back to the start of the song without playing.
+    pub fn rewind(&self) {
+        self.pulses.store(0, Ordering::Relaxed);
+    }
+
+    /// Plays from the start of the song.
+    pub fn start(&self) {
+        self.rewind();
+        self.playing.store(true, Ordering::Relaxed);
+    }
+
+    /// Plays from the current position.
+    pub fn resume(&self) {
+        self.playing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.playing.store(false, Ordering::Relaxed);
+    }
+
+    pub fn set_tempo(&self, tempo: f64) {
+        self.tempo.set_value(tempo.clamp(MIN_TEMPO, MAX_TEMPO));
+    }
+
+    /// Returns whether the tempo is coming from another device's MIDI clock.
+    pub fn is_external(&self) -> bool {
+        self.last_external_pulse
+            .lock()
+            .ok()
+            .and_then(|last| *last)
+            .is_some_and(|last| last.elapsed() < EXTERNAL_TIMEOUT)
+    }
+
+    /// Time between two clock pulses at the current tempo.
+    pub fn pulse_interval(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / (self.tempo.value().max(MIN_TEMPO) * PULSES_PER_BEAT))
+    }
+
+    /// Moves the position on by one clock pulse if playing.
+    fn pulse(&self) {
+        if self.is_playing() {
+            self.pulses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Follows MIDI clock and transport messages from another device.
+pub(crate) struct Follower {
+    transport: Transport,
+    last_pulse: Option<Instant>,
+}
+
+impl Follower {
+    pub(crate) fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            last_pulse: None,
+        }
+    }
+
+    pub(crate) fn handle(&mut self, message: &MidiMessage) {
+        self.handle_at(message, Instant::now())
+    }
+
+    /// Handles `message` as if it arrived at `now`.
+    fn handle_at(&mut self, message: &MidiMessage, now: Instant) {
+        match *message {
+            MidiMessage::TimingClock => {
+                if let Some(interval) = self.last_pulse.map(|last| now - last) {
+                    if interval < EXTERNAL_TIMEOUT && !interval.is_zero() {
+                        let tempo = 60.0 / (interval.as_secs_f64() * PULSES_PER_BEAT);
+                        let current = self.transport.tempo.value();
+                        self.transport
+                            .set_tempo(current + (tempo - current) * TEMPO_SMOOTHING);
+                    }
+                }
+                self.last_pulse = Some(now);
+                if let Ok(mut last) = self.transport.last_external_pulse.lock() {
+                    *last = Some(now);
+                }
+                self.transport.pulse();
+            }
+            MidiMessage::Start => self.transport.start(),
+            MidiMessage::Continue => self.transport.resume(),
+            MidiMessage::Stop => self.transport.stop(),
+            MidiMessage::SongPositionPointer(position) => self.transport.pulses.store(
+                u64::from(u16::from(position)) * PULSES_PER_SIXTEENTH,
+                Ordering::Relaxed,
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Background service that runs the internal clock, moving the transport on and sending MIDI
+/// clock and transport messages to the keyboard and the clock output chosen in `MidiPorts`. While another device is sending clock this
+/// service stays quiet and the `Follower` moves the transport instead.
+pub struct ClockService {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ClockService {
+    pub fn start(transport: Transport, ports: MidiPorts) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || run(running, transport, ports))
+        };
+
+        Self {
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for ClockService {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(running: Arc<AtomicBool>, transport: Transport, ports: MidiPorts) {
+    let mut outputs = [
+        Output::default(),
+        Output::chosen(ports, MidiPorts::clock_output),
+    ];
+    let mut was_playing = transport.is_playing();
+    let mut next_pulse = Instant::now();
+
+    while running.load(Ordering::Relaxed) {
+        outputs.iter_mut().for_each(Output::poll);
+
+        let playing = transport.is_playing();
+        if transport.is_external() {
+            was_playing = playing;
+            thread::sleep(transport.pulse_interval());
+            next_pulse = Instant::now();
+            continue;
+        }
+
+        if playing != was_playing {
+            if !playing {
+                send(&mut outputs, &MidiMessage::Stop);
+            } else if transport.pulses() == 0 {
+                send(&mut outputs, &MidiMessage::Start);
+            } else {
+                let sixteenths = transport.pulses() / PULSES_PER_SIXTEENTH;
+                let position = u16::try_from(sixteenths)
+                    .ok()
+                    .and_then(|sixteenths| U14::try_from(sixteenths).ok())
+                    .unwrap_or(U14::MAX);
+                send(&mut outputs, &MidiMessage::SongPositionPointer(position));
+                send(&mut outputs, &MidiMessage::Continue);
+            }
+            was_playing = playing;
+        }
+
+        send(&mut outputs, &MidiMessage::TimingClock);
+        transport.pulse();
+
+        // Pulses are scheduled from the last one rather than from now so the tempo doesn't
+        // drift, unless the clock fell so far behind it would have to catch up
+        let interval = transport.pulse_interval();
+        next_pulse += interval;
+        let now = Instant::now();
+        if next_pulse < now {
+            next_pulse = now + interval;
+        }
+        thread::sleep(next_pulse - now);
+    }
+}
+
+fn send(outputs: &mut [Output], message: &MidiMessage) {
+    for output in outputs {
+        output.send(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `count` clock pulses at `tempo` starting from `start`, returning when the next
+    /// one would arrive.
+    fn send_pulses(follower: &mut Follower, start: Instant, tempo: f64, count: u32) -> Instant {
+        let interval = Duration::from_secs_f64(60.0 / (tempo * PULSES_PER_BEAT));
+        let mut now = start;
+        for _ in 0..count {
+            follower.handle_at(&MidiMessage::TimingClock, now);
+            now += interval;
+        }
+        now
+    }
+
+    #[test]
+    fn follower_settles_on_the_incoming_tempo_within_two_beats() {
+        let transport = Transport::default();
+        let mut follower = Follower::new(transport.clone());
+        let start = Instant::now();
+
+        let now = send_pulses(&mut follower, start, 140.0, 12);
+        let tempo = transport.tempo.value();
+        assert!(tempo > DEFAULT_TEMPO && tempo < 140.0, "{tempo}");
+
+        send_pulses(&mut follower, now, 140.0, 36);
+        assert!((transport.tempo.value() - 140.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn follower_ignores_the_gap_before_the_clock_starts_again() {
+        let transport = Transport::default();
+        let mut follower = Follower::new(transport.clone());
+        let start = Instant::now();
+
+        let now = send_pulses(&mut follower, start, DEFAULT_TEMPO, 4);
+        send_pulses(
+            &mut follower,
+            now + Duration::from_secs(2),
+            DEFAULT_TEMPO,
+            1,
+        );
+        assert!((transport.tempo.value() - DEFAULT_TEMPO).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pulses_only_move_the_position_while_playing() {
+        let transport = Transport::default();
+        let mut follower = Follower::new(transport.clone());
+        let start = Instant::now();
+
+        send_pulses(&mut follower, start, DEFAULT_TEMPO, 24);
+        assert_eq!(transport.pulses(), 0);
+
+        follower.handle(&MidiMessage::Start);
+        send_pulses(&mut follower, start, DEFAULT_TEMPO, 24 * 64 * 4);
+        assert_eq!(transport.position(), 256.0);
+
+        follower.handle(&MidiMessage::Stop);
+        send_pulses(&mut follower, start, DEFAULT_TEMPO, 24);
+        assert_eq!(transport.position(), 256.0);
+    }
+
+    #[test]
+    fn song_position_pointer_counts_sixteenths() {
+        let transport = Transport::default();
+        let mut follower = Follower::new(transport.clone());
+
+        follower.handle(&MidiMessage::SongPositionPointer(U14::try_from(0).unwrap()));
+        assert_eq!(transport.position(), 0.0);
+        follower.handle(&MidiMessage::SongPositionPointer(U14::try_from(1).unwrap()));
+        assert_eq!(transport.pulses(), 6);
+        assert_eq!(transport.position(), 0.25);
+        follower.handle(&MidiMessage::SongPositionPointer(
+            U14::try_from(16).unwrap(),
+        ));
+        assert_eq!(transport.position(), 4.0);
+        follower.handle(&MidiMessage::SongPositionPointer(U14::MAX));
+        assert_eq!(transport.position(), 16383.0 / 4.0);
+
+        // Continue plays on from the pointer, Start goes back to the beginning
+        follower.handle(&MidiMessage::Continue);
+        assert_eq!(transport.position(), 16383.0 / 4.0);
+        follower.handle(&MidiMessage::Start);
+        assert_eq!(transport.position(), 0.0);
+    }
+
+    #[test]
+    fn external_clock_times_out() {
+        let transport = Transport::default();
+        assert!(!transport.is_external());
+
+        let mut follower = Follower::new(transport.clone());
+        follower.handle(&MidiMessage::TimingClock);
+        assert!(transport.is_external());
+
+        let late = Instant::now() - EXTERNAL_TIMEOUT - Duration::from_millis(10);
+        *transport.last_external_pulse.lock().unwrap() = Some(late);
+        assert!(!transport.is_external());
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    f64::consts::TAU,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use fundsp::{
+    hacker::{shared, AudioNode, Frame, Shared, U2},
+    DEFAULT_SR,
+};
+use serde::{Deserialize, Serialize};
+
+use super::lfo::DIVISIONS;
+use crate::clock::Transport;
+
+pub const NUM_SLOTS: usize = 4;
+/// Longest delay time, synced delays that would be longer are cut to this.
+pub const MAX_DELAY: f64 = 2.0;
+pub const MAX_FEEDBACK: f64 = 0.95;
+/// Bit depth that counts as not crushed at all.
+pub const MAX_BITS: f64 = 16.0;
+pub const MAX_DOWNSAMPLE: f64 = 32.0;
+/// Chorus and flanger delay lines are this long, enough for the longest sweep.
+const MAX_CHORUS_DELAY: f64 = 0.05;
+/// Centre of the sweep and how far it reaches either side at full depth, in seconds.
+const CHORUS_DELAY: (f64, f64) = (0.015, 0.01);
+const FLANGER_DELAY: (f64, f64) = (0.003, 0.0025);
+/// How quickly a change in delay time is followed, so turning the time doesn't crackle.
+const DELAY_SMOOTHING: f64 = 0.0005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EffectKind {
+    Drive = 0,
+    Chorus = 1,
+    Delay = 2,
+    Reverb = 3,
+}
+
+impl EffectKind {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => EffectKind::Chorus,
+            2 => EffectKind::Delay,
+            3 => EffectKind::Reverb,
+            _ => EffectKind::Drive,
+        }
+    }
+}
+
+impl fmt::Display for EffectKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EffectKind::Drive => write!(f, "Drive"),
+            EffectKind::Chorus => write!(f, "Chorus"),
+            EffectKind::Delay => write!(f, "Delay"),
+            EffectKind::Reverb => write!(f, "Reverb"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DelaySettings {
+    /// Delay time in seconds while not synced
+    pub time: Shared<f64>,
+    /// How much of each repeat is fed back, from 0.0 to `MAX_FEEDBACK`
+    pub feedback: Shared<f64>,
+    synced: Arc<AtomicBool>,
+    /// Index into `DIVISIONS` while synced to the tempo
+    division: Arc<AtomicU8>,
+}
+
+impl DelaySettings {
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::Relaxed)
+    }
+
+    pub fn set_synced(&self, synced: bool) {
+        self.synced.store(synced, Ordering::Relaxed);
+    }
+
+    pub fn division(&self) -> usize {
+        usize::from(self.division.load(Ordering::Relaxed)).min(DIVISIONS.len() - 1)
+    }
+
+    pub fn set_division(&self, division: usize) {
+        self.division
+            .store(division.min(DIVISIONS.len() - 1) as u8, Ordering::Relaxed);
+    }
+
+    /// Returns the delay time in seconds, worked out from the tempo while synced.
+    pub fn seconds(&self, tempo: f64) -> f64 {
+        let time = if self.is_synced() {
+            60.0 / tempo.max(1.0) * DIVISIONS[self.division()].0
+        } else {
+            self.time.value()
+        };
+        time.clamp(0.001, MAX_DELAY)
+    }
+}
+
+#[derive(Clone)]
+pub struct ReverbSettings {
+    /// Room size from 0.0 to 1.0, bigger rooms ring for longer
+    pub size: Shared<f64>,
+    /// How quickly high frequencies die away, from 0.0 to 1.0
+    pub damping: Shared<f64>,
+}
+
+#[derive(Clone)]
+pub struct ChorusSettings {
+    /// Sweep rate in Hz
+    pub rate: Shared<f64>,
+    /// How far the sweep reaches, from 0.0 to 1.0
+    pub depth: Shared<f64>,
+    /// From 0.0 to `MAX_FEEDBACK`, a flanger needs some to ring
+    pub feedback: Shared<f64>,
+    /// Whether to sweep a short delay as a flanger rather than a longer one as a chorus
+    flanger: Arc<AtomicBool>,
+}
+
+impl ChorusSettings {
+    pub fn is_flanger(&self) -> bool {
+        self.flanger.load(Ordering::Relaxed)
+    }
+
+    pub fn set_flanger(&self, flanger: bool) {
+        self.flanger.store(flanger, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct DriveSettings {
+    /// Saturation from 0.0 (clean) to 1.0
+    pub drive: Shared<f64>,
+    /// Bit depth the signal is crushed to, from 1.0 to `MAX_BITS`
+    pub bits: Shared<f64>,
+    /// Each sample is held for this many, from 1.0 to `MAX_DOWNSAMPLE`
+    pub downsample: Shared<f64>,
+}
+
+/// Settings for the effects rack after the voices, shared between the UI and the audio thread.
+/// Every effect is in the rack once, in the order of the slots, and can be bypassed.
+#[derive(Clone)]
+pub struct Effects {
+    /// The kind in each slot, a byte per slot so the order is changed in one store
+    order: Arc<AtomicU32>,
+    /// Indexed by `EffectKind`
+    bypassed: [Arc<AtomicBool>; NUM_SLOTS],
+    /// How much of each effect is heard over the dry signal, indexed by `EffectKind`
+    mix: [Shared<f64>; NUM_SLOTS],
+    pub delay: DelaySettings,
+    pub reverb: ReverbSettings,
+    pub chorus: ChorusSettings,
+    pub drive: DriveSettings,
+}
+
+impl Default for Effects {
+    fn default() -> Self {
+        let order = [
+            EffectKind::Drive,
+            EffectKind::Chorus,
+            EffectKind::Delay,
+            EffectKind::Reverb,
+        ];
+        Self {
+            order: Arc::new(AtomicU32::new(pack(order))),
+            bypassed: std::array::from_fn(|_| Arc::new(AtomicBool::new(true))),
+            mix: [shared(1.0), shared(0.5), shared(0.3), shared(0.3)],
+            delay: DelaySettings {
+                time: shared(0.375),
+                feedback: shared(0.4),
+                synced: Arc::new(AtomicBool::new(false)),
+                division: Arc::new(AtomicU8::new(1)),
+            },
+            reverb: ReverbSettings {
+                size: shared(0.6),
+                damping: shared(0.5),
+            },
+            chorus: ChorusSettings {
+                rate: shared(0.5),
+                depth: shared(0.5),
+                feedback: shared(0.0),
+                flanger: Arc::new(AtomicBool::new(false)),
+            },
+            drive: DriveSettings {
+                drive: shared(0.3),
+                bits: shared(MAX_BITS),
+                downsample: shared(1.0),
+            },
+        }
+    }
+}
+
+fn pack(order: [EffectKind; NUM_SLOTS]) -> u32 {
+    order.iter().enumerate().fold(0, |packed, (slot, kind)| {
+        packed | (*kind as u32) << (slot * 8)
+    })
+}
+
+impl Effects {
+    pub fn order(&self) -> [EffectKind; NUM_SLOTS] {
+        let packed = self.order.load(Ordering::Relaxed);
+        std::array::from_fn(|slot| EffectKind::from_u8((packed >> (slot * 8)) as u8))
+    }
+
+    /// Swaps the effect in `slot` with the one after it if `later`, otherwise the one before.
+    /// Returns the slot it moved to.
+    pub fn move_slot(&self, slot: usize, later: bool) -> usize {
+        let target = if later {
+            (slot + 1).min(NUM_SLOTS - 1)
+        } else {
+            slot.saturating_sub(1)
+        };
+        let mut order = self.order();
+        order.swap(slot, target);
+        self.order.store(pack(order), Ordering::Relaxed);
+        target
+    }
+
+    /// Puts the effects in `order`, unless it leaves an effect out or has one twice.
+    pub fn set_order(&self, order: [EffectKind; NUM_SLOTS]) {
+        let complete = order
+            .iter()
+            .all(|kind| order.iter().filter(|other| *other == kind).count() == 1);
+        if complete {
+            self.order.store(pack(order), Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_bypassed(&self, kind: EffectKind) -> bool {
+        self.bypassed[kind as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn set_bypassed(&self, kind: EffectKind, bypassed: bool) {
+        self.bypassed[kind as usize].store(bypassed, Ordering::Relaxed);
+    }
+
+    pub fn mix(&self, kind: EffectKind) -> &Shared<f64> {
+        &self.mix[kind as usize]
+    }
+}
+
+/// A delay line holding the last few seconds of one channel.
+#[derive(Clone)]
+struct DelayLine {
+    buffer: Vec<f64>,
+    write: usize,
+}
+
+impl DelayLine {
+    fn new(seconds: f64, sample_rate: f64) -> Self {
+        Self {
+            buffer: vec![0.0; (seconds * sample_rate) as usize + 2],
+            write: 0,
+        }
+    }
+
+    /// Returns the value written `delay` samples ago, interpolating between samples.
+    fn read(&self, delay: f64) -> f64 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1.0, (len - 2) as f64);
+        let position = self.write as f64 + len as f64 - delay;
+        let index = position.floor() as usize;
+        let fraction = position.fract();
+        let current = self.buffer[index % len];
+        let next = self.buffer[(index + 1) % len];
+        current + (next - current) * fraction
+    }
+
+    fn write(&mut self, value: f64) {
+        self.buffer[self.write] = value;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+}
+
+/// Freeverb comb filter with a damping low pass in its feedback loop.
+#[derive(Clone)]
+struct Comb {
+    buffer: Vec<f64>,
+    index: usize,
+    store: f64,
+}
+
+impl Comb {
+    fn tick(&mut self, input: f64, feedback: f64, damping: f64) -> f64 {
+        let output = self.buffer[self.index];
+        self.store = output * (1.0 - damping) + self.store * damping;
+        self.buffer[self.index] = input + self.store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+#[derive(Clone)]
+struct Allpass {
+    buffer: Vec<f64>,
+    index: usize,
+}
+
+impl Allpass {
+    fn tick(&mut self, input: f64) -> f64 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// Comb and allpass lengths of the Freeverb algorithm at 44.1 kHz, the right channel's are
+/// longer by `STEREO_SPREAD` so the two sides don't ring together.
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const REVERB_INPUT_GAIN: f64 = 0.015;
+const REVERB_OUTPUT_GAIN: f64 = 3.0;
+
+#[derive(Clone)]
+struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl Reverb {
+    fn new(sample_rate: f64) -> Self {
+        let scale = |length: usize| ((length as f64 * sample_rate / 44_100.0) as usize).max(1);
+        let channel = |spread: usize| {
+            let combs = COMB_LENGTHS
+                .iter()
+                .map(|length| Comb {
+                    buffer: vec![0.0; scale(length + spread)],
+                    index: 0,
+                    store: 0.0,
+                })
+                .collect();
+            let allpasses = ALLPASS_LENGTHS
+                .iter()
+                .map(|length| Allpass {
+                    buffer: vec![0.0; scale(length + spread)],
+                    index: 0,
+                })
+                .collect();
+            (combs, allpasses)
+        };
+        let (left_combs, left_allpasses) = channel(0);
+        let (right_combs, right_allpasses) = channel(STEREO_SPREAD);
+        Self {
+            combs: [left_combs, right_combs],
+            allpasses: [left_allpasses, right_allpasses],
+        }
+    }
+
+    fn tick(&mut self, input: (f64, f64), settings: &ReverbSettings) -> (f64, f64) {
+        let feedback = 0.7 + 0.28 * settings.size.value().clamp(0.0, 1.0);
+        let damping = 0.4 * settings.damping.value().clamp(0.0, 1.0);
+        let mono = (input.0 + input.1) * REVERB_INPUT_GAIN;
+        let mut output = [0.0; 2];
+        for (channel, output) in output.iter_mut().enumerate() {
+            let summed: f64 = self.combs[channel]
+                .iter_mut()
+                .map(|comb| comb.tick(mono, feedback, damping))
+                .sum();
+            *output = self.allpasses[channel]
+                .iter_mut()
+                .fold(summed, |signal, allpass| allpass.tick(signal))
+                * REVERB_OUTPUT_GAIN;
+        }
+        (output[0], output[1])
+    }
+}
+
+/// Every effect's running state. The effects are built by the engine, their settings come from
+/// `Effects` every sample so changes are heard straight away.
+#[derive(Clone)]
+pub(crate) struct EffectsRack {
+    effects: Effects,
+    transport: Transport,
+    sample_rate: f64,
+    delay: [DelayLine; 2],
+    /// Delay time in samples, following the setting slowly.
+    delay_time: f64,
+    reverb: Reverb,
+    chorus: [DelayLine; 2],
+    chorus_phase: f64,
+    /// The crushed sample being held and how many more samples to hold it for.
+    held: (f64, f64),
+    hold: f64,
+}
+
+impl EffectsRack {
+    pub(crate) fn new(effects: &Effects, transport: &Transport) -> Self {
+        let mut rack = Self {
+            effects: effects.clone(),
+            transport: transport.clone(),
+            sample_rate: DEFAULT_SR,
+            delay: [DelayLine::new(0.0, 0.0), DelayLine::new(0.0, 0.0)],
+            delay_time: 0.0,
+            reverb: Reverb::new(DEFAULT_SR),
+            chorus: [DelayLine::new(0.0, 0.0), DelayLine::new(0.0, 0.0)],
+            chorus_phase: 0.0,
+            held: (0.0, 0.0),
+            hold: 0.0,
+        };
+        rack.allocate();
+        rack
+    }
+
+    /// Sizes the delay lines for the sample rate, clearing them.
+    fn allocate(&mut self) {
+        self.delay = std::array::from_fn(|_| DelayLine::new(MAX_DELAY, self.sample_rate));
+        self.delay_time =
+            self.effects.delay.seconds(self.transport.tempo.value()) * self.sample_rate;
+        self.reverb = Reverb::new(self.sample_rate);
+        self.chorus = std::array::from_fn(|_| DelayLine::new(MAX_CHORUS_DELAY, self.sample_rate));
+    }
+
+    fn drive(&mut self, input: (f64, f64)) -> (f64, f64) {
+        let settings = &self.effects.drive;
+        let drive = settings.drive.value().clamp(0.0, 1.0);
+        let gain = 1.0 + drive * 20.0;
+        // Fading into the saturated signal by the drive keeps no drive clean
+        let saturate = |x: f64| x + ((x * gain).tanh() / gain.tanh() - x) * drive;
+        let driven = (saturate(input.0), saturate(input.1));
+
+        // Sample rate reduction holds each sample for a while, then it is crushed to fewer bits
+        if self.hold <= 0.0 {
+            self.held = driven;
+            self.hold += settings.downsample.value().clamp(1.0, MAX_DOWNSAMPLE);
+        }
+        self.hold -= 1.0;
+        let levels = (settings.bits.value().clamp(1.0, MAX_BITS) - 1.0).exp2();
+        let crush = |x: f64| (x * levels).round() / levels;
+        (crush(self.held.0), crush(self.held.1))
+    }
+
+    fn chorus(&mut self, input: (f64, f64)) -> (f64, f64) {
+        let settings = &self.effects.chorus;
+        let (center, reach) = if settings.is_flanger() {
+            FLANGER_DELAY
+        } else {
+            CHORUS_DELAY
+        };
+        let depth = settings.depth.value().clamp(0.0, 1.0);
+        let feedback = settings.feedback.value().clamp(0.0, MAX_FEEDBACK);
+        self.chorus_phase = (self.chorus_phase + settings.rate.value() / self.sample_rate).fract();
+
+        // The right side sweeps a quarter of a cycle ahead of the left for width
+        let mut output = [0.0; 2];
+        for (channel, (line, input)) in self.chorus.iter_mut().zip([input.0, input.1]).enumerate() {
+            let phase = self.chorus_phase + channel as f64 * 0.25;
+            let delay = (center + reach * depth * (phase * TAU).sin()) * self.sample_rate;
+            let delayed = line.read(delay);
+            line.write(input + delayed * feedback);
+            output[channel] = delayed;
+        }
+        (output[0], output[1])
+    }
+
+    fn delay(&mut self, input: (f64, f64)) -> (f64, f64) {
+        let settings = &self.effects.delay;
+        let target = settings.seconds(self.transport.tempo.value()) * self.sample_rate;
+        self.delay_time += (target - self.delay_time) * DELAY_SMOOTHING;
+        let feedback = settings.feedback.value().clamp(0.0, MAX_FEEDBACK);
+
+        let mut output = [0.0; 2];
+        for (channel, (line, input)) in self.delay.iter_mut().zip([input.0, input.1]).enumerate() {
+            let delayed = line.read(self.delay_time);
+            line.write(input + delayed * feedback);
+            output[channel] = delayed;
+        }
+        (output[0], output[1])
+    }
+}
+
+impl AudioNode for EffectsRack {
+    const ID: u64 = 0x4546_5852;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U2;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.allocate();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            self.allocate();
+        }
+    }
+
+    fn tick(&mut self, input: &Frame<f64, Self::Inputs>) -> Frame<f64, Self::Outputs> {
+        let mut signal = (input[0], input[1]);
+        for kind in self.effects.order() {
+            if self.effects.is_bypassed(kind) {
+                continue;
+            }
+            let wet = match kind {
+                EffectKind::Drive => self.drive(signal),
+                EffectKind::Chorus => self.chorus(signal),
+                EffectKind::Delay => self.delay(signal),
+                EffectKind::Reverb => self.reverb.tick(signal, &self.effects.reverb),
+            };
+            let mix = self.effects.mix(kind).value().clamp(0.0, 1.0);
+            signal = (
+                signal.0 + (wet.0 - signal.0) * mix,
+                signal.1 + (wet.1 - signal.1) * mix,
+            );
+        }
+        [signal.0, signal.1].into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use EffectKind::*;
+
+    #[test]
+    fn slot_order_survives_packing_and_moves() {
+        let effects = Effects::default();
+        assert_eq!(effects.order(), [Drive, Chorus, Delay, Reverb]);
+
+        effects.set_order([Reverb, Delay, Chorus, Drive]);
+        assert_eq!(effects.order(), [Reverb, Delay, Chorus, Drive]);
+
+        assert_eq!(effects.move_slot(1, true), 2);
+        assert_eq!(effects.order(), [Reverb, Chorus, Delay, Drive]);
+        assert_eq!(effects.move_slot(1, false), 0);
+        assert_eq!(effects.order(), [Chorus, Reverb, Delay, Drive]);
+        // Nothing moves past either end
+        assert_eq!(effects.move_slot(0, false), 0);
+        assert_eq!(effects.move_slot(3, true), 3);
+        assert_eq!(effects.order(), [Chorus, Reverb, Delay, Drive]);
+    }
+
+    #[test]
+    fn orders_with_an_effect_twice_are_rejected() {
+        let effects = Effects::default();
+        effects.set_order([Delay, Delay, Chorus, Drive]);
+        assert_eq!(effects.order(), [Drive, Chorus, Delay, Reverb]);
+    }
+
+    #[test]
+    fn synced_delay_time_follows_the_tempo() {
+        let delay = Effects::default().delay;
+        delay.time.set_value(0.25);
+        assert_eq!(delay.seconds(120.0), 0.25);
+
+        // An eighth at 120 BPM is a quarter of a second, and half that at double the tempo
+        delay.set_synced(true);
+        delay.set_division(1);
+        assert_eq!(delay.seconds(120.0), 0.25);
+        assert_eq!(delay.seconds(240.0), 0.125);
+        // Four bars at 60 BPM would be 16 seconds, longer than the delay line
+        delay.set_division(DIVISIONS.len());
+        assert_eq!(delay.seconds(60.0), MAX_DELAY);
+    }
+}
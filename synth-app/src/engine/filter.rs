@@ -0,0 +1,321 @@
+use std::{
+    f64::consts::PI,
+    fmt,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use fundsp::{hacker::*, DEFAULT_SR};
+use serde::{Deserialize, Serialize};
+
+use crate::sample::ROOT_NOTE;
+
+pub const MIN_CUTOFF: f64 = 20.0;
+pub const MAX_CUTOFF: f64 = 20_000.0;
+/// How far the envelope moves the cutoff with the envelope amount at full.
+pub const ENVELOPE_OCTAVES: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FilterMode {
+    LowPass = 0,
+    HighPass = 1,
+    BandPass = 2,
+    Notch = 3,
+}
+
+impl FilterMode {
+    pub fn next(&self) -> Self {
+        use FilterMode::*;
+        match *self {
+            LowPass => HighPass,
+            HighPass => BandPass,
+            BandPass => Notch,
+            Notch => LowPass,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => FilterMode::HighPass,
+            2 => FilterMode::BandPass,
+            3 => FilterMode::Notch,
+            _ => FilterMode::LowPass,
+        }
+    }
+}
+
+impl fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterMode::LowPass => write!(f, "Low pass"),
+            FilterMode::HighPass => write!(f, "High pass"),
+            FilterMode::BandPass => write!(f, "Band pass"),
+            FilterMode::Notch => write!(f, "Notch"),
+        }
+    }
+}
+
+/// How steeply the filter cuts, 24 dB runs the signal through two 12 dB stages.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FilterSlope {
+    Db12 = 0,
+    Db24 = 1,
+}
+
+impl FilterSlope {
+    pub fn next(&self) -> Self {
+        match *self {
+            FilterSlope::Db12 => FilterSlope::Db24,
+            FilterSlope::Db24 => FilterSlope::Db12,
+        }
+    }
+
+    fn stages(&self) -> usize {
+        match self {
+            FilterSlope::Db12 => 1,
+            FilterSlope::Db24 => 2,
+        }
+    }
+}
+
+impl fmt::Display for FilterSlope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterSlope::Db12 => write!(f, "12 dB"),
+            FilterSlope::Db24 => write!(f, "24 dB"),
+        }
+    }
+}
+
+/// Filter settings shared by every voice, read every sample so changes are heard straight away.
+#[derive(Clone)]
+pub struct Filter {
+    /// Cutoff in Hz before the envelope and key tracking move it
+    pub cutoff: Shared<f64>,
+    /// Resonance from 0.0 to 1.0, self oscillation starts just before 1.0
+    pub resonance: Shared<f64>,
+    /// How far the envelope opens the filter, from -1.0 to 1.0 of `ENVELOPE_OCTAVES`
+    pub envelope_amount: Shared<f64>,
+    /// How much the cutoff follows the note played, 1.0 moves it an octave per octave
+    pub key_tracking: Shared<f64>,
+    mode: Arc<AtomicU8>,
+    slope: Arc<AtomicU8>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            cutoff: shared(4000.0),
+            resonance: shared(0.3),
+            envelope_amount: shared(0.0),
+            key_tracking: shared(0.0),
+            mode: Arc::new(AtomicU8::new(FilterMode::LowPass as u8)),
+            slope: Arc::new(AtomicU8::new(FilterSlope::Db12 as u8)),
+        }
+    }
+}
+
+impl Filter {
+    pub fn mode(&self) -> FilterMode {
+        FilterMode::from_u8(self.mode.load(Ordering::Relaxed))
+    }
+
+    pub fn set_mode(&self, mode: FilterMode) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    pub fn slope(&self) -> FilterSlope {
+        match self.slope.load(Ordering::Relaxed) {
+            1 => FilterSlope::Db24,
+            _ => FilterSlope::Db12,
+        }
+    }
+
+    pub fn set_slope(&self, slope: FilterSlope) {
+        self.slope.store(slope as u8, Ordering::Relaxed);
+    }
+
+    pub fn set_cutoff(&self, cutoff: f64) {
+        self.cutoff.set_value(cutoff.clamp(MIN_CUTOFF, MAX_CUTOFF));
+    }
+
+    /// Returns the cutoff for a voice playing `pitch` Hz with its envelope at `envelope` and
+    /// `modulation` octaves added by the LFOs.
+    pub fn cutoff_at(&self, envelope: f64, pitch: f64, modulation: f64) -> f64 {
+        let octaves = envelope * self.envelope_amount.value() * ENVELOPE_OCTAVES + modulation;
+        let tracking = (pitch.max(1.0) / ROOT_NOTE.to_freq_f64()).powf(self.key_tracking.value());
+        (self.cutoff.value() * octaves.exp2() * tracking).clamp(MIN_CUTOFF, MAX_CUTOFF)
+    }
+
+    /// Damping of each stage, 2.0 with no resonance down to nearly 0.0 at full.
+    fn damping(&self) -> f64 {
+        2.0 * (1.0 - 0.98 * self.resonance.value().clamp(0.0, 1.0))
+    }
+
+    /// Returns the gain at `frequency` Hz with the cutoff at `cutoff` Hz, for plotting the
+    /// response. This is the response of the analog filter the digital one is modelled on.
+    pub fn response(&self, frequency: f64, cutoff: f64) -> f64 {
+        let k = self.damping();
+        // With s = jw normalised to the cutoff, the denominator is 1 + ks + s^2
+        let w = frequency / cutoff;
+        let denominator = (1.0 - w * w).hypot(k * w);
+        let numerator = match self.mode() {
+            FilterMode::LowPass => 1.0,
+            FilterMode::HighPass => w * w,
+            FilterMode::BandPass => k * w,
+            FilterMode::Notch => (1.0 - w * w).abs(),
+        };
+        (numerator / denominator.max(f64::EPSILON)).powi(self.slope().stages() as i32)
+    }
+}
+
+/// One state variable filter stage, from Andrew Simper's trapezoidal SVF.
+#[derive(Clone, Default)]
+struct Stage {
+    ic1eq: f64,
+    ic2eq: f64,
+}
+
+impl Stage {
+    fn tick(&mut self, input: f64, g: f64, k: f64, mode: FilterMode) -> f64 {
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let high = input - k * v1 - v2;
+        match mode {
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => high,
+            FilterMode::BandPass => k * v1,
+            FilterMode::Notch => v2 + high,
+        }
+    }
+}
+
+/// Multimode filter for a voice. The inputs are the audio, the voice's envelope, its pitch in Hz
+/// and the LFO modulation in octaves, which all move the cutoff.
+#[derive(Clone)]
+pub(crate) struct MultimodeFilter {
+    filter: Filter,
+    stages: [Stage; 2],
+    sample_rate: f64,
+}
+
+impl MultimodeFilter {
+    pub(crate) fn new(filter: &Filter) -> Self {
+        Self {
+            filter: filter.clone(),
+            stages: Default::default(),
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl AudioNode for MultimodeFilter {
+    const ID: u64 = 0x4D4D_4654;
+    type Sample = f64;
+    type Inputs = U4;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.stages = Default::default();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, input: &Frame<f64, Self::Inputs>) -> Frame<f64, Self::Outputs> {
+        let cutoff = self
+            .filter
+            .cutoff_at(input[1], input[2], input[3])
+            .min(self.sample_rate * 0.45);
+        let g = (PI * cutoff / self.sample_rate).tan();
+        let k = self.filter.damping();
+        let mode = self.filter.mode();
+
+        let stages = self.filter.slope().stages();
+        let output = self.stages[..stages]
+            .iter_mut()
+            .fold(input[0], |signal, stage| stage.tick(signal, g, k, mode));
+        [output].into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn cutoff_follows_the_envelope_keys_and_modulation() {
+        let filter = Filter::default();
+        filter.cutoff.set_value(1000.0);
+        let root = ROOT_NOTE.to_freq_f64();
+        assert_close(filter.cutoff_at(1.0, root, 0.0), 1000.0);
+        assert_close(filter.cutoff_at(0.0, root, 1.0), 2000.0);
+
+        // A full envelope at half the amount opens it by half of `ENVELOPE_OCTAVES`
+        filter.envelope_amount.set_value(0.5);
+        assert_close(filter.cutoff_at(1.0, root, 0.0), 1000.0 * 2.5f64.exp2());
+        filter.envelope_amount.set_value(-0.2);
+        assert_close(filter.cutoff_at(1.0, root, 0.0), 500.0);
+        filter.envelope_amount.set_value(0.0);
+
+        // Full tracking moves it an octave per octave, half tracking half as far
+        filter.key_tracking.set_value(1.0);
+        assert_close(filter.cutoff_at(0.0, root * 2.0, 0.0), 2000.0);
+        filter.key_tracking.set_value(0.5);
+        assert_close(filter.cutoff_at(0.0, root * 4.0, 0.0), 2000.0);
+    }
+
+    #[test]
+    fn cutoff_stays_in_range() {
+        let filter = Filter::default();
+        filter.cutoff.set_value(1000.0);
+        assert_eq!(filter.cutoff_at(0.0, 440.0, 10.0), MAX_CUTOFF);
+        assert_eq!(filter.cutoff_at(0.0, 440.0, -10.0), MIN_CUTOFF);
+        filter.set_cutoff(1.0);
+        assert_eq!(filter.cutoff.value(), MIN_CUTOFF);
+    }
+
+    #[test]
+    fn response_has_the_shape_of_each_mode() {
+        let filter = Filter::default();
+        filter.resonance.set_value(0.0);
+
+        // Low pass lets the bass through and cuts the highs
+        assert_close(filter.response(1.0, 1000.0), 1.0);
+        assert!(filter.response(10_000.0, 1000.0) < 0.02);
+
+        // A notch removes the cutoff itself
+        filter.set_mode(FilterMode::Notch);
+        assert!(filter.response(1000.0, 1000.0) < 1e-6);
+        assert!(filter.response(100.0, 1000.0) > 0.9);
+
+        // Two stages cut twice as many dB an octave as one
+        filter.set_mode(FilterMode::LowPass);
+        let db = |gain: f64| 20.0 * gain.log10();
+        let db12 = db(filter.response(8000.0, 1000.0)) - db(filter.response(4000.0, 1000.0));
+        filter.set_slope(FilterSlope::Db24);
+        let db24 = db(filter.response(8000.0, 1000.0)) - db(filter.response(4000.0, 1000.0));
+        assert!((db12 + 12.0).abs() < 1.0, "12 dB slope is {} dB", db12);
+        assert_close(db24, db12 * 2.0);
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    f64::consts::TAU,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use fundsp::{
+    hacker::{shared, AudioNode, Frame, Shared, U0, U4},
+    DEFAULT_SR,
+};
+use serde::{Deserialize, Serialize};
+use synth_core::random::{self, xorshift};
+
+use crate::clock::Transport;
+
+pub const NUM_LFOS: usize = 2;
+pub const MIN_RATE: f64 = 0.05;
+pub const MAX_RATE: f64 = 20.0;
+/// Beats per cycle and their names, for LFOs synced to the tempo.
+pub const DIVISIONS: [(f64, &str); 7] = [
+    (0.25, "1/16"),
+    (0.5, "1/8"),
+    (1.0, "1/4"),
+    (2.0, "1/2"),
+    (4.0, "1 bar"),
+    (8.0, "2 bars"),
+    (16.0, "4 bars"),
+];
+/// How far each destination is moved with the depth at full.
+const PITCH_SEMITONES: f64 = 12.0;
+const CUTOFF_OCTAVES: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LfoShape {
+    Sine = 0,
+    Triangle = 1,
+    Saw = 2,
+    Square = 3,
+    /// A new random level every cycle.
+    SampleAndHold = 4,
+}
+
+impl LfoShape {
+    pub fn next(&self) -> Self {
+        use LfoShape::*;
+        match *self {
+            Sine => Triangle,
+            Triangle => Saw,
+            Saw => Square,
+            Square => SampleAndHold,
+            SampleAndHold => Sine,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => LfoShape::Triangle,
+            2 => LfoShape::Saw,
+            3 => LfoShape::Square,
+            4 => LfoShape::SampleAndHold,
+            _ => LfoShape::Sine,
+        }
+    }
+
+    /// Returns the level from -1.0 to 1.0 at `phase` through the cycle, `held` is the level a
+    /// sample and hold LFO picked at the start of the cycle.
+    pub fn value(&self, phase: f64, held: f64) -> f64 {
+        match self {
+            LfoShape::Sine => (phase * TAU).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square if phase < 0.5 => 1.0,
+            LfoShape::Square => -1.0,
+            LfoShape::SampleAndHold => held,
+        }
+    }
+}
+
+impl fmt::Display for LfoShape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LfoShape::Sine => write!(f, "Sine"),
+            LfoShape::Triangle => write!(f, "Triangle"),
+            LfoShape::Saw => write!(f, "Saw"),
+            LfoShape::Square => write!(f, "Square"),
+            LfoShape::SampleAndHold => write!(f, "S&H"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LfoDestination {
+    Pitch = 0,
+    Cutoff = 1,
+    Amplitude = 2,
+    Pan = 3,
+}
+
+impl LfoDestination {
+    pub fn next(&self) -> Self {
+        use LfoDestination::*;
+        match *self {
+            Pitch => Cutoff,
+            Cutoff => Amplitude,
+            Amplitude => Pan,
+            Pan => Pitch,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => LfoDestination::Cutoff,
+            2 => LfoDestination::Amplitude,
+            3 => LfoDestination::Pan,
+            _ => LfoDestination::Pitch,
+        }
+    }
+}
+
+impl fmt::Display for LfoDestination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LfoDestination::Pitch => write!(f, "Pitch"),
+            LfoDestination::Cutoff => write!(f, "Cutoff"),
+            LfoDestination::Amplitude => write!(f, "Amplitude"),
+            LfoDestination::Pan => write!(f, "Pan"),
+        }
+    }
+}
+
+/// Settings for one LFO, shared by every voice.
+#[derive(Clone)]
+pub struct Lfo {
+    /// Rate in Hz while free running
+    pub rate: Shared<f64>,
+    /// How far the destination is moved, from 0.0 to 1.0
+    pub depth: Shared<f64>,
+    shape: Arc<AtomicU8>,
+    destination: Arc<AtomicU8>,
+    /// Index into `DIVISIONS` while synced to the tempo
+    division: Arc<AtomicU8>,
+    synced: Arc<AtomicBool>,
+    /// Whether each note starts the LFO from the beginning of its cycle, rather than every voice
+    /// following the same free running one.
+    retrigger: Arc<AtomicBool>,
+}
+
+impl Lfo {
+    fn new(destination: LfoDestination, rate: f64) -> Self {
+        Self {
+            rate: shared(rate),
+            depth: shared(0.0),
+            shape: Arc::new(AtomicU8::new(LfoShape::Sine as u8)),
+            destination: Arc::new(AtomicU8::new(destination as u8)),
+            division: Arc::new(AtomicU8::new(2)),
+            synced: Arc::new(AtomicBool::new(false)),
+            retrigger: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the default LFOs, the first on pitch for vibrato and the second on the cutoff.
+    pub fn defaults() -> [Lfo; NUM_LFOS] {
+        [
+            Lfo::new(LfoDestination::Pitch, 5.0),
+            Lfo::new(LfoDestination::Cutoff, 0.5),
+        ]
+    }
+
+    pub fn shape(&self) -> LfoShape {
+        LfoShape::from_u8(self.shape.load(Ordering::Relaxed))
+    }
+
+    pub fn set_shape(&self, shape: LfoShape) {
+        self.shape.store(shape as u8, Ordering::Relaxed);
+    }
+
+    pub fn destination(&self) -> LfoDestination {
+        LfoDestination::from_u8(self.destination.load(Ordering::Relaxed))
+    }
+
+    pub fn set_destination(&self, destination: LfoDestination) {
+        self.destination.store(destination as u8, Ordering::Relaxed);
+    }
+
+    pub fn division(&self) -> usize {
+        usize::from(self.division.load(Ordering::Relaxed)).min(DIVISIONS.len() - 1)
+    }
+
+    pub fn set_division(&self, division: usize) {
+        self.division
+            .store(division.min(DIVISIONS.len() - 1) as u8, Ordering::Relaxed);
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::Relaxed)
+    }
+
+    pub fn set_synced(&self, synced: bool) {
+        self.synced.store(synced, Ordering::Relaxed);
+    }
+
+    pub fn retriggers(&self) -> bool {
+        self.retrigger.load(Ordering::Relaxed)
+    }
+
+    pub fn set_retrigger(&self, retrigger: bool) {
+        self.retrigger.store(retrigger, Ordering::Relaxed);
+    }
+
+    pub fn set_rate(&self, rate: f64) {
+        self.rate.set_value(rate.clamp(MIN_RATE, MAX_RATE));
+    }
+
+    /// Returns the rate in Hz, worked out from the tempo while synced.
+    pub fn frequency(&self, tempo: f64) -> f64 {
+        if self.is_synced() {
+            tempo / 60.0 / DIVISIONS[self.division()].0
+        } else {
+            self.rate.value()
+        }
+    }
+}
+
+/// Runs every LFO for a voice. Its outputs are the pitch ratio, the cutoff offset in octaves, the
+/// gain and the pan position.
+///
+/// Free running LFOs stay in step across voices without sharing any state: every voice's graph
+/// starts at the same time and is ticked with the same settings, so they all get the same phase
+/// and the same sample and hold levels.
+#[derive(Clone)]
+pub(crate) struct LfoModulation {
+    lfos: [Lfo; NUM_LFOS],
+    transport: Transport,
+    trigger: Shared<f64>,
+    seen: f64,
+    was_playing: bool,
+    phases: [f64; NUM_LFOS],
+    held: [f64; NUM_LFOS],
+    /// Xorshift state for the sample and hold levels, one per LFO so a retriggered LFO doesn't
+    /// change the levels of a free running one.
+    random: [u32; NUM_LFOS],
+    sample_rate: f64,
+}
+
+impl LfoModulation {
+    pub(crate) fn new(
+        lfos: &[Lfo; NUM_LFOS],
+        transport: &Transport,
+        trigger: &Shared<f64>,
+    ) -> Self {
+        Self {
+            lfos: lfos.clone(),
+            transport: transport.clone(),
+            trigger: trigger.clone(),
+            seen: trigger.value(),
+            was_playing: false,
+            phases: [0.0; NUM_LFOS],
+            held: [0.0; NUM_LFOS],
+            random: [random::SEED; NUM_LFOS],
+            sample_rate: DEFAULT_SR,
+        }
+    }
+
+    /// Starts a new cycle of LFO `index`, picking its next sample and hold level.
+    fn restart(&mut self, index: usize) {
+        let level = xorshift(&mut self.random[index]);
+        self.held[index] = f64::from(level) / f64::from(u32::MAX) * 2.0 - 1.0;
+    }
+}
+
+impl AudioNode for LfoModulation {
+    const ID: u64 = 0x4C46_4F4D;
+    type Sample = f64;
+    type Inputs = U0;
+    type Outputs = U4;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.phases = [0.0; NUM_LFOS];
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, _input: &Frame<f64, Self::Inputs>) -> Frame<f64, Self::Outputs> {
+        let triggered = self.trigger.value() != self.seen;
+        self.seen = self.trigger.value();
+        // Synced LFOs start their cycle on the beat when the transport starts
+        let playing = self.transport.is_playing();
+        let started = playing && !self.was_playing;
+        self.was_playing = playing;
+        let tempo = self.transport.tempo.value();
+
+        let (mut semitones, mut octaves, mut gain, mut pan) = (0.0, 0.0, 1.0, 0.0);
+        for index in 0..NUM_LFOS {
+            let lfo = &self.lfos[index];
+            let (shape, destination, depth) = (lfo.shape(), lfo.destination(), lfo.depth.value());
+            let restart = (lfo.retriggers() && triggered) || (lfo.is_synced() && started);
+
+            self.phases[index] += lfo.frequency(tempo) / self.sample_rate;
+            if restart {
+                self.phases[index] = 0.0;
+            }
+            if restart || self.phases[index] >= 1.0 {
+                self.phases[index] = self.phases[index].fract();
+                self.restart(index);
+            }
+
+            let value = shape.value(self.phases[index], self.held[index]);
+            match destination {
+                LfoDestination::Pitch => semitones += value * depth * PITCH_SEMITONES,
+                LfoDestination::Cutoff => octaves += value * depth * CUTOFF_OCTAVES,
+                // Tremolo only turns the level down, from full at the top of the cycle
+                LfoDestination::Amplitude => gain *= 1.0 - depth * (1.0 - value) / 2.0,
+                LfoDestination::Pan => pan += value * depth,
+            }
+        }
+
+        [
+            (semitones / 12.0).exp2(),
+            octaves,
+            gain.max(0.0),
+            pan.clamp(-1.0, 1.0),
+        ]
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn shapes_go_through_their_cycle() {
+        // Levels at the start, a quarter, half and three quarters of the way through
+        let cycles = [
+            (LfoShape::Sine, [0.0, 1.0, 0.0, -1.0]),
+            (LfoShape::Triangle, [0.0, 1.0, 0.0, -1.0]),
+            (LfoShape::Saw, [-1.0, -0.5, 0.0, 0.5]),
+            (LfoShape::Square, [1.0, 1.0, -1.0, -1.0]),
+            (LfoShape::SampleAndHold, [0.3, 0.3, 0.3, 0.3]),
+        ];
+        for (shape, levels) in cycles {
+            for (quarter, level) in levels.into_iter().enumerate() {
+                assert_close(shape.value(quarter as f64 / 4.0, 0.3), level);
+            }
+        }
+    }
+
+    #[test]
+    fn synced_rate_follows_the_tempo() {
+        let lfo = Lfo::new(LfoDestination::Pitch, 5.0);
+        assert_eq!(lfo.frequency(120.0), 5.0);
+
+        // A cycle a beat at 120 BPM is 2 Hz, a cycle every four bars a sixteenth of that
+        lfo.set_synced(true);
+        lfo.set_division(2);
+        assert_close(lfo.frequency(120.0), 2.0);
+        lfo.set_division(DIVISIONS.len() - 1);
+        assert_close(lfo.frequency(120.0), 0.125);
+        lfo.set_division(0);
+        assert_close(lfo.frequency(90.0), 6.0);
+    }
+
+    #[test]
+    fn notes_restart_a_retriggered_lfo() {
+        let lfos = Lfo::defaults();
+        lfos[0].set_rate(10.0);
+        let trigger = shared(0.0);
+        let mut modulation = LfoModulation::new(&lfos, &Transport::default(), &trigger);
+        modulation.set_sample_rate(1000.0);
+        let tick = |modulation: &mut LfoModulation, count: usize| {
+            for _ in 0..count {
+                modulation.tick(&Frame::default());
+            }
+            modulation.phases[0]
+        };
+
+        // A free running LFO carries on through a note
+        assert_close(tick(&mut modulation, 30), 0.3);
+        trigger.set_value(1.0);
+        assert_close(tick(&mut modulation, 1), 0.31);
+
+        lfos[0].set_retrigger(true);
+        trigger.set_value(2.0);
+        assert_close(tick(&mut modulation, 1), 0.0);
+        assert_close(tick(&mut modulation, 10), 0.1);
+    }
+}
@@ -0,0 +1,164 @@
+pub mod effects;
+pub mod filter;
+pub mod lfo;
+mod sampler;
+pub mod tap;
+mod voice;
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SizedSample, Stream, StreamConfig,
+};
+use fundsp::hacker::*;
+use log::{error, info};
+use wmidi::{Note, U7};
+
+use crate::app::State;
+
+use self::{
+    effects::EffectsRack,
+    voice::{Voice, VoiceAllocator},
+};
+
+/// Number of notes that can be played at once.
+pub const NUM_VOICES: usize = 8;
+
+/// The audio engine, this owns the output stream so it must be kept alive for as long as the app
+/// should make sound.
+pub struct Engine {
+    _stream: Stream,
+    voices: Voices,
+}
+
+/// Cloneable handle for playing notes on the engine from any thread.
+#[derive(Clone)]
+pub struct Voices {
+    allocator: Arc<Mutex<VoiceAllocator>>,
+}
+
+impl Voices {
+    pub fn note_on(&self, note: Note, velocity: U7) {
+        if let Ok(mut allocator) = self.allocator.lock() {
+            allocator.note_on(note, velocity);
+        }
+    }
+
+    pub fn note_off(&self, note: Note) {
+        if let Ok(mut allocator) = self.allocator.lock() {
+            allocator.note_off(note);
+        }
+    }
+
+    pub fn all_notes_off(&self) {
+        if let Ok(mut allocator) = self.allocator.lock() {
+            allocator.all_notes_off();
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for Voices {
+    /// Voices that aren't part of any audio graph, for testing what plays them.
+    fn default() -> Self {
+        Self {
+            allocator: Arc::new(Mutex::new(VoiceAllocator::new(
+                (0..NUM_VOICES).map(|_| Voice::new()).collect(),
+            ))),
+        }
+    }
+}
+
+impl Engine {
+    /// Opens the default output device and starts playing the synth graph.
+    pub fn new(state: State) -> anyhow::Result<Self> {
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or_else(|| anyhow!("No audio output device found"))?;
+        let config = device
+            .default_output_config()
+            .context("Failed to get the default output config")?;
+
+        info!(
+            "Using audio device {} with config {:?}",
+            device.name().unwrap_or_default(),
+            config
+        );
+
+        let voices: Vec<Voice> = (0..NUM_VOICES).map(|_| Voice::new()).collect();
+        let net = graph(&state, &voices);
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => run::<f32>(&device, &config.into(), net, &state),
+            cpal::SampleFormat::I16 => run::<i16>(&device, &config.into(), net, &state),
+            cpal::SampleFormat::U16 => run::<u16>(&device, &config.into(), net, &state),
+            format => Err(anyhow!("Unsupported sample format {}", format)),
+        }?;
+        stream.play().context("Failed to start the audio stream")?;
+
+        Ok(Self {
+            _stream: stream,
+            voices: Voices {
+                allocator: Arc::new(Mutex::new(VoiceAllocator::new(voices))),
+            },
+        })
+    }
+
+    /// Returns a handle for playing notes.
+    pub fn voices(&self) -> Voices {
+        self.voices.clone()
+    }
+}
+
+/// Builds the full graph: every stereo voice is summed into the effects rack, then limited.
+fn graph(state: &State, voices: &[Voice]) -> Net64 {
+    let mut mix = Net64::wrap(Box::new(zero() | zero()));
+    for voice in voices {
+        mix = mix + Net64::wrap(voice.graph(state));
+    }
+
+    let effects = ((dcblock() | dcblock()) * (1.0 / NUM_VOICES as f64).sqrt())
+        >> An(EffectsRack::new(&state.effects, &state.transport))
+        >> limiter_stereo(0.01);
+
+    mix >> effects
+}
+
+fn run<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut net: Net64,
+    state: &State,
+) -> anyhow::Result<Stream>
+where
+    T: SizedSample + FromSample<f64>,
+{
+    let channels = config.channels as usize;
+    net.set_sample_rate(f64::from(config.sample_rate.0));
+    let (tap, recorder) = (state.tap.clone(), state.recorder.clone());
+    tap.set_sample_rate(config.sample_rate.0);
+    recorder.set_sample_rate(config.sample_rate.0);
+
+    let mut backend = net.backend();
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            for frame in data.chunks_mut(channels) {
+                let (left, right) = backend.get_stereo();
+                tap.push(((left + right) / 2.0) as f32);
+                recorder.push(left as f32, right as f32);
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let value = if channel % 2 == 0 { left } else { right };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |err| error!("Audio stream error: {}", err),
+        None,
+    )?;
+
+    Ok(stream)
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use fundsp::{hacker::*, DEFAULT_SR};
+
+use crate::sample::{Sample, SampleSlot, ROOT_NOTE};
+
+/// Plays the sample in the slot at the pitch on its first input, restarting from the start point
+/// whenever `trigger` changes. While the slot is empty the oscillator on its second input is
+/// passed through instead.
+#[derive(Clone)]
+pub(crate) struct SamplePlayer {
+    slot: SampleSlot,
+    trigger: Shared<f64>,
+    seen: f64,
+    /// The sample playing, taken from the slot on each trigger so the audio thread only has to
+    /// look at the slot once per note.
+    sample: Option<Arc<Sample>>,
+    /// Position in the sample, in frames.
+    position: f64,
+    sample_rate: f64,
+}
+
+impl SamplePlayer {
+    pub(crate) fn new(slot: &SampleSlot, trigger: &Shared<f64>) -> Self {
+        Self {
+            slot: slot.clone(),
+            trigger: trigger.clone(),
+            seen: trigger.value(),
+            sample: slot.get(),
+            position: 0.0,
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl AudioNode for SamplePlayer {
+    const ID: u64 = 0x5A4D_504C;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self) {
+        self.position = 0.0;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn tick(&mut self, input: &Frame<f64, Self::Inputs>) -> Frame<f64, Self::Outputs> {
+        if self.trigger.value() != self.seen {
+            self.seen = self.trigger.value();
+            // If the slot is being edited right now keep playing the last sample. The slot keeps
+            // replaced samples alive, so letting go of the old one here never frees it
+            if let Some(sample) = self.slot.try_get() {
+                self.sample = sample;
+            }
+            self.position = self
+                .sample
+                .as_ref()
+                .map_or(0.0, |sample| sample.start() as f64);
+        }
+
+        let Some(sample) = &self.sample else {
+            return [input[1]].into();
+        };
+        if self.position >= sample.end() as f64 {
+            return [0.0].into();
+        }
+
+        let value = sample.value_at(self.position);
+        let rate =
+            input[0] / ROOT_NOTE.to_freq_f64() * f64::from(sample.sample_rate()) / self.sample_rate;
+        self.position += rate.max(0.0);
+        if sample.looping && self.position >= sample.loop_end() as f64 {
+            let length = (sample.loop_end() - sample.loop_start()) as f64;
+            self.position -=
+                length * ((self.position - sample.loop_start() as f64) / length).floor();
+        }
+        [value].into()
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Number of samples kept, a power of two so the write position wraps with a mask. About 1.4
+/// seconds at 48 kHz.
+pub const TAP_SIZE: usize = 1 << 16;
+const MASK: usize = TAP_SIZE - 1;
+
+struct TapBuffer {
+    /// Samples stored as `f32` bits.
+    samples: Box<[AtomicU32]>,
+    /// Total samples ever written, the next one goes at `written & MASK`.
+    written: AtomicUsize,
+    sample_rate: AtomicU32,
+}
+
+/// Lock-free ring buffer holding the last few moments of the engine's output, mixed to mono.
+/// The audio thread is the only writer and never waits, readers copy out whatever is there, so
+/// a read that overlaps a write can mix old and new samples. That's fine for drawing.
+#[derive(Clone)]
+pub struct AudioTap {
+    buffer: Arc<TapBuffer>,
+}
+
+impl Default for AudioTap {
+    fn default() -> Self {
+        Self {
+            buffer: Arc::new(TapBuffer {
+                samples: (0..TAP_SIZE).map(|_| AtomicU32::new(0)).collect(),
+                written: AtomicUsize::new(0),
+                sample_rate: AtomicU32::new(44_100),
+            }),
+        }
+    }
+}
+
+impl AudioTap {
+    /// Adds a sample, only the audio thread calls this.
+    pub(crate) fn push(&self, sample: f32) {
+        let written = self.buffer.written.load(Ordering::Relaxed);
+        self.buffer.samples[written & MASK].store(sample.to_bits(), Ordering::Relaxed);
+        self.buffer.written.store(written + 1, Ordering::Release);
+    }
+
+    pub(crate) fn set_sample_rate(&self, sample_rate: u32) {
+        self.buffer
+            .sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.buffer.sample_rate.load(Ordering::Relaxed)
+    }
+
+    /// Returns the most recent `count` samples, oldest first. `count` is limited to `TAP_SIZE`.
+    pub fn latest(&self, count: usize) -> Vec<f32> {
+        let count = count.min(TAP_SIZE);
+        let written = self.buffer.written.load(Ordering::Acquire);
+        let start = written.saturating_sub(count);
+        (start..written)
+            .map(|index| f32::from_bits(self.buffer.samples[index & MASK].load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_returns_only_what_was_written() {
+        let tap = AudioTap::default();
+        assert!(tap.latest(10).is_empty());
+        for sample in [0.1, 0.2, 0.3] {
+            tap.push(sample);
+        }
+        assert_eq!(tap.latest(2), [0.2, 0.3]);
+        assert_eq!(tap.latest(10), [0.1, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn latest_reads_across_the_wrap() {
+        let tap = AudioTap::default();
+        for index in 0..TAP_SIZE + 3 {
+            tap.push(index as f32);
+        }
+        let expected: Vec<f32> = (TAP_SIZE - 2..TAP_SIZE + 3)
+            .map(|index| index as f32)
+            .collect();
+        assert_eq!(tap.latest(5), expected);
+
+        // Only the last `TAP_SIZE` are kept
+        let all = tap.latest(TAP_SIZE * 2);
+        assert_eq!(all.len(), TAP_SIZE);
+        assert_eq!(all[0], 3.0);
+        assert_eq!(all[TAP_SIZE - 1], (TAP_SIZE + 2) as f32);
+    }
+}
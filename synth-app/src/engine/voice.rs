@@ -0,0 +1,213 @@
+use std::time::Instant;
+
+use fundsp::hacker::*;
+use wmidi::{Note, U7};
+
+use crate::app::State;
+
+use super::{filter::MultimodeFilter, lfo::LfoModulation, sampler::SamplePlayer};
+
+/// Controls for a single voice, the audio graph reads these every sample so they can be set from
+/// any thread.
+#[derive(Clone)]
+pub(crate) struct Voice {
+    pitch: Shared<f64>,
+    velocity: Shared<f64>,
+    gate: Shared<f64>,
+    /// Incremented on every note on, so the envelope restarts even if the gate never went low.
+    trigger: Shared<f64>,
+
+    note: Option<Note>,
+    pressed: Option<Instant>,
+    released: Option<Instant>,
+}
+
+impl Voice {
+    pub(crate) fn new() -> Self {
+        Self {
+            pitch: shared(440.0),
+            velocity: shared(0.0),
+            gate: shared(0.0),
+            trigger: shared(0.0),
+            note: None,
+            pressed: None,
+            released: None,
+        }
+    }
+
+    /// Builds the stereo audio graph for this voice: oscillator or sample -> ADSR -> multimode
+    /// filter -> pan, with the LFOs moving the pitch, cutoff, level and pan.
+    pub(crate) fn graph(&self, state: &State) -> Box<dyn AudioUnit64> {
+        let lfos = An(LfoModulation::new(
+            &state.lfos,
+            &state.transport,
+            &self.trigger,
+        ));
+        // Pitch, cutoff offset, gain and pan
+        let modulation =
+            (var(&self.pitch) | lfos) >> map(|i: &Frame<f64, U5>| (i[0] * i[1], i[2], i[3], i[4]));
+        // The pitch is split between the oscillator, the sample player and the filter
+        let source = split::<U3>()
+            >> (((pass() | saw()) >> An(SamplePlayer::new(&state.sample, &self.trigger))) | pass());
+        // The envelope is split so it moves the cutoff as well as the level
+        let envelope = adsr(state, &self.gate, &self.trigger) >> split::<U2>();
+        let filter = An(MultimodeFilter::new(&state.filter));
+
+        // Audio, pitch, cutoff offset, gain, pan, envelope, envelope, velocity
+        let voice = (modulation >> (source | multipass::<U3>())) | envelope | var(&self.velocity);
+        Box::new(
+            voice
+                >> map(|i: &Frame<f64, U8>| (i[0] * i[3] * i[5] * i[7], i[6], i[1], i[2], i[4]))
+                >> (filter | pass())
+                >> panner(),
+        )
+    }
+
+    fn start(&mut self, note: Note, velocity: U7) {
+        self.pitch.set_value(note.to_freq_f64());
+        self.velocity
+            .set_value(f64::from(u8::from(velocity)) / 127.0);
+        self.trigger.set_value(self.trigger.value() + 1.0);
+        self.gate.set_value(1.0);
+
+        self.note = Some(note);
+        self.pressed = Some(Instant::now());
+        self.released = None;
+    }
+
+    fn stop(&mut self) {
+        self.gate.set_value(0.0);
+        self.released = Some(Instant::now());
+    }
+
+    fn is_held(&self) -> bool {
+        self.note.is_some() && self.released.is_none()
+    }
+}
+
+/// Assigns notes to a fixed pool of voices.
+///
+/// A note that is already playing is retriggered on the same voice, otherwise the voice that has
+/// been released the longest is used, and if every voice is held the oldest note is stolen.
+pub(crate) struct VoiceAllocator {
+    voices: Vec<Voice>,
+}
+
+impl VoiceAllocator {
+    pub(crate) fn new(voices: Vec<Voice>) -> Self {
+        Self { voices }
+    }
+
+    pub(crate) fn note_on(&mut self, note: Note, velocity: U7) {
+        let index = self.find(note);
+        self.voices[index].start(note, velocity);
+    }
+
+    pub(crate) fn note_off(&mut self, note: Note) {
+        for voice in self.voices.iter_mut() {
+            if voice.is_held() && voice.note == Some(note) {
+                voice.stop();
+            }
+        }
+    }
+
+    /// Releases every held voice.
+    pub(crate) fn all_notes_off(&mut self) {
+        for voice in self.voices.iter_mut().filter(|voice| voice.is_held()) {
+            voice.stop();
+        }
+    }
+
+    fn find(&self, note: Note) -> usize {
+        if let Some(index) = self
+            .voices
+            .iter()
+            .position(|voice| voice.note == Some(note))
+        {
+            return index;
+        }
+        if let Some(index) = self.voices.iter().position(|voice| voice.note.is_none()) {
+            return index;
+        }
+        let released = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, voice)| voice.released.map(|time| (index, time)))
+            .min_by_key(|(_, time)| *time);
+        if let Some((index, _)) = released {
+            return index;
+        }
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, voice)| voice.pressed)
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+}
+
+/// ADSR envelope that reads its times and level from the shared state, so changes made in the UI
+/// are heard straight away, even on notes that are already playing.
+///
+/// The envelope starts its attack when `trigger` changes and releases when `gate` goes low. Both
+/// the attack and the release start from the current level, so retriggering a voice doesn't click.
+fn adsr(
+    state: &State,
+    gate: &Shared<f64>,
+    trigger: &Shared<f64>,
+) -> An<impl AudioNode<Sample = f64, Inputs = U0, Outputs = U1>> {
+    let (attack, decay, sustain, release) = (
+        state.attack.clone(),
+        state.decay.clone(),
+        state.sustain.clone(),
+        state.release.clone(),
+    );
+    let (gate, trigger) = (gate.clone(), trigger.clone());
+
+    let seen = shared(0.0);
+    let level = shared(0.0);
+    let start_level = shared(0.0);
+    let attack_start = shared(-1.0);
+    let release_start = shared(-1.0);
+
+    envelope(move |time: f64| {
+        if trigger.value() != seen.value() {
+            seen.set_value(trigger.value());
+            start_level.set_value(level.value());
+            attack_start.set_value(time);
+            release_start.set_value(-1.0);
+        } else if gate.value() <= 0.0 && attack_start.value() >= 0.0 && release_start.value() < 0.0
+        {
+            start_level.set_value(level.value());
+            release_start.set_value(time);
+        }
+
+        let value = if attack_start.value() < 0.0 {
+            0.0
+        } else if release_start.value() < 0.0 {
+            let elapsed = time - attack_start.value();
+            let attack = attack.value().max(0.001);
+            let decay = decay.value().max(0.001);
+            if elapsed < attack {
+                lerp(start_level.value(), 1.0, elapsed / attack)
+            } else if elapsed - attack < decay {
+                lerp(1.0, sustain.value(), (elapsed - attack) / decay)
+            } else {
+                sustain.value()
+            }
+        } else {
+            let elapsed = time - release_start.value();
+            let release = release.value().max(0.001);
+            if elapsed < release {
+                lerp(start_level.value(), 0.0, elapsed / release)
+            } else {
+                0.0
+            }
+        };
+
+        let value = clamp01(value);
+        level.set_value(value);
+        value
+    })
+}
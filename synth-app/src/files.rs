@@ -0,0 +1,107 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// A directory the app keeps one kind of file in, such as samples or presets. It is relative to
+/// the working directory unless overridden with an environment variable.
+pub struct FileDir {
+    /// Directory used when `variable` isn't set.
+    default: &'static str,
+    /// Environment variable that overrides the directory, e.g. `SYNTH_SAMPLES_DIR`.
+    variable: &'static str,
+    /// Extension of the files kept in it, without the dot.
+    extension: &'static str,
+}
+
+impl FileDir {
+    pub const fn new(
+        default: &'static str,
+        variable: &'static str,
+        extension: &'static str,
+    ) -> Self {
+        Self {
+            default,
+            variable,
+            extension,
+        }
+    }
+
+    /// Returns the directory, from the environment variable if it is set.
+    pub fn path(&self) -> PathBuf {
+        env::var_os(self.variable).map_or_else(|| PathBuf::from(self.default), PathBuf::from)
+    }
+
+    /// Returns the files in the directory with its extension, sorted by name.
+    pub fn list(&self) -> Vec<PathBuf> {
+        list(&self.path(), self.extension)
+    }
+
+    /// Returns the first file named `prefix` followed by a number that isn't taken.
+    pub fn next_path(&self, prefix: &str) -> PathBuf {
+        next_free(&self.path(), prefix, &[self.extension])
+    }
+}
+
+fn list(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|found| found.eq_ignore_ascii_case(extension))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+/// Returns the first `<prefix>NNN` name in `dir` that isn't taken with any of `extensions`, with
+/// the first of them. Files that go together, such as a recording's WAV and MIDI files, share a
+/// number this way.
+pub fn next_free(dir: &Path, prefix: &str, extensions: &[&str]) -> PathBuf {
+    (1..)
+        .map(|number| dir.join(format!("{}{:03}", prefix, number)))
+        .find(|stem| {
+            extensions
+                .iter()
+                .all(|extension| !stem.with_extension(extension).exists())
+        })
+        .zip(extensions.first())
+        .map(|(stem, extension)| stem.with_extension(extension))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_and_numbers_files_by_extension() {
+        let dir = env::temp_dir().join(format!("synth-files-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["b-002.wav", "b-001.wav", "b-003.mid", "c.WAV", "notes.txt"] {
+            fs::write(dir.join(name), []).unwrap();
+        }
+
+        assert_eq!(
+            list(&dir, "wav"),
+            [
+                dir.join("b-001.wav"),
+                dir.join("b-002.wav"),
+                dir.join("c.WAV")
+            ]
+        );
+        assert_eq!(next_free(&dir, "a-", &["wav"]), dir.join("a-001.wav"));
+        assert_eq!(next_free(&dir, "b-", &["wav"]), dir.join("b-003.wav"));
+        // A number taken by either kind of file is skipped for both
+        assert_eq!(
+            next_free(&dir, "b-", &["wav", "mid"]),
+            dir.join("b-004.wav")
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,4 +1,14 @@
 pub mod app;
+pub mod clock;
+pub mod engine;
+pub mod files;
+pub mod midi;
+pub mod preset;
+pub mod recorder;
+pub mod sample;
+pub mod sequencer;
+pub mod spectrum;
+mod state;
 
 // Only compile this module on the Raspberry Pi
 #[cfg(feature = "raspberry_pi")]
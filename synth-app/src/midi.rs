@@ -0,0 +1,405 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::queue::SegQueue;
+use log::{info, warn};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use wmidi::{ControlFunction, MidiMessage};
+
+use crate::{
+    app::ActionMessage,
+    clock::{Follower, Transport},
+    engine::Voices,
+    recorder::Recorder,
+};
+
+/// Product name the keyboard firmware reports over USB.
+/// midir only exposes port names, so the device is matched on this rather than its VID/PID.
+pub(crate) const DEVICE_NAME: &str = "MIDI Keyboard";
+pub(crate) const CLIENT_NAME: &str = "synth-app";
+/// How often to check if the keyboard has been plugged in or unplugged.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// MIDI ports other than the keyboard, chosen from the UI. Ports are remembered by name so a
+/// device that is unplugged is connected again when it comes back.
+#[derive(Clone, Default)]
+pub struct MidiPorts {
+    /// Port MIDI clock and transport are followed from, the keyboard never sends any.
+    clock_input: Arc<Mutex<Option<String>>>,
+    /// Port the sequencer's MIDI tracks play out of, the keyboard ignores notes.
+    output: Arc<Mutex<Option<String>>>,
+    /// Port MIDI clock and transport are sent to as well as the keyboard, e.g. a drum machine.
+    clock_output: Arc<Mutex<Option<String>>>,
+}
+
+impl MidiPorts {
+    pub fn clock_input(&self) -> Option<String> {
+        self.clock_input
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn set_clock_input(&self, name: Option<String>) {
+        *self
+            .clock_input
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = name;
+    }
+
+    pub fn output(&self) -> Option<String> {
+        self.output
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn set_output(&self, name: Option<String>) {
+        *self
+            .output
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = name;
+    }
+
+    pub fn clock_output(&self) -> Option<String> {
+        self.clock_output
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn set_clock_output(&self, name: Option<String>) {
+        *self
+            .clock_output
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = name;
+    }
+}
+
+/// Returns whether a port can be chosen in the UI, the keyboard is always connected and this
+/// app's own ports would only loop back to it.
+fn is_choosable(name: &str) -> bool {
+    !name.contains(DEVICE_NAME) && !name.contains(CLIENT_NAME)
+}
+
+/// Names of the MIDI input ports that can be chosen in the UI.
+pub(crate) fn input_ports() -> Vec<String> {
+    match MidiInput::new(CLIENT_NAME) {
+        Ok(input) => input
+            .ports()
+            .iter()
+            .filter_map(|port| input.port_name(port).ok())
+            .filter(|name| is_choosable(name))
+            .collect(),
+        Err(e) => {
+            warn!("Failed to create MIDI input: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Names of the MIDI output ports that can be chosen in the UI.
+pub(crate) fn output_ports() -> Vec<String> {
+    match MidiOutput::new(CLIENT_NAME) {
+        Ok(output) => output
+            .ports()
+            .iter()
+            .filter_map(|port| output.port_name(port).ok())
+            .filter(|name| is_choosable(name))
+            .collect(),
+        Err(e) => {
+            warn!("Failed to create MIDI output: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Returns the port after (or before) `current` in `ports`, going through no port at all
+/// between the last and the first.
+pub(crate) fn next_port(ports: &[String], current: Option<&str>, forward: bool) -> Option<String> {
+    let index = current.and_then(|current| ports.iter().position(|name| name == current));
+    let index = match (index, forward) {
+        (None, true) => Some(0),
+        (None, false) => ports.len().checked_sub(1),
+        (Some(index), true) => Some(index + 1).filter(|&index| index < ports.len()),
+        (Some(index), false) => index.checked_sub(1),
+    };
+    index.and_then(|index| ports.get(index)).cloned()
+}
+
+/// Background service that connects to the keyboard and forwards its messages to the engine and
+/// the UI. The MIDI callback never blocks on the UI, messages are pushed onto the action queue.
+/// Everything played is handed to the recorder. MIDI clock and transport messages move the shared
+/// transport, but only from the port chosen in `MidiPorts` so there is never more than one
+/// source of clock.
+pub struct MidiService {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MidiService {
+    pub fn start(
+        voices: Voices,
+        actions: Arc<SegQueue<ActionMessage>>,
+        transport: Transport,
+        recorder: Recorder,
+        ports: MidiPorts,
+    ) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || watch(running, voices, actions, transport, recorder, ports))
+        };
+
+        Self {
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for MidiService {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Polls the available ports, connecting to the keyboard and the chosen clock input when they
+/// appear and dropping the connections when they go away.
+fn watch(
+    running: Arc<AtomicBool>,
+    voices: Voices,
+    actions: Arc<SegQueue<ActionMessage>>,
+    transport: Transport,
+    recorder: Recorder,
+    ports: MidiPorts,
+) {
+    let mut connection: Option<(String, MidiInputConnection<()>)> = None;
+    let mut clock: Option<(String, MidiInputConnection<()>)> = None;
+
+    while running.load(Ordering::Relaxed) {
+        let port_names = match MidiInput::new(CLIENT_NAME) {
+            Ok(input) => input
+                .ports()
+                .iter()
+                .filter_map(|port| input.port_name(port).ok())
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                warn!("Failed to create MIDI input: {}", e);
+                Vec::new()
+            }
+        };
+
+        if let Some((name, _)) = &connection {
+            if !port_names.contains(name) {
+                info!("MIDI device {} disconnected", name);
+                // Nothing will send the Note Offs for keys still held, so release them once the
+                // connection is closed and can't play anything more
+                connection = None;
+                voices.all_notes_off();
+            }
+        }
+
+        if connection.is_none() {
+            if let Some(name) = port_names.iter().find(|name| name.contains(DEVICE_NAME)) {
+                match connect_keyboard(name, voices.clone(), Arc::clone(&actions), recorder.clone())
+                {
+                    Ok(conn) => {
+                        info!("Connected to MIDI device {}", name);
+                        voices.all_notes_off();
+                        connection = Some((name.clone(), conn));
+                    }
+                    Err(e) => warn!("Failed to connect to MIDI device {}: {}", name, e),
+                }
+            }
+        }
+
+        let clock_input = ports.clock_input().filter(|name| port_names.contains(name));
+        if clock.as_ref().map(|(name, _)| name) != clock_input.as_ref() {
+            if let Some((name, _)) = clock.take() {
+                info!("Stopped following MIDI clock from {}", name);
+            }
+            if let Some(name) = clock_input {
+                let mut follower = Follower::new(transport.clone());
+                match connect(&name, move |_, message| follower.handle(&message)) {
+                    Ok(conn) => {
+                        info!("Following MIDI clock from {}", name);
+                        clock = Some((name, conn));
+                    }
+                    Err(e) => warn!("Failed to connect to MIDI device {}: {}", name, e),
+                }
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn connect_keyboard(
+    name: &str,
+    voices: Voices,
+    actions: Arc<SegQueue<ActionMessage>>,
+    recorder: Recorder,
+) -> anyhow::Result<MidiInputConnection<()>> {
+    connect(name, move |bytes, message| {
+        recorder.push_midi(bytes);
+        handle(&message, &voices, &actions);
+    })
+}
+
+/// Connects to input port `name`, calling `callback` with every message received on it.
+fn connect<F>(name: &str, mut callback: F) -> anyhow::Result<MidiInputConnection<()>>
+where
+    F: FnMut(&[u8], MidiMessage) + Send + 'static,
+{
+    let mut input = MidiInput::new(CLIENT_NAME)?;
+    input.ignore(Ignore::None);
+
+    let port = input
+        .ports()
+        .into_iter()
+        .find(|port| input.port_name(port).ok().as_deref() == Some(name))
+        .ok_or_else(|| anyhow::anyhow!("Port {} not found", name))?;
+
+    let connection = input
+        .connect(
+            &port,
+            CLIENT_NAME,
+            move |_, bytes, _| match MidiMessage::try_from(bytes) {
+                Ok(message) => callback(bytes, message),
+                Err(e) => warn!("Failed to parse MIDI message {:?}: {:?}", bytes, e),
+            },
+            (),
+        )
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    Ok(connection)
+}
+
+fn handle(message: &MidiMessage, voices: &Voices, actions: &SegQueue<ActionMessage>) {
+    match *message {
+        MidiMessage::NoteOn(_, note, velocity) if u8::from(velocity) == 0 => {
+            voices.note_off(note);
+            actions.push(ActionMessage::NoteOff(note));
+        }
+        MidiMessage::NoteOn(_, note, velocity) => {
+            voices.note_on(note, velocity);
+            actions.push(ActionMessage::NoteOn(note, velocity));
+        }
+        MidiMessage::NoteOff(_, note, _) => {
+            voices.note_off(note);
+            actions.push(ActionMessage::NoteOff(note));
+        }
+        MidiMessage::ControlChange(_, function, value) => {
+            if function == ControlFunction::ALL_NOTES_OFF {
+                voices.all_notes_off();
+            }
+            actions.push(ActionMessage::ControlChange(function, value));
+        }
+        _ => {}
+    }
+}
+
+/// Picks one of the ports chosen in `MidiPorts`, e.g. `MidiPorts::output`.
+type PortChoice = fn(&MidiPorts) -> Option<String>;
+
+/// Connection to a MIDI output, reconnected when it's plugged back in. The default output is the
+/// keyboard's MIDI input.
+#[derive(Default)]
+pub(crate) struct Output {
+    /// Where the chosen port is kept and which of its choices to follow, or `None` to send to
+    /// the keyboard.
+    chosen: Option<(MidiPorts, PortChoice)>,
+    connection: Option<(String, MidiOutputConnection)>,
+    last_poll: Option<Instant>,
+}
+
+impl Output {
+    /// Output to the port `choice` picks from `ports`, e.g. `MidiPorts::output`, following it as
+    /// the choice changes.
+    pub(crate) fn chosen(ports: MidiPorts, choice: PortChoice) -> Self {
+        Self {
+            chosen: Some((ports, choice)),
+            ..Self::default()
+        }
+    }
+
+    /// Checks the port is still there and still the one wanted, at most every `POLL_INTERVAL`.
+    pub(crate) fn poll(&mut self) {
+        if self
+            .last_poll
+            .is_some_and(|last| last.elapsed() < POLL_INTERVAL)
+        {
+            return;
+        }
+        self.last_poll = Some(Instant::now());
+
+        let output = match MidiOutput::new(CLIENT_NAME) {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Failed to create MIDI output: {}", e);
+                return;
+            }
+        };
+        let chosen = self.chosen.as_ref().map(|(ports, choice)| choice(ports));
+        let port = output.ports().into_iter().find_map(|port| {
+            let name = output.port_name(&port).ok()?;
+            let wanted = match &chosen {
+                Some(chosen) => chosen.as_ref() == Some(&name),
+                None => name.contains(DEVICE_NAME),
+            };
+            wanted.then_some((name, port))
+        });
+
+        match (&self.connection, port) {
+            (Some((connected, _)), Some((name, _))) if *connected == name => {}
+            (_, Some((name, port))) => match output.connect(&port, CLIENT_NAME) {
+                Ok(connection) => {
+                    info!("Connected to MIDI output {}", name);
+                    self.connection = Some((name, connection));
+                }
+                Err(e) => warn!("Failed to connect to MIDI output {}: {}", name, e),
+            },
+            (Some((name, _)), None) => {
+                info!("MIDI output {} disconnected", name);
+                self.connection = None;
+            }
+            (None, None) => {}
+        }
+    }
+
+    pub(crate) fn send(&mut self, message: &MidiMessage) {
+        if let Some((name, connection)) = &mut self.connection {
+            if let Err(e) = connection.send(&message.to_vec()) {
+                warn!("Failed to send MIDI to {}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_port_goes_through_no_port_between_the_ends() {
+        let ports = ["A".to_string(), "B".to_string()];
+        assert_eq!(next_port(&ports, None, true).as_deref(), Some("A"));
+        assert_eq!(next_port(&ports, Some("A"), true).as_deref(), Some("B"));
+        assert_eq!(next_port(&ports, Some("B"), true), None);
+        assert_eq!(next_port(&ports, None, false).as_deref(), Some("B"));
+        assert_eq!(next_port(&ports, Some("A"), false), None);
+        // A port that has been unplugged starts again from the first
+        assert_eq!(next_port(&ports, Some("C"), true).as_deref(), Some("A"));
+        assert_eq!(next_port(&[], None, true), None);
+    }
+}
@@ -0,0 +1,697 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, bail, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::{
+    app::{State, DEFAULT_ATTACK, DEFAULT_DECAY, DEFAULT_RELEASE, DEFAULT_SUSTAIN},
+    engine::{
+        effects::{EffectKind, Effects, NUM_SLOTS},
+        filter::{Filter, FilterMode, FilterSlope},
+        lfo::{Lfo, LfoDestination, LfoShape, DIVISIONS},
+    },
+    files::FileDir,
+    sample::Sample,
+};
+
+/// Version of the preset format written by this build. When the format changes, bump this and add
+/// a step to `MIGRATIONS` that upgrades presets saved by the version before.
+pub const PRESET_VERSION: i64 = 1;
+/// Steps that upgrade older presets, `MIGRATIONS[0]` takes version 1 to version 2 and so on.
+/// Parameters that are only added don't need a step, they take their default when missing.
+const MIGRATIONS: [fn(&mut Table); PRESET_VERSION as usize - 1] = [];
+/// Directory presets are loaded from and saved to.
+pub const PRESETS: FileDir = FileDir::new("presets", "SYNTH_PRESETS_DIR", "toml");
+/// New presets are named this followed by a number, e.g. `preset-001.toml`.
+pub const PRESET_PREFIX: &str = "preset-";
+
+/// Returns the division named `name`, for LFOs and delays synced to the tempo. Divisions are
+/// saved by name so presets don't depend on the order of `DIVISIONS`.
+fn division_index(name: &str) -> Option<usize> {
+    DIVISIONS.iter().position(|(_, division)| *division == name)
+}
+
+fn division_name(index: usize) -> String {
+    DIVISIONS[index.min(DIVISIONS.len() - 1)].1.to_string()
+}
+
+/// Every setting that makes up a sound. Anything missing from a file takes its default, so
+/// presets saved before a parameter existed still load.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    pub version: i64,
+    /// Sample played in place of the oscillator, if there is one
+    pub sample: Option<SamplePreset>,
+    pub envelope: EnvelopePreset,
+    pub filter: FilterPreset,
+    pub lfos: Vec<LfoPreset>,
+    pub effects: EffectsPreset,
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self {
+            version: PRESET_VERSION,
+            sample: None,
+            envelope: EnvelopePreset::default(),
+            filter: FilterPreset::default(),
+            lfos: Lfo::defaults().iter().map(LfoPreset::capture).collect(),
+            effects: EffectsPreset::default(),
+        }
+    }
+}
+
+impl Preset {
+    /// Reads the current sound from `state`.
+    pub fn capture(state: &State) -> Self {
+        Self {
+            version: PRESET_VERSION,
+            sample: state
+                .sample
+                .get()
+                .map(|sample| SamplePreset::capture(&sample)),
+            envelope: EnvelopePreset {
+                attack: state.attack.value(),
+                decay: state.decay.value(),
+                sustain: state.sustain.value(),
+                release: state.release.value(),
+            },
+            filter: FilterPreset::capture(&state.filter),
+            lfos: state.lfos.iter().map(LfoPreset::capture).collect(),
+            effects: EffectsPreset::capture(&state.effects),
+        }
+    }
+
+    /// Sets `state` to this sound. A sample that can't be loaded is left out and the voices play
+    /// their oscillator instead.
+    pub fn apply(&self, state: &State) {
+        let envelope = &self.envelope;
+        state.attack.set_value(envelope.attack.max(0.0));
+        state.decay.set_value(envelope.decay.max(0.0));
+        state.sustain.set_value(envelope.sustain.clamp(0.0, 1.0));
+        state.release.set_value(envelope.release.max(0.0));
+        self.filter.apply(&state.filter);
+        for (index, lfo) in state.lfos.iter().enumerate() {
+            match self.lfos.get(index) {
+                Some(preset) => preset.apply(lfo),
+                None => LfoPreset::capture(&Lfo::defaults()[index]).apply(lfo),
+            }
+        }
+        self.effects.apply(&state.effects);
+
+        match &self.sample {
+            Some(preset) => preset.apply(state),
+            None => state.sample.set(None),
+        }
+    }
+
+    /// Loads a preset, upgrading it first if it was saved by an older version.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Failed to load {}", path.display()))
+    }
+
+    /// Reads a preset from the text of a preset file.
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut table: Table = text.parse().context("Failed to parse the preset")?;
+        migrate(&mut table)?;
+        Value::Table(table)
+            .try_into()
+            .context("Failed to read the preset's settings")
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let text = toml::to_string_pretty(self).context("Failed to write the preset")?;
+        fs::write(path, text).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Brings a preset saved by an older version up to `PRESET_VERSION`, one version at a time.
+/// Presets from a newer version are refused rather than guessed at.
+fn migrate(table: &mut Table) -> anyhow::Result<()> {
+    let version = match table.get("version") {
+        Some(version) => version
+            .as_integer()
+            .ok_or_else(|| anyhow!("The preset version isn't a number"))?,
+        None => bail!("The preset has no version"),
+    };
+    if version < 1 {
+        bail!("Unknown preset version {}", version);
+    }
+    if version > PRESET_VERSION {
+        bail!(
+            "The preset is version {}, this version only reads up to {}",
+            version,
+            PRESET_VERSION
+        );
+    }
+
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(table);
+    }
+    table.insert("version".to_string(), Value::Integer(PRESET_VERSION));
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvelopePreset {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Default for EnvelopePreset {
+    fn default() -> Self {
+        Self {
+            attack: DEFAULT_ATTACK,
+            decay: DEFAULT_DECAY,
+            sustain: DEFAULT_SUSTAIN,
+            release: DEFAULT_RELEASE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterPreset {
+    pub cutoff: f64,
+    pub resonance: f64,
+    pub envelope_amount: f64,
+    pub key_tracking: f64,
+    pub mode: FilterMode,
+    pub slope: FilterSlope,
+}
+
+impl Default for FilterPreset {
+    fn default() -> Self {
+        Self::capture(&Filter::default())
+    }
+}
+
+impl FilterPreset {
+    fn capture(filter: &Filter) -> Self {
+        Self {
+            cutoff: filter.cutoff.value(),
+            resonance: filter.resonance.value(),
+            envelope_amount: filter.envelope_amount.value(),
+            key_tracking: filter.key_tracking.value(),
+            mode: filter.mode(),
+            slope: filter.slope(),
+        }
+    }
+
+    fn apply(&self, filter: &Filter) {
+        filter.set_cutoff(self.cutoff);
+        filter.resonance.set_value(self.resonance.clamp(0.0, 1.0));
+        filter
+            .envelope_amount
+            .set_value(self.envelope_amount.clamp(-1.0, 1.0));
+        filter.key_tracking.set_value(self.key_tracking);
+        filter.set_mode(self.mode);
+        filter.set_slope(self.slope);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LfoPreset {
+    pub shape: LfoShape,
+    pub destination: LfoDestination,
+    pub rate: f64,
+    pub depth: f64,
+    pub synced: bool,
+    /// Name of the division in `DIVISIONS` while synced
+    pub division: String,
+    pub retrigger: bool,
+}
+
+impl Default for LfoPreset {
+    fn default() -> Self {
+        Self::capture(&Lfo::defaults()[0])
+    }
+}
+
+impl LfoPreset {
+    fn capture(lfo: &Lfo) -> Self {
+        Self {
+            shape: lfo.shape(),
+            destination: lfo.destination(),
+            rate: lfo.rate.value(),
+            depth: lfo.depth.value(),
+            synced: lfo.is_synced(),
+            division: division_name(lfo.division()),
+            retrigger: lfo.retriggers(),
+        }
+    }
+
+    fn apply(&self, lfo: &Lfo) {
+        lfo.set_shape(self.shape);
+        lfo.set_destination(self.destination);
+        lfo.set_rate(self.rate);
+        lfo.depth.set_value(self.depth.clamp(0.0, 1.0));
+        lfo.set_synced(self.synced);
+        if let Some(division) = division_index(&self.division) {
+            lfo.set_division(division);
+        }
+        lfo.set_retrigger(self.retrigger);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectsPreset {
+    /// The effect in each slot of the rack, first to last
+    pub order: Vec<EffectKind>,
+    pub drive: DrivePreset,
+    pub chorus: ChorusPreset,
+    pub delay: DelayPreset,
+    pub reverb: ReverbPreset,
+}
+
+impl Default for EffectsPreset {
+    fn default() -> Self {
+        Self::capture(&Effects::default())
+    }
+}
+
+impl EffectsPreset {
+    fn capture(effects: &Effects) -> Self {
+        let bypassed = |kind| effects.is_bypassed(kind);
+        let mix = |kind| effects.mix(kind).value();
+        Self {
+            order: effects.order().to_vec(),
+            drive: DrivePreset {
+                bypassed: bypassed(EffectKind::Drive),
+                mix: mix(EffectKind::Drive),
+                drive: effects.drive.drive.value(),
+                bits: effects.drive.bits.value(),
+                downsample: effects.drive.downsample.value(),
+            },
+            chorus: ChorusPreset {
+                bypassed: bypassed(EffectKind::Chorus),
+                mix: mix(EffectKind::Chorus),
+                rate: effects.chorus.rate.value(),
+                depth: effects.chorus.depth.value(),
+                feedback: effects.chorus.feedback.value(),
+                flanger: effects.chorus.is_flanger(),
+            },
+            delay: DelayPreset {
+                bypassed: bypassed(EffectKind::Delay),
+                mix: mix(EffectKind::Delay),
+                time: effects.delay.time.value(),
+                feedback: effects.delay.feedback.value(),
+                synced: effects.delay.is_synced(),
+                division: division_name(effects.delay.division()),
+            },
+            reverb: ReverbPreset {
+                bypassed: bypassed(EffectKind::Reverb),
+                mix: mix(EffectKind::Reverb),
+                size: effects.reverb.size.value(),
+                damping: effects.reverb.damping.value(),
+            },
+        }
+    }
+
+    fn apply(&self, effects: &Effects) {
+        if let Ok(order) = <[EffectKind; NUM_SLOTS]>::try_from(self.order.as_slice()) {
+            effects.set_order(order);
+        }
+        let set = |kind, bypassed, mix: f64| {
+            effects.set_bypassed(kind, bypassed);
+            effects.mix(kind).set_value(mix.clamp(0.0, 1.0));
+        };
+
+        let drive = &self.drive;
+        set(EffectKind::Drive, drive.bypassed, drive.mix);
+        effects.drive.drive.set_value(drive.drive.clamp(0.0, 1.0));
+        effects.drive.bits.set_value(drive.bits);
+        effects.drive.downsample.set_value(drive.downsample);
+
+        let chorus = &self.chorus;
+        set(EffectKind::Chorus, chorus.bypassed, chorus.mix);
+        effects.chorus.rate.set_value(chorus.rate);
+        effects.chorus.depth.set_value(chorus.depth.clamp(0.0, 1.0));
+        effects.chorus.feedback.set_value(chorus.feedback);
+        effects.chorus.set_flanger(chorus.flanger);
+
+        let delay = &self.delay;
+        set(EffectKind::Delay, delay.bypassed, delay.mix);
+        effects.delay.time.set_value(delay.time);
+        effects.delay.feedback.set_value(delay.feedback);
+        effects.delay.set_synced(delay.synced);
+        if let Some(division) = division_index(&delay.division) {
+            effects.delay.set_division(division);
+        }
+
+        let reverb = &self.reverb;
+        set(EffectKind::Reverb, reverb.bypassed, reverb.mix);
+        effects.reverb.size.set_value(reverb.size.clamp(0.0, 1.0));
+        effects
+            .reverb
+            .damping
+            .set_value(reverb.damping.clamp(0.0, 1.0));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DrivePreset {
+    pub bypassed: bool,
+    pub mix: f64,
+    pub drive: f64,
+    pub bits: f64,
+    pub downsample: f64,
+}
+
+impl Default for DrivePreset {
+    fn default() -> Self {
+        EffectsPreset::default().drive
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChorusPreset {
+    pub bypassed: bool,
+    pub mix: f64,
+    pub rate: f64,
+    pub depth: f64,
+    pub feedback: f64,
+    pub flanger: bool,
+}
+
+impl Default for ChorusPreset {
+    fn default() -> Self {
+        EffectsPreset::default().chorus
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DelayPreset {
+    pub bypassed: bool,
+    pub mix: f64,
+    pub time: f64,
+    pub feedback: f64,
+    pub synced: bool,
+    /// Name of the division in `DIVISIONS` while synced
+    pub division: String,
+}
+
+impl Default for DelayPreset {
+    fn default() -> Self {
+        EffectsPreset::default().delay
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverbPreset {
+    pub bypassed: bool,
+    pub mix: f64,
+    pub size: f64,
+    pub damping: f64,
+}
+
+impl Default for ReverbPreset {
+    fn default() -> Self {
+        EffectsPreset::default().reverb
+    }
+}
+
+/// The sample file and the points it plays between. Edits to the audio itself have to be saved
+/// from the Edit screen, a preset only refers to the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplePreset {
+    pub path: PathBuf,
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub looping: bool,
+}
+
+impl SamplePreset {
+    fn capture(sample: &Sample) -> Self {
+        Self {
+            path: sample.path.clone(),
+            start: sample.start(),
+            end: sample.end(),
+            loop_start: sample.loop_start(),
+            loop_end: sample.loop_end(),
+            looping: sample.looping,
+        }
+    }
+
+    /// Loads the sample unless it is already the one playing, then sets its points.
+    fn apply(&self, state: &State) {
+        let loaded = state
+            .sample
+            .get()
+            .is_some_and(|sample| sample.path == self.path);
+        if !loaded {
+            match Sample::load(&self.path) {
+                Ok(sample) => state.sample.set(Some(sample)),
+                Err(e) => {
+                    warn!("Failed to load the preset's sample: {:#}", e);
+                    state.sample.set(None);
+                    return;
+                }
+            }
+        }
+        state.sample.edit(|sample| {
+            // Open the end up first so the start can move past where the end was
+            sample.set_end(sample.len());
+            sample.set_start(self.start);
+            sample.set_end(self.end);
+            sample.set_loop_start(self.loop_start);
+            sample.set_loop_end(self.loop_end);
+            sample.looping = self.looping;
+        });
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    /// The preset last loaded or saved, as it is on disk.
+    loaded: Option<(PathBuf, Preset)>,
+    /// The sound as it was edited, kept while comparing it with the loaded preset.
+    edited: Option<Preset>,
+}
+
+/// Cloneable handle to the loaded preset, shared so comparing carries on while other screens are
+/// open.
+#[derive(Clone, Default)]
+pub struct PresetSlot {
+    session: Arc<Mutex<Session>>,
+}
+
+impl PresetSlot {
+    fn lock(&self) -> MutexGuard<'_, Session> {
+        self.session
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns the file of the preset last loaded or saved.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.lock().loaded.as_ref().map(|(path, _)| path.clone())
+    }
+
+    /// Returns whether the loaded preset is playing in place of the edited sound.
+    pub fn is_comparing(&self) -> bool {
+        self.lock().edited.is_some()
+    }
+
+    /// Returns whether the sound has been changed since the preset was loaded or saved.
+    pub fn is_modified(&self, state: &State) -> bool {
+        let session = self.lock();
+        let Some((_, preset)) = &session.loaded else {
+            return false;
+        };
+        match &session.edited {
+            Some(edited) => preset != edited,
+            None => *preset != Preset::capture(state),
+        }
+    }
+
+    pub fn load(&self, path: &Path, state: &State) -> anyhow::Result<()> {
+        let preset = Preset::load(path)?;
+        preset.apply(state);
+        info!("Loaded preset {}", path.display());
+        *self.lock() = Session {
+            loaded: Some((path.to_path_buf(), preset)),
+            edited: None,
+        };
+        Ok(())
+    }
+
+    /// Saves the edited sound, even while the loaded preset is being compared with it.
+    pub fn save(&self, path: &Path, state: &State) -> anyhow::Result<()> {
+        let mut session = self.lock();
+        let preset = match session.edited.take() {
+            Some(edited) => {
+                edited.apply(state);
+                edited
+            }
+            None => Preset::capture(state),
+        };
+        preset.save(path)?;
+        info!("Saved preset {}", path.display());
+        session.loaded = Some((path.to_path_buf(), preset));
+        Ok(())
+    }
+
+    /// Starts again from the default sound, forgetting the loaded preset.
+    pub fn init(&self, state: &State) {
+        Preset::default().apply(state);
+        *self.lock() = Session::default();
+    }
+
+    /// Switches between the edited sound and the loaded preset as it was saved.
+    pub fn compare(&self, state: &State) {
+        let mut session = self.lock();
+        if let Some(edited) = session.edited.take() {
+            edited.apply(state);
+        } else if let Some((_, preset)) = &session.loaded {
+            let edited = Preset::capture(state);
+            preset.apply(state);
+            session.edited = Some(edited);
+        }
+    }
+
+    /// Throws away any changes, going back to the loaded preset.
+    pub fn revert(&self, state: &State) {
+        let mut session = self.lock();
+        if let Some((_, preset)) = &session.loaded {
+            preset.apply(state);
+        }
+        session.edited = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A preset saved by version 1, it must keep loading as the format moves on.
+    const V1: &str = include_str!("../tests/fixtures/preset-v1.toml");
+
+    fn error(text: &str) -> String {
+        format!("{:#}", Preset::parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn saved_presets_load_back_the_same() {
+        let state = State::default();
+        state.attack.set_value(0.25);
+        state.filter.set_mode(FilterMode::HighPass);
+        state.lfos[0].set_shape(LfoShape::SampleAndHold);
+        state.lfos[1].set_synced(true);
+        state.lfos[1].set_division(3);
+        state.effects.set_order([
+            EffectKind::Reverb,
+            EffectKind::Delay,
+            EffectKind::Chorus,
+            EffectKind::Drive,
+        ]);
+        state.effects.set_bypassed(EffectKind::Drive, false);
+        let saved = Preset::capture(&state);
+
+        let path = std::env::temp_dir()
+            .join(format!("synth-preset-test-{}", std::process::id()))
+            .join("round-trip.toml");
+        saved.save(&path).unwrap();
+        let loaded = Preset::load(&path);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded, saved);
+
+        let other = State::default();
+        loaded.apply(&other);
+        assert_eq!(Preset::capture(&other), saved);
+    }
+
+    #[test]
+    fn version_1_presets_load() {
+        let preset = Preset::parse(V1).unwrap();
+        assert_eq!(preset.version, PRESET_VERSION);
+
+        let sample = preset.sample.unwrap();
+        assert_eq!(sample.path, PathBuf::from("samples/pad.wav"));
+        assert_eq!((sample.start, sample.end), (1200, 96000));
+        assert!(sample.looping);
+
+        assert_eq!(preset.envelope.release, 1.5);
+        assert_eq!(preset.filter.mode, FilterMode::BandPass);
+        assert_eq!(preset.filter.slope, FilterSlope::Db24);
+
+        assert_eq!(preset.lfos.len(), 2);
+        assert_eq!(preset.lfos[0].shape, LfoShape::SampleAndHold);
+        assert_eq!(preset.lfos[1].destination, LfoDestination::Pan);
+        assert_eq!(preset.lfos[1].division, "1 bar");
+
+        assert_eq!(preset.effects.order[0], EffectKind::Reverb);
+        assert!(preset.effects.chorus.flanger);
+        assert_eq!(preset.effects.delay.division, "1/8");
+        assert!(preset.effects.reverb.bypassed);
+    }
+
+    #[test]
+    fn presets_without_a_known_version_are_refused() {
+        assert_eq!(
+            error("[envelope]\nattack = 0.5\n"),
+            "The preset has no version"
+        );
+        assert_eq!(error("version = 0\n"), "Unknown preset version 0");
+        assert_eq!(error("version = -3\n"), "Unknown preset version -3");
+        assert_eq!(
+            error("version = \"1\"\n"),
+            "The preset version isn't a number"
+        );
+        assert_eq!(
+            error(&format!("version = {}\n", PRESET_VERSION + 1)),
+            format!(
+                "The preset is version {}, this version only reads up to {}",
+                PRESET_VERSION + 1,
+                PRESET_VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        assert_eq!(Preset::parse("version = 1\n").unwrap(), Preset::default());
+
+        let preset = Preset::parse(
+            "version = 1\n\
+             [envelope]\n\
+             attack = 0.5\n\
+             [effects.delay]\n\
+             feedback = 0.7\n",
+        )
+        .unwrap();
+        assert_eq!(preset.envelope.attack, 0.5);
+        assert_eq!(preset.envelope.decay, DEFAULT_DECAY);
+        assert_eq!(preset.effects.delay.feedback, 0.7);
+        assert_eq!(preset.effects.delay.mix, DelayPreset::default().mix);
+        assert_eq!(preset.effects.reverb, ReverbPreset::default());
+        assert_eq!(preset.filter, FilterPreset::default());
+        assert_eq!(preset.lfos, Preset::default().lfos);
+        assert_eq!(preset.sample, None);
+    }
+}
@@ -0,0 +1,724 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam::queue::{ArrayQueue, SegQueue};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::{info, warn};
+
+use crate::{
+    clock::Transport,
+    files::{next_free, FileDir},
+};
+
+/// Directory recordings are saved to.
+pub const RECORDINGS: FileDir = FileDir::new("recordings", "SYNTH_RECORDINGS_DIR", "wav");
+/// Recordings are named this followed by a number, e.g. `take-001.wav`.
+const TAKE_PREFIX: &str = "take-";
+/// Frames the audio thread can get ahead of the writer before it starts dropping them, about 2.7
+/// seconds at 48 kHz.
+const QUEUE_SIZE: usize = 1 << 17;
+/// An armed recorder starts once the output gets louder than this, about -60 dB.
+const ARM_THRESHOLD: f32 = 0.001;
+/// How often the writer empties the queue.
+const WRITE_INTERVAL: Duration = Duration::from_millis(20);
+/// Ticks per quarter note in the MIDI file.
+const TICKS_PER_BEAT: u16 = 480;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    Int16 = 0,
+    Int24 = 1,
+    Float = 2,
+}
+
+impl RecordFormat {
+    pub fn next(&self) -> Self {
+        match *self {
+            RecordFormat::Int16 => RecordFormat::Int24,
+            RecordFormat::Int24 => RecordFormat::Float,
+            RecordFormat::Float => RecordFormat::Int16,
+        }
+    }
+
+    fn spec(&self, sample_rate: u32) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            RecordFormat::Int16 => (16, SampleFormat::Int),
+            RecordFormat::Int24 => (24, SampleFormat::Int),
+            RecordFormat::Float => (32, SampleFormat::Float),
+        };
+        WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+impl fmt::Display for RecordFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordFormat::Int16 => write!(f, "16 bit"),
+            RecordFormat::Int24 => write!(f, "24 bit"),
+            RecordFormat::Float => write!(f, "32 bit float"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordState {
+    Idle = 0,
+    /// Waiting for the output to make a sound before recording.
+    Armed = 1,
+    Recording = 2,
+}
+
+impl RecordState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => RecordState::Armed,
+            2 => RecordState::Recording,
+            _ => RecordState::Idle,
+        }
+    }
+}
+
+impl fmt::Display for RecordState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordState::Idle => write!(f, "Stopped"),
+            RecordState::Armed => write!(f, "Armed"),
+            RecordState::Recording => write!(f, "Recording"),
+        }
+    }
+}
+
+struct Inner {
+    state: AtomicU8,
+    format: AtomicU8,
+    midi: AtomicBool,
+    /// Counts the recordings started, everything queued is tagged with the take it belongs to so
+    /// a take stopped and another started before the writer catches up go to separate files.
+    take: AtomicU32,
+    /// Stereo frames waiting to be written, the audio thread never waits on this.
+    frames: ArrayQueue<(u32, f32, f32)>,
+    /// MIDI messages waiting to be written, with the frame of the recording they arrived at.
+    events: SegQueue<(u32, u64, Vec<u8>)>,
+    /// Frames since the recording started.
+    recorded: AtomicU64,
+    /// Frames lost because the writer fell behind.
+    dropped: AtomicUsize,
+    sample_rate: AtomicU32,
+    /// The file being recorded to, or the last one recorded.
+    path: Mutex<Option<PathBuf>>,
+}
+
+/// Records the engine's output to a WAV file, and optionally the MIDI coming in from the
+/// keyboard to a MIDI file next to it. The audio thread hands frames over through a lock-free
+/// queue and `RecorderService` writes them to disk.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Inner>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: AtomicU8::new(RecordState::Idle as u8),
+                format: AtomicU8::new(RecordFormat::Int24 as u8),
+                midi: AtomicBool::new(false),
+                take: AtomicU32::new(0),
+                frames: ArrayQueue::new(QUEUE_SIZE),
+                events: SegQueue::new(),
+                recorded: AtomicU64::new(0),
+                dropped: AtomicUsize::new(0),
+                sample_rate: AtomicU32::new(44_100),
+                path: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+impl Recorder {
+    pub fn state(&self) -> RecordState {
+        RecordState::from_u8(self.inner.state.load(Ordering::Acquire))
+    }
+
+    /// The take being recorded, or the last one.
+    fn take(&self) -> u32 {
+        self.inner.take.load(Ordering::Relaxed)
+    }
+
+    /// Starts a new take, the take is counted before the state changes so anything seeing the
+    /// new state also sees the new take.
+    fn start_take(&self) {
+        self.inner.recorded.store(0, Ordering::Relaxed);
+        self.inner.take.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .state
+            .store(RecordState::Recording as u8, Ordering::Release);
+    }
+
+    /// Starts recording as soon as there is any sound.
+    pub fn arm(&self) {
+        self.inner.dropped.store(0, Ordering::Relaxed);
+        let _ = self.inner.state.compare_exchange(
+            RecordState::Idle as u8,
+            RecordState::Armed as u8,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Starts recording straight away.
+    pub fn record(&self) {
+        if self.state() == RecordState::Idle {
+            self.inner.dropped.store(0, Ordering::Relaxed);
+        }
+        if self.state() != RecordState::Recording {
+            self.start_take();
+        }
+    }
+
+    pub fn stop(&self) {
+        self.inner
+            .state
+            .store(RecordState::Idle as u8, Ordering::Release);
+    }
+
+    pub fn format(&self) -> RecordFormat {
+        match self.inner.format.load(Ordering::Relaxed) {
+            0 => RecordFormat::Int16,
+            2 => RecordFormat::Float,
+            _ => RecordFormat::Int24,
+        }
+    }
+
+    /// Sets the format of the next recording, the current one carries on in its own format.
+    pub fn set_format(&self, format: RecordFormat) {
+        self.inner.format.store(format as u8, Ordering::Relaxed);
+    }
+
+    pub fn records_midi(&self) -> bool {
+        self.inner.midi.load(Ordering::Relaxed)
+    }
+
+    pub fn set_record_midi(&self, midi: bool) {
+        self.inner.midi.store(midi, Ordering::Relaxed);
+    }
+
+    /// Returns how long the current recording has been going, in seconds.
+    pub fn seconds(&self) -> f64 {
+        self.inner.recorded.load(Ordering::Relaxed) as f64
+            / f64::from(self.inner.sample_rate.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many frames have been lost because the disk couldn't keep up.
+    pub fn dropped(&self) -> usize {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the file being recorded to, or the last one recorded.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.inner
+            .path
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_sample_rate(&self, sample_rate: u32) {
+        self.inner.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Hands a frame of output to the writer, only the audio thread calls this.
+    pub(crate) fn push(&self, left: f32, right: f32) {
+        match self.state() {
+            RecordState::Idle => return,
+            RecordState::Armed if left.abs().max(right.abs()) < ARM_THRESHOLD => return,
+            RecordState::Armed => self.start_take(),
+            RecordState::Recording => {}
+        }
+        if self.inner.frames.push((self.take(), left, right)).is_err() {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.recorded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Keeps an incoming MIDI message for the MIDI file, if one is being recorded. System
+    /// messages such as clock are left out.
+    pub(crate) fn push_midi(&self, bytes: &[u8]) {
+        if !self.records_midi()
+            || self.state() != RecordState::Recording
+            || bytes.first().is_none_or(|&status| status >= 0xF0)
+        {
+            return;
+        }
+        let frame = self.inner.recorded.load(Ordering::Relaxed);
+        self.inner.events.push((self.take(), frame, bytes.to_vec()));
+    }
+}
+
+/// Writes a variable length quantity, seven bits to a byte with the top bit set on all but the
+/// last.
+fn write_vlq(track: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    track.extend(bytes.iter().rev());
+}
+
+/// Returns the MIDI file tick `frame` falls on, at `tempo`.
+fn frame_to_tick(frame: u64, sample_rate: u32, tempo: f64) -> u32 {
+    let ticks_per_frame = tempo / 60.0 * f64::from(TICKS_PER_BEAT) / f64::from(sample_rate);
+    (frame as f64 * ticks_per_frame).round() as u32
+}
+
+/// Encodes `events` as a single track Standard MIDI File at `tempo`, converting the frames they
+/// arrived at into ticks.
+fn encode_smf(events: &[(u64, Vec<u8>)], sample_rate: u32, tempo: f64) -> Vec<u8> {
+    let mut track = Vec::new();
+
+    // Tempo in microseconds per quarter note
+    let micros = (60_000_000.0 / tempo).round() as u32;
+    track.extend([0x00, 0xFF, 0x51, 0x03]);
+    track.extend(&micros.to_be_bytes()[1..]);
+
+    let mut last = 0;
+    for (frame, bytes) in events {
+        let tick = frame_to_tick(*frame, sample_rate, tempo);
+        write_vlq(&mut track, tick.saturating_sub(last));
+        track.extend(bytes);
+        last = last.max(tick);
+    }
+    // End of track
+    track.extend([0x00, 0xFF, 0x2F, 0x00]);
+
+    let mut smf = Vec::with_capacity(track.len() + 22);
+    smf.extend(b"MThd");
+    smf.extend(6u32.to_be_bytes());
+    // Format 0, one track
+    smf.extend(0u16.to_be_bytes());
+    smf.extend(1u16.to_be_bytes());
+    smf.extend(TICKS_PER_BEAT.to_be_bytes());
+    smf.extend(b"MTrk");
+    smf.extend((track.len() as u32).to_be_bytes());
+    smf.extend(track);
+    smf
+}
+
+/// Writes `events` to a Standard MIDI File at `path`.
+fn write_smf(
+    path: &Path,
+    events: &[(u64, Vec<u8>)],
+    sample_rate: u32,
+    tempo: f64,
+) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&encode_smf(events, sample_rate, tempo))?;
+    file.flush()?;
+    Ok(())
+}
+
+/// A recording in progress.
+struct Take {
+    /// Which of the recorder's takes this is.
+    number: u32,
+    path: PathBuf,
+    writer: WavWriter<BufWriter<File>>,
+    format: RecordFormat,
+    midi: bool,
+    events: Vec<(u64, Vec<u8>)>,
+    sample_rate: u32,
+    /// Tempo when the recording started, the MIDI file is written at this tempo.
+    tempo: f64,
+}
+
+impl Take {
+    fn start(
+        number: u32,
+        dir: &Path,
+        recorder: &Recorder,
+        transport: &Transport,
+    ) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = next_free(dir, TAKE_PREFIX, &["wav", "mid"]);
+        let format = recorder.format();
+        let sample_rate = recorder.inner.sample_rate.load(Ordering::Relaxed);
+        let writer = WavWriter::create(&path, format.spec(sample_rate))
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        Ok(Self {
+            number,
+            path,
+            writer,
+            format,
+            midi: recorder.records_midi(),
+            events: Vec::new(),
+            sample_rate,
+            tempo: transport.tempo.value(),
+        })
+    }
+
+    fn write(&mut self, left: f32, right: f32) -> hound::Result<()> {
+        for sample in [left, right] {
+            let sample = sample.clamp(-1.0, 1.0);
+            match self.format {
+                RecordFormat::Int16 => self
+                    .writer
+                    .write_sample((sample * f32::from(i16::MAX)) as i16)?,
+                RecordFormat::Int24 => self.writer.write_sample((sample * 8_388_607.0) as i32)?,
+                RecordFormat::Float => self.writer.write_sample(sample)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        self.writer
+            .finalize()
+            .with_context(|| format!("Failed to finish {}", self.path.display()))?;
+        info!("Saved recording {}", self.path.display());
+
+        if self.midi {
+            let path = self.path.with_extension("mid");
+            write_smf(&path, &self.events, self.sample_rate, self.tempo)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            info!("Saved MIDI recording {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Background service that writes what the recorder captures to disk, so the audio thread never
+/// waits on the file system.
+pub struct RecorderService {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RecorderService {
+    pub fn start(recorder: Recorder, transport: Transport) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || write(running, recorder, transport))
+        };
+
+        Self {
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for RecorderService {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Opens a file for each take, writes everything queued up for it and finishes the file once it
+/// stops or the next take starts.
+struct Writer {
+    dir: PathBuf,
+    take: Option<Take>,
+    /// The latest take a file was opened for, or that failed to open. Anything still queued for it
+    /// or an earlier take once its file is closed is thrown away.
+    latest: Option<u32>,
+    /// MIDI messages for takes whose file isn't open yet.
+    events: Vec<(u32, u64, Vec<u8>)>,
+}
+
+impl Writer {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            take: None,
+            latest: None,
+            events: Vec::new(),
+        }
+    }
+
+    /// Returns the open file for take `number`, finishing the previous take's first. Returns
+    /// `None` if the take is already over or its file can't be opened.
+    fn open(
+        &mut self,
+        number: u32,
+        recorder: &Recorder,
+        transport: &Transport,
+    ) -> Option<&mut Take> {
+        if self.take.as_ref().is_some_and(|take| take.number == number) {
+            return self.take.as_mut();
+        }
+        if self.latest.is_some_and(|latest| number <= latest) {
+            return None;
+        }
+        self.finish();
+        self.latest = Some(number);
+        match Take::start(number, &self.dir, recorder, transport) {
+            Ok(started) => {
+                info!("Recording to {}", started.path.display());
+                *recorder
+                    .inner
+                    .path
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(started.path.clone());
+                self.take = Some(started);
+                self.adopt_events();
+            }
+            Err(e) => {
+                warn!("Failed to start recording: {:#}", e);
+                if recorder.take() == number {
+                    recorder.stop();
+                }
+            }
+        }
+        self.take.as_mut()
+    }
+
+    /// Hands the MIDI messages waiting for the open take over to it, dropping any left over from
+    /// earlier takes.
+    fn adopt_events(&mut self) {
+        let latest = self.latest;
+        let mut waiting = Vec::new();
+        for (number, frame, bytes) in self.events.drain(..) {
+            match &mut self.take {
+                Some(take) if take.number == number => take.events.push((frame, bytes)),
+                _ if latest.is_none_or(|latest| number > latest) => {
+                    waiting.push((number, frame, bytes))
+                }
+                _ => {}
+            }
+        }
+        self.events = waiting;
+    }
+
+    fn finish(&mut self) {
+        if let Some(finished) = self.take.take() {
+            if let Err(e) = finished.finish() {
+                warn!("{:#}", e);
+            }
+        }
+    }
+
+    /// Writes everything queued, then opens a file for the take being recorded or finishes the
+    /// last one if recording has stopped.
+    fn write_pending(&mut self, recorder: &Recorder, transport: &Transport, running: bool) {
+        // The state is read first so a new take it shows is already counted
+        let recording = running && recorder.state() == RecordState::Recording;
+        let number = recorder.take();
+
+        while let Some(event) = recorder.inner.events.pop() {
+            self.events.push(event);
+        }
+        self.adopt_events();
+        while let Some((take, left, right)) = recorder.inner.frames.pop() {
+            if let Some(current) = self.open(take, recorder, transport) {
+                if let Err(e) = current.write(left, right) {
+                    warn!("Failed to write recording: {}", e);
+                    recorder.stop();
+                }
+            }
+        }
+
+        if recording {
+            self.open(number, recorder, transport);
+        } else {
+            self.finish();
+        }
+    }
+}
+
+/// Writes what the recorder captures every `WRITE_INTERVAL`. A recording still going when the app
+/// quits is finished too.
+fn write(running: Arc<AtomicBool>, recorder: Recorder, transport: Transport) {
+    let mut writer = Writer::new(RECORDINGS.path());
+    loop {
+        let running = running.load(Ordering::Relaxed);
+        writer.write_pending(&recorder, &transport, running);
+        if !running {
+            break;
+        }
+        thread::sleep(WRITE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::WavReader;
+
+    fn vlq(value: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_vlq(&mut bytes, value);
+        bytes
+    }
+
+    #[test]
+    fn vlq_uses_one_byte_per_seven_bits() {
+        assert_eq!(vlq(0), [0x00]);
+        assert_eq!(vlq(0x7F), [0x7F]);
+        assert_eq!(vlq(0x80), [0x81, 0x00]);
+        assert_eq!(vlq(0x3FFF), [0xFF, 0x7F]);
+        assert_eq!(vlq(0x4000), [0x81, 0x80, 0x00]);
+        assert_eq!(vlq(0x0FFF_FFFF), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn frames_round_to_the_nearest_tick() {
+        // At 120 BPM and 48 kHz a beat is 24000 frames, so each tick is 50 frames
+        assert_eq!(frame_to_tick(0, 48_000, 120.0), 0);
+        assert_eq!(frame_to_tick(24, 48_000, 120.0), 0);
+        assert_eq!(frame_to_tick(26, 48_000, 120.0), 1);
+        assert_eq!(frame_to_tick(24_000, 48_000, 120.0), 480);
+        assert_eq!(frame_to_tick(29_400, 44_100, 90.0), 480);
+        assert_eq!(frame_to_tick(48_000 * 60, 48_000, 120.0), 480 * 120);
+    }
+
+    #[test]
+    fn smf_has_a_tempo_and_delta_times() {
+        let events = [(0, vec![0x90, 60, 100]), (24_000, vec![0x80, 60, 64])];
+        let smf = encode_smf(&events, 48_000, 120.0);
+        #[rustfmt::skip]
+        let expected = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xE0,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 20,
+            // 500000 microseconds per beat
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x00, 0x90, 60, 100,
+            // 480 ticks later
+            0x83, 0x60, 0x80, 60, 64,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        assert_eq!(smf, expected);
+    }
+
+    #[test]
+    fn smf_never_goes_back_in_time() {
+        let events = [(24_000, vec![0x90, 60, 100]), (0, vec![0x80, 60, 64])];
+        let smf = encode_smf(&events, 48_000, 120.0);
+        let track = &smf[22 + 7..];
+        assert_eq!(track[..6], [0x83, 0x60, 0x90, 60, 100, 0x00]);
+    }
+
+    #[test]
+    fn armed_recorder_starts_on_sound() {
+        let recorder = Recorder::default();
+        recorder.push(0.5, 0.5);
+        assert!(recorder.inner.frames.is_empty());
+
+        recorder.arm();
+        assert_eq!(recorder.state(), RecordState::Armed);
+        recorder.push(0.0, ARM_THRESHOLD / 2.0);
+        assert_eq!(recorder.state(), RecordState::Armed);
+        assert!(recorder.inner.frames.is_empty());
+
+        recorder.push(0.0, 0.5);
+        assert_eq!(recorder.state(), RecordState::Recording);
+        assert_eq!(recorder.inner.frames.pop(), Some((1, 0.0, 0.5)));
+        // Arming again while recording changes nothing
+        recorder.arm();
+        assert_eq!(recorder.state(), RecordState::Recording);
+
+        recorder.stop();
+        assert_eq!(recorder.state(), RecordState::Idle);
+        recorder.push(0.5, 0.5);
+        assert!(recorder.inner.frames.is_empty());
+    }
+
+    #[test]
+    fn each_recording_is_a_new_take() {
+        let recorder = Recorder::default();
+        recorder.set_sample_rate(10);
+        recorder.record();
+        for _ in 0..5 {
+            recorder.push(0.0, 0.0);
+        }
+        assert_eq!(recorder.take(), 1);
+        assert_eq!(recorder.seconds(), 0.5);
+        // Recording again while recording carries on with the same take
+        recorder.record();
+        assert_eq!(recorder.take(), 1);
+
+        recorder.stop();
+        recorder.record();
+        assert_eq!(recorder.take(), 2);
+        assert_eq!(recorder.seconds(), 0.0);
+    }
+
+    #[test]
+    fn only_channel_messages_are_kept_for_the_midi_file() {
+        let recorder = Recorder::default();
+        recorder.record();
+        recorder.push_midi(&[0x90, 60, 100]);
+        assert!(recorder.inner.events.is_empty());
+
+        recorder.set_record_midi(true);
+        recorder.push(0.0, 0.0);
+        recorder.push_midi(&[0xF8]);
+        recorder.push_midi(&[0x90, 60, 100]);
+        assert_eq!(
+            recorder.inner.events.pop(),
+            Some((1, 1, vec![0x90, 60, 100]))
+        );
+        assert!(recorder.inner.events.is_empty());
+
+        recorder.stop();
+        recorder.push_midi(&[0x80, 60, 64]);
+        assert!(recorder.inner.events.is_empty());
+    }
+
+    #[test]
+    fn takes_restarted_before_the_writer_catches_up_get_their_own_files() {
+        let dir = std::env::temp_dir().join(format!("synth-recorder-test-{}", std::process::id()));
+        let recorder = Recorder::default();
+        let transport = Transport::default();
+        recorder.set_sample_rate(48_000);
+        recorder.set_record_midi(true);
+        let mut writer = Writer::new(dir.clone());
+
+        recorder.record();
+        for _ in 0..3 {
+            recorder.push(0.25, 0.25);
+        }
+        recorder.push_midi(&[0x90, 60, 100]);
+        recorder.stop();
+        recorder.record();
+        for _ in 0..5 {
+            recorder.push(0.5, 0.5);
+        }
+        writer.write_pending(&recorder, &transport, true);
+        recorder.stop();
+        writer.write_pending(&recorder, &transport, true);
+        // A frame that was on its way when the take stopped doesn't open another file
+        let _ = recorder.inner.frames.push((2, 0.5, 0.5));
+        writer.write_pending(&recorder, &transport, true);
+
+        let frames = |name: &str| WavReader::open(dir.join(name)).unwrap().duration();
+        assert_eq!(frames("take-001.wav"), 3);
+        assert_eq!(frames("take-002.wav"), 5);
+        assert!(dir.join("take-001.mid").exists());
+        assert!(dir.join("take-002.mid").exists());
+        assert!(!dir.join("take-003.wav").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
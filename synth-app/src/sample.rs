@@ -0,0 +1,329 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Context};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use wmidi::Note;
+
+use crate::files::FileDir;
+
+/// A sample plays back at its recorded pitch on this note.
+pub const ROOT_NOTE: Note = Note::C4;
+/// Directory the Edit screen loads samples from and saves them to.
+pub const SAMPLES: FileDir = FileDir::new("samples", "SYNTH_SAMPLES_DIR", "wav");
+
+/// A mono sample and the points it plays between. Stereo files are mixed down to mono when they
+/// are loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub path: PathBuf,
+    spec: WavSpec,
+    data: Vec<f32>,
+    /// First frame played.
+    start: usize,
+    /// Frame after the last one played.
+    end: usize,
+    loop_start: usize,
+    loop_end: usize,
+    /// Whether playback jumps back to the loop start on reaching the loop end, rather than
+    /// stopping at the end.
+    pub looping: bool,
+}
+
+impl Sample {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut reader =
+            WavReader::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let spec = reader.spec();
+        let channels = usize::from(spec.channels.max(1));
+
+        let samples: Vec<f32> = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            SampleFormat::Int => {
+                let scale = 1.0 / (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        if samples.is_empty() {
+            return Err(anyhow!("{} has no audio", path.display()));
+        }
+        let data: Vec<f32> = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+            .collect();
+
+        let len = data.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            spec: WavSpec {
+                channels: 1,
+                ..spec
+            },
+            data,
+            start: 0,
+            end: len,
+            loop_start: 0,
+            loop_end: len,
+            looping: false,
+        })
+    }
+
+    /// Writes the whole sample to `path` in the format it was loaded in, the start, end and loop
+    /// points aren't saved so trim the sample first to keep only the part between them.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut writer = WavWriter::create(path, self.spec)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        match self.spec.sample_format {
+            SampleFormat::Float => {
+                for sample in &self.data {
+                    writer.write_sample(*sample)?;
+                }
+            }
+            SampleFormat::Int => {
+                let max = ((1_i64 << (self.spec.bits_per_sample - 1)) - 1) as f32;
+                for sample in &self.data {
+                    writer.write_sample((sample.clamp(-1.0, 1.0) * max).round() as i32)?;
+                }
+            }
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn loop_start(&self) -> usize {
+        self.loop_start
+    }
+
+    pub fn loop_end(&self) -> usize {
+        self.loop_end
+    }
+
+    /// Moves the start point, keeping at least one frame before the end. The loop is kept
+    /// between the start and end.
+    pub fn set_start(&mut self, start: usize) {
+        self.start = start.min(self.end - 1);
+        self.set_loop_start(self.loop_start);
+        self.set_loop_end(self.loop_end);
+    }
+
+    /// Moves the end point, keeping at least one frame after the start. The loop is kept
+    /// between the start and end.
+    pub fn set_end(&mut self, end: usize) {
+        self.end = end.clamp(self.start + 1, self.len());
+        self.set_loop_start(self.loop_start);
+        self.set_loop_end(self.loop_end);
+    }
+
+    pub fn set_loop_start(&mut self, loop_start: usize) {
+        self.loop_start = loop_start.clamp(self.start, self.end - 1);
+        self.loop_end = self.loop_end.max(self.loop_start + 1);
+    }
+
+    pub fn set_loop_end(&mut self, loop_end: usize) {
+        self.loop_end = loop_end.clamp(self.loop_start + 1, self.end);
+    }
+
+    /// Scales the part between the start and end so its loudest frame is at full scale.
+    pub fn normalise(&mut self) {
+        let region = &mut self.data[self.start..self.end];
+        let peak = region
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        if peak > 0.0 {
+            region.iter_mut().for_each(|sample| *sample /= peak);
+        }
+    }
+
+    /// Reverses the part between the start and end.
+    pub fn reverse(&mut self) {
+        self.data[self.start..self.end].reverse();
+    }
+
+    /// Cuts off everything before the start and after the end.
+    pub fn trim(&mut self) {
+        self.data.truncate(self.end);
+        self.data.drain(..self.start);
+        self.loop_start -= self.start;
+        self.loop_end -= self.start;
+        self.start = 0;
+        self.end = self.data.len();
+    }
+
+    /// Splits the sample into `columns` equal slices and returns the lowest and highest value in
+    /// each, for drawing the waveform.
+    pub fn peaks(&self, columns: usize) -> Vec<(f32, f32)> {
+        (0..columns)
+            .map(|column| {
+                let from = column * self.len() / columns;
+                let to = ((column + 1) * self.len() / columns)
+                    .max(from + 1)
+                    .min(self.len());
+                self.data[from.min(to)..to]
+                    .iter()
+                    .fold((0.0_f32, 0.0_f32), |(low, high), sample| {
+                        (low.min(*sample), high.max(*sample))
+                    })
+            })
+            .collect()
+    }
+
+    /// Returns the value at `position` frames, interpolating between frames.
+    pub fn value_at(&self, position: f64) -> f64 {
+        let index = position.floor() as usize;
+        let fraction = position.fract();
+        let current = self.data.get(index).copied().unwrap_or(0.0);
+        let next = self.data.get(index + 1).copied().unwrap_or(0.0);
+        f64::from(current) + (f64::from(next) - f64::from(current)) * fraction
+    }
+}
+
+/// Cloneable handle to the sample the voices play, shared between the Edit screen and the audio
+/// engine. While it is empty the voices play their oscillator instead.
+#[derive(Clone, Default)]
+pub struct SampleSlot {
+    sample: Arc<Mutex<Option<Arc<Sample>>>>,
+    /// Samples that have been replaced but may still be playing. They are kept here so the voices
+    /// never let go of the last copy, which would free it on the audio thread.
+    retired: Arc<Mutex<Vec<Arc<Sample>>>>,
+}
+
+impl SampleSlot {
+    fn lock(&self) -> MutexGuard<'_, Option<Arc<Sample>>> {
+        self.sample
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self) -> Option<Arc<Sample>> {
+        self.lock().clone()
+    }
+
+    /// Returns the sample without waiting, `None` if the slot is being changed. This is for the
+    /// audio thread, which must never block.
+    pub fn try_get(&self) -> Option<Option<Arc<Sample>>> {
+        self.sample.try_lock().ok().map(|sample| sample.clone())
+    }
+
+    pub fn set(&self, sample: Option<Sample>) {
+        let old = std::mem::replace(&mut *self.lock(), sample.map(Arc::new));
+        self.retire(old);
+    }
+
+    /// Edits the sample in place. Voices that are playing keep the sample they started with, the
+    /// edit is heard from the next note.
+    pub fn edit(&self, edit: impl FnOnce(&mut Sample)) {
+        let mut slot = self.lock();
+        if let Some(sample) = slot.as_mut() {
+            // A voice still has the old sample, so the edit is made on a copy
+            let old = (Arc::strong_count(sample) > 1).then(|| Arc::clone(sample));
+            edit(Arc::make_mut(sample));
+            drop(slot);
+            self.retire(old);
+        }
+    }
+
+    fn retire(&self, sample: Option<Arc<Sample>>) {
+        let mut retired = self
+            .retired
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        retired.extend(sample);
+        retired.retain(|sample| Arc::strong_count(sample) > 1);
+    }
+
+    /// Frees the replaced samples no voice is playing any more. This has to be called from
+    /// outside the audio thread every so often, the UI does it on every update.
+    pub fn drop_retired(&self) {
+        self.retire(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(length: usize) -> Sample {
+        Sample {
+            path: PathBuf::from("test.wav"),
+            spec: WavSpec {
+                channels: 1,
+                sample_rate: 48_000,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            },
+            data: vec![0.5; length],
+            start: 0,
+            end: length,
+            loop_start: 0,
+            loop_end: length,
+            looping: false,
+        }
+    }
+
+    #[test]
+    fn replaced_samples_outlive_the_voices_playing_them() {
+        let slot = SampleSlot::default();
+        slot.set(Some(sample(16)));
+        // A voice picks up the sample, then a new one is loaded
+        let playing = slot.try_get().flatten().unwrap();
+        let old = Arc::downgrade(&playing);
+        slot.set(Some(sample(32)));
+
+        // The voice moving on must not be what frees the old sample
+        drop(playing);
+        assert!(old.upgrade().is_some());
+        slot.drop_retired();
+        assert!(old.upgrade().is_none());
+    }
+
+    #[test]
+    fn edits_while_playing_keep_the_old_sample_for_the_ui_to_free() {
+        let slot = SampleSlot::default();
+        slot.set(Some(sample(16)));
+        let playing = slot.get().unwrap();
+        let old = Arc::downgrade(&playing);
+
+        slot.edit(|sample| sample.set_end(8));
+        assert_eq!(slot.get().unwrap().end(), 8);
+        assert_eq!(playing.end(), 16);
+
+        drop(playing);
+        assert!(old.upgrade().is_some());
+        slot.drop_retired();
+        assert!(old.upgrade().is_none());
+    }
+
+    #[test]
+    fn unplayed_samples_are_freed_straight_away() {
+        let slot = SampleSlot::default();
+        slot.set(Some(sample(16)));
+        let old = Arc::downgrade(&slot.get().unwrap());
+        slot.set(None);
+        assert!(old.upgrade().is_none());
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use synth_core::random::{self, xorshift};
+use wmidi::{Channel, MidiMessage, Note, U7};
+
+use crate::{
+    clock::{Transport, PULSES_PER_BEAT},
+    engine::Voices,
+    midi::{MidiPorts, Output},
+};
+
+pub const NUM_TRACKS: usize = 4;
+pub const NUM_PATTERNS: usize = 8;
+pub const MIN_STEPS: usize = 16;
+pub const MAX_STEPS: usize = 64;
+/// Steps are sixteenth notes.
+pub const STEPS_PER_BEAT: f64 = 4.0;
+/// MIDI clock pulses in each step.
+const PULSES_PER_STEP: u64 = (PULSES_PER_BEAT / STEPS_PER_BEAT) as u64;
+/// How often the playback thread checks the transport position.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A note on one step of a track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub note: Note,
+    pub velocity: U7,
+    /// How long the note is held, as a fraction of a step.
+    pub gate: f64,
+    /// Chance of the step playing each time round, from 0.0 to 1.0.
+    pub probability: f64,
+}
+
+impl Step {
+    pub fn new(note: Note, velocity: U7) -> Self {
+        Self {
+            note,
+            velocity,
+            gate: 0.5,
+            probability: 1.0,
+        }
+    }
+}
+
+/// Where a track's notes are played.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Destination {
+    /// The app's own audio engine.
+    #[default]
+    Engine,
+    /// The MIDI output port chosen on the Play screen's MIDI page, on the track's channel.
+    Midi,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// One entry for every possible step, steps past the pattern's length are kept but not played.
+    pub steps: Vec<Option<Step>>,
+    pub channel: Channel,
+    pub destination: Destination,
+    pub muted: bool,
+}
+
+impl Track {
+    fn new(channel: Channel) -> Self {
+        Self {
+            steps: vec![None; MAX_STEPS],
+            channel,
+            destination: Destination::default(),
+            muted: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    /// Number of steps played before the pattern repeats or the chain moves on, from `MIN_STEPS`
+    /// to `MAX_STEPS`.
+    pub length: usize,
+    pub tracks: Vec<Track>,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self {
+            length: MIN_STEPS,
+            tracks: (0..NUM_TRACKS)
+                .map(|index| Track::new(Channel::from_index(index as u8).unwrap_or(Channel::Ch1)))
+                .collect(),
+        }
+    }
+}
+
+/// Which step is playing, and where in the chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Playhead {
+    pub chain_index: usize,
+    pub step: usize,
+}
+
+/// Every pattern and the order they are played in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence {
+    pub patterns: Vec<Pattern>,
+    /// Patterns played one after the other, then from the start again.
+    pub chain: Vec<usize>,
+    /// Whether notes played on the keyboard are written into the playing pattern.
+    pub recording: bool,
+    /// The step playing, `None` while stopped.
+    pub playhead: Option<Playhead>,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self {
+            patterns: vec![Pattern::default(); NUM_PATTERNS],
+            chain: vec![0],
+            recording: false,
+            playhead: None,
+        }
+    }
+}
+
+impl Sequence {
+    /// Returns the index of the pattern playing, or the first in the chain while stopped.
+    pub fn playing_pattern(&self) -> usize {
+        let chain_index = self.playhead.map_or(0, |playhead| playhead.chain_index);
+        self.chain.get(chain_index).copied().unwrap_or(0)
+    }
+
+    /// Returns where the chain is `step` steps after it started, going round again once every
+    /// pattern in it has played.
+    pub fn playhead_at(&self, step: usize) -> Playhead {
+        let length = |index: &usize| self.patterns.get(*index).map_or(MIN_STEPS, |p| p.length);
+        let total: usize = self.chain.iter().map(length).sum();
+        let mut step = step % total.max(1);
+        for (chain_index, pattern) in self.chain.iter().enumerate() {
+            if step < length(pattern) {
+                return Playhead { chain_index, step };
+            }
+            step -= length(pattern);
+        }
+        Playhead {
+            chain_index: 0,
+            step: 0,
+        }
+    }
+
+    /// Writes a note into `track` of the playing pattern, on whichever step is nearest to
+    /// `position` (in beats).
+    pub fn record(&mut self, track: usize, note: Note, velocity: U7, position: f64) {
+        let Some(playhead) = self.playhead else {
+            return;
+        };
+        let pattern = self.playing_pattern();
+        let Some(pattern) = self.patterns.get_mut(pattern) else {
+            return;
+        };
+        let late = (position * STEPS_PER_BEAT).fract() >= 0.5;
+        let step = (playhead.step + usize::from(late)) % pattern.length;
+        if let Some(track) = pattern.tracks.get_mut(track) {
+            track.steps[step] = Some(Step::new(note, velocity));
+        }
+    }
+}
+
+/// Cloneable handle to the sequence, shared between the UI and the playback thread.
+#[derive(Clone, Default)]
+pub struct Sequencer {
+    sequence: Arc<Mutex<Sequence>>,
+}
+
+impl Sequencer {
+    /// Locks the sequence for reading or editing, keep it short as playback waits on it.
+    pub fn lock(&self) -> MutexGuard<'_, Sequence> {
+        self.sequence
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Background service that plays the sequence in time with the transport.
+pub struct SequencerService {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SequencerService {
+    pub fn start(
+        sequencer: Sequencer,
+        transport: Transport,
+        voices: Voices,
+        ports: MidiPorts,
+    ) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || {
+                Playback {
+                    sequencer,
+                    transport,
+                    voices,
+                    output: Output::chosen(ports, MidiPorts::output),
+                    last_step: None,
+                    held: Vec::new(),
+                    random: random::SEED,
+                }
+                .run(running)
+            })
+        };
+
+        Self {
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for SequencerService {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A note that is playing and the position (in beats) it ends at.
+struct HeldNote {
+    end: f64,
+    note: Note,
+    channel: Channel,
+    destination: Destination,
+}
+
+/// Returns the transport step that clock pulse `pulses` falls in.
+fn step_at(pulses: u64) -> i64 {
+    (pulses / PULSES_PER_STEP) as i64
+}
+
+struct Playback {
+    sequencer: Sequencer,
+    transport: Transport,
+    voices: Voices,
+    output: Output,
+    /// The transport step that was last played.
+    last_step: Option<i64>,
+    held: Vec<HeldNote>,
+    /// Xorshift state for step probability.
+    random: u32,
+}
+
+impl Playback {
+    fn run(&mut self, running: Arc<AtomicBool>) {
+        while running.load(Ordering::Relaxed) {
+            self.output.poll();
+            let pulses = self.transport.pulses();
+            let position = pulses as f64 / PULSES_PER_BEAT;
+
+            if !self.transport.is_playing() {
+                self.release(f64::INFINITY);
+                self.last_step = None;
+                self.sequencer.lock().playhead = None;
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+
+            self.release(position);
+            let step = step_at(pulses);
+            if self.last_step != Some(step) {
+                // Anything but the next step means the transport was started or moved, so find
+                // where that is in the chain
+                let moved = self.last_step != Some(step - 1);
+                self.last_step = Some(step);
+                self.play_step(step, moved);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.release(f64::INFINITY);
+    }
+
+    /// Moves the playhead on to transport step `step` and plays the notes on it.
+    fn play_step(&mut self, step: i64, moved: bool) {
+        let start = step as f64 / STEPS_PER_BEAT;
+        let mut sequence = self.sequencer.lock();
+        let playhead = match sequence.playhead {
+            Some(playhead) if !moved => {
+                let length = sequence.patterns[sequence.playing_pattern()].length;
+                if playhead.step + 1 < length {
+                    Playhead {
+                        step: playhead.step + 1,
+                        ..playhead
+                    }
+                } else {
+                    Playhead {
+                        chain_index: (playhead.chain_index + 1) % sequence.chain.len().max(1),
+                        step: 0,
+                    }
+                }
+            }
+            _ => sequence.playhead_at(step.max(0) as usize),
+        };
+        sequence.playhead = Some(playhead);
+
+        let pattern = &sequence.patterns[sequence.playing_pattern()];
+        let notes: Vec<_> = pattern
+            .tracks
+            .iter()
+            .filter(|track| !track.muted)
+            .filter_map(|track| track.steps[playhead.step].map(|step| (track, step)))
+            .map(|(track, step)| (track.channel, track.destination, step))
+            .collect();
+        drop(sequence);
+
+        for (channel, destination, step) in notes {
+            if self.next_random() > step.probability {
+                continue;
+            }
+            self.held.push(HeldNote {
+                end: start + step.gate / STEPS_PER_BEAT,
+                note: step.note,
+                channel,
+                destination,
+            });
+            match destination {
+                Destination::Engine => self.voices.note_on(step.note, step.velocity),
+                Destination::Midi => {
+                    self.output
+                        .send(&MidiMessage::NoteOn(channel, step.note, step.velocity))
+                }
+            }
+        }
+    }
+
+    /// Ends every held note that finishes by `position` (in beats).
+    fn release(&mut self, position: f64) {
+        let (ended, held): (Vec<_>, Vec<_>) =
+            self.held.drain(..).partition(|note| note.end <= position);
+        self.held = held;
+        for note in ended {
+            match note.destination {
+                Destination::Engine => self.voices.note_off(note.note),
+                Destination::Midi => {
+                    self.output
+                        .send(&MidiMessage::NoteOff(note.channel, note.note, U7::MIN))
+                }
+            }
+        }
+    }
+
+    /// Returns a random number from 0.0 to 1.0.
+    fn next_random(&mut self) -> f64 {
+        f64::from(xorshift(&mut self.random)) / f64::from(u32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32 step pattern followed by a 16 step one.
+    fn chained() -> Sequence {
+        let mut sequence = Sequence::default();
+        sequence.patterns[1].length = 32;
+        sequence.chain = vec![1, 0];
+        sequence
+    }
+
+    fn playhead(chain_index: usize, step: usize) -> Playhead {
+        Playhead { chain_index, step }
+    }
+
+    #[test]
+    fn playhead_wraps_across_chained_patterns_of_different_lengths() {
+        let sequence = chained();
+        assert_eq!(sequence.playhead_at(0), playhead(0, 0));
+        assert_eq!(sequence.playhead_at(31), playhead(0, 31));
+        assert_eq!(sequence.playhead_at(32), playhead(1, 0));
+        assert_eq!(sequence.playhead_at(47), playhead(1, 15));
+        // Round again once both have played
+        assert_eq!(sequence.playhead_at(48), playhead(0, 0));
+        assert_eq!(sequence.playhead_at(48 * 3 + 33), playhead(1, 1));
+    }
+
+    #[test]
+    fn recorded_notes_go_on_the_nearest_step() {
+        let mut sequence = Sequence::default();
+        let recorded = |sequence: &Sequence| {
+            sequence.patterns[0].tracks[0]
+                .steps
+                .iter()
+                .position(Option::is_some)
+        };
+
+        // Nothing is recorded while stopped
+        sequence.record(0, Note::C4, U7::MAX, 0.8);
+        assert_eq!(recorded(&sequence), None);
+
+        // A little after step 3 starts stays on it, past halfway goes on the next one
+        sequence.playhead = Some(playhead(0, 3));
+        sequence.record(0, Note::C4, U7::MAX, 0.8);
+        assert_eq!(recorded(&sequence), Some(3));
+        sequence.patterns[0].tracks[0].steps[3] = None;
+        sequence.record(0, Note::C4, U7::MAX, 0.875);
+        assert_eq!(recorded(&sequence), Some(4));
+        assert_eq!(
+            sequence.patterns[0].tracks[0].steps[4],
+            Some(Step::new(Note::C4, U7::MAX))
+        );
+        sequence.patterns[0].tracks[0].steps[4] = None;
+
+        // Late on the last step wraps round to the first
+        sequence.playhead = Some(playhead(0, 15));
+        sequence.record(0, Note::C4, U7::MAX, 3.9);
+        assert_eq!(recorded(&sequence), Some(0));
+    }
+
+    #[test]
+    fn playback_moves_along_the_chain() {
+        let sequencer = Sequencer::default();
+        *sequencer.lock() = chained();
+        sequencer.lock().patterns[1].tracks[0].steps[0] = Some(Step::new(Note::C4, U7::MAX));
+        let mut playback = Playback {
+            sequencer: sequencer.clone(),
+            transport: Transport::default(),
+            voices: Voices::default(),
+            output: Output::default(),
+            last_step: None,
+            held: Vec::new(),
+            random: random::SEED,
+        };
+
+        playback.play_step(0, true);
+        assert_eq!(sequencer.lock().playhead, Some(playhead(0, 0)));
+        for step in 1..32 {
+            playback.play_step(step, false);
+        }
+        assert_eq!(sequencer.lock().playhead, Some(playhead(0, 31)));
+        playback.play_step(32, false);
+        assert_eq!(sequencer.lock().playhead, Some(playhead(1, 0)));
+        for step in 33..48 {
+            playback.play_step(step, false);
+        }
+        playback.play_step(48, false);
+        assert_eq!(sequencer.lock().playhead, Some(playhead(0, 0)));
+
+        // The note on the first pattern in the chain played each time round, and only then
+        assert_eq!(playback.held.len(), 2);
+        assert!(playback.held.iter().all(|held| held.note == Note::C4));
+
+        // Moving the transport finds the step again from the start of the chain
+        playback.play_step(100, true);
+        assert_eq!(sequencer.lock().playhead, Some(playhead(0, 4)));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{fmt, sync::Arc};
+
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+
+/// Samples in each FFT, about 43 ms at 48 kHz which gives bins 23 Hz apart.
+pub const FFT_SIZE: usize = 2048;
+/// Bands the spectrum is grouped into, spaced evenly in octaves.
+pub const NUM_BANDS: usize = 64;
+/// Quietest level shown, anything below reads as this.
+pub const MIN_DB: f32 = -90.0;
+pub const MIN_FREQUENCY: f32 = 20.0;
+pub const MAX_FREQUENCY: f32 = 20_000.0;
+/// Seconds a peak is held before it starts to fall.
+const PEAK_HOLD: f32 = 1.0;
+/// How fast a peak falls once it has been held, in dB per second.
+const PEAK_FALL: f32 = 30.0;
+
+/// Returns the frequency in Hz at `position` from 0.0 to 1.0 across the log frequency axis.
+pub fn frequency_at(position: f32) -> f32 {
+    MIN_FREQUENCY * (MAX_FREQUENCY / MIN_FREQUENCY).powf(position)
+}
+
+/// FFT spectrum analyser. Each update windows the latest samples, measures every band in dB and
+/// smooths the result, so it is meant to run on the UI thread rather than the audio thread.
+pub struct Spectrum {
+    fft: Arc<dyn RealToComplex<f32>>,
+    /// Hann window, applied before the FFT so energy doesn't leak between bins.
+    window: Vec<f32>,
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// FFT bins each band covers, worked out for `sample_rate`.
+    bands: Vec<(usize, usize)>,
+    sample_rate: u32,
+    levels: Vec<f32>,
+    peaks: Vec<f32>,
+    /// Seconds since each peak was set.
+    peak_ages: Vec<f32>,
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+        let window = (0..FFT_SIZE)
+            .map(|index| {
+                let phase = index as f32 / FFT_SIZE as f32;
+                0.5 - 0.5 * (phase * std::f32::consts::TAU).cos()
+            })
+            .collect();
+        Self {
+            input: fft.make_input_vec(),
+            output: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
+            window,
+            bands: Vec::new(),
+            sample_rate: 0,
+            levels: vec![MIN_DB; NUM_BANDS],
+            peaks: vec![MIN_DB; NUM_BANDS],
+            peak_ages: vec![0.0; NUM_BANDS],
+        }
+    }
+}
+
+impl fmt::Debug for Spectrum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Spectrum")
+            .field("sample_rate", &self.sample_rate)
+            .field("levels", &self.levels)
+            .field("peaks", &self.peaks)
+            .finish()
+    }
+}
+
+/// Two analysers are equal when they show the same levels.
+impl PartialEq for Spectrum {
+    fn eq(&self, other: &Self) -> bool {
+        self.levels == other.levels && self.peaks == other.peaks
+    }
+}
+
+impl Spectrum {
+    /// Level of each band in dB, from `MIN_DB` up to around 0.0 for a full scale sine.
+    pub fn levels(&self) -> &[f32] {
+        &self.levels
+    }
+
+    /// Highest recent level of each band in dB.
+    pub fn peaks(&self) -> &[f32] {
+        &self.peaks
+    }
+
+    /// Works out which FFT bins fall in each band. Each band starts where the last one ended and
+    /// takes at least one bin, so at the bottom end, where the bands would be narrower than the
+    /// bins, they are a bin each until the spacing in octaves catches up.
+    fn plan_bands(&mut self, sample_rate: u32) {
+        let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+        let last = FFT_SIZE / 2;
+        let mut next = 1;
+        self.bands = (0..NUM_BANDS)
+            .map(|band| {
+                let high = frequency_at((band + 1) as f32 / NUM_BANDS as f32) / bin_width;
+                let low = next.min(last);
+                let high = (high.round() as usize).clamp(low + 1, last + 1);
+                next = high;
+                (low, high)
+            })
+            .collect();
+        self.sample_rate = sample_rate;
+    }
+
+    /// Returns where `frequency` Hz is shown, from 0.0 to 1.0 across the bands.
+    pub fn position_of(&self, frequency: f32) -> f32 {
+        if self.bands.is_empty() {
+            return (frequency / MIN_FREQUENCY).ln() / (MAX_FREQUENCY / MIN_FREQUENCY).ln();
+        }
+        let bin = frequency * FFT_SIZE as f32 / self.sample_rate as f32;
+        let band = self
+            .bands
+            .iter()
+            .position(|&(_, high)| bin < high as f32)
+            .unwrap_or(NUM_BANDS - 1);
+        let (low, high) = self.bands[band];
+        let within = ((bin - low as f32) / (high - low) as f32).clamp(0.0, 1.0);
+        (band as f32 + within) / NUM_BANDS as f32
+    }
+
+    /// Analyses the latest `samples`, `elapsed` seconds after the last update. Levels rise
+    /// straight away and fall back over `smoothing` seconds, zero follows the signal exactly.
+    pub fn update(&mut self, samples: &[f32], sample_rate: u32, elapsed: f32, smoothing: f32) {
+        if sample_rate != self.sample_rate {
+            self.plan_bands(sample_rate);
+        }
+
+        // Line the samples up with the end of the window, padding the start if there are too few
+        let offset = FFT_SIZE.saturating_sub(samples.len());
+        let samples = &samples[samples.len().saturating_sub(FFT_SIZE)..];
+        self.input[..offset].fill(0.0);
+        for ((input, sample), window) in self.input[offset..]
+            .iter_mut()
+            .zip(samples)
+            .zip(&self.window[offset..])
+        {
+            *input = sample * window;
+        }
+        if self
+            .fft
+            .process_with_scratch(&mut self.input, &mut self.output, &mut self.scratch)
+            .is_err()
+        {
+            return;
+        }
+
+        // The Hann window halves the amplitude, so a full scale sine peaks at FFT_SIZE / 4
+        let scale = 4.0 / FFT_SIZE as f32;
+        let fall = if smoothing > 0.0 {
+            (-elapsed / smoothing).exp()
+        } else {
+            0.0
+        };
+        for (band, &(low, high)) in self.bands.iter().enumerate() {
+            let magnitude = self.output[low..high]
+                .iter()
+                .map(|bin| bin.norm())
+                .fold(0.0, f32::max);
+            let level = (20.0 * (magnitude * scale).log10()).max(MIN_DB);
+
+            let smoothed = &mut self.levels[band];
+            *smoothed = if level >= *smoothed {
+                level
+            } else {
+                level + (*smoothed - level) * fall
+            };
+
+            self.peak_ages[band] += elapsed;
+            if *smoothed >= self.peaks[band] {
+                self.peaks[band] = *smoothed;
+                self.peak_ages[band] = 0.0;
+            } else if self.peak_ages[band] > PEAK_HOLD {
+                self.peaks[band] = (self.peaks[band] - PEAK_FALL * elapsed).max(*smoothed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `FFT_SIZE` samples of a full scale sine at `frequency` Hz.
+    fn sine(frequency: f32, sample_rate: u32) -> Vec<f32> {
+        (0..FFT_SIZE)
+            .map(|index| {
+                (index as f32 * frequency / sample_rate as f32 * std::f32::consts::TAU).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_bin_is_in_one_band() {
+        for sample_rate in [44_100, 48_000] {
+            let mut spectrum = Spectrum::default();
+            spectrum.plan_bands(sample_rate);
+            assert_eq!(spectrum.bands.len(), NUM_BANDS);
+            assert_eq!(spectrum.bands[0].0, 1);
+            for &(low, high) in &spectrum.bands {
+                assert!(high > low, "empty band at {}", low);
+            }
+            let last = spectrum.bands[NUM_BANDS - 1].1;
+            for bin in 1..last {
+                let count = spectrum
+                    .bands
+                    .iter()
+                    .filter(|&&(low, high)| (low..high).contains(&bin))
+                    .count();
+                assert_eq!(count, 1, "bin {} is in {} bands", bin, count);
+            }
+            // The top band ends at `MAX_FREQUENCY`
+            let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+            assert_eq!(last, (MAX_FREQUENCY / bin_width).round() as usize);
+        }
+    }
+
+    #[test]
+    fn window_is_a_hann_window() {
+        let window = &Spectrum::default().window;
+        assert_eq!(window[0], 0.0);
+        assert!((window[FFT_SIZE / 2] - 1.0).abs() < 1e-6);
+        assert!((window[FFT_SIZE / 4] - 0.5).abs() < 1e-6);
+        for index in 1..FFT_SIZE / 2 {
+            assert!((window[index] - window[FFT_SIZE - index]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn sine_lights_up_its_band() {
+        let mut spectrum = Spectrum::default();
+        spectrum.update(&sine(1000.0, 48_000), 48_000, 0.0, 0.0);
+
+        let (loudest, &level) = spectrum
+            .levels()
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        let position = spectrum.position_of(1000.0) * NUM_BANDS as f32;
+        assert_eq!(loudest, position as usize);
+        assert!(
+            level > -3.0 && level < 1.0,
+            "full scale sine read {} dB",
+            level
+        );
+        // Well away from it is down near the floor
+        assert!(spectrum.levels()[NUM_BANDS - 1] < -60.0);
+    }
+
+    #[test]
+    fn levels_fall_back_smoothly_and_peaks_hold() {
+        let mut spectrum = Spectrum::default();
+        let loud = sine(1000.0, 48_000);
+        let silence = vec![0.0; FFT_SIZE];
+        spectrum.update(&loud, 48_000, 0.0, 0.5);
+        let band = (spectrum.position_of(1000.0) * NUM_BANDS as f32) as usize;
+        let level = spectrum.levels()[band];
+
+        // After one smoothing time the level has fallen most of the way to the floor
+        spectrum.update(&silence, 48_000, 0.5, 0.5);
+        let fallen = MIN_DB + (level - MIN_DB) * (-1.0f32).exp();
+        assert!((spectrum.levels()[band] - fallen).abs() < 1e-3);
+        // The peak is held for `PEAK_HOLD`, then falls at `PEAK_FALL`
+        assert_eq!(spectrum.peaks()[band], level);
+        spectrum.update(&silence, 48_000, 0.25, 0.0);
+        assert_eq!(spectrum.levels()[band], MIN_DB);
+        assert_eq!(spectrum.peaks()[band], level);
+        spectrum.update(&silence, 48_000, 0.5, 0.0);
+        assert!((spectrum.peaks()[band] - (level - PEAK_FALL * 0.5)).abs() < 1e-3);
+    }
+}
@@ -1,13 +1,240 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{convert::Infallible, fmt, sync::Arc};
 
 use crossbeam::queue::SegQueue;
-use embedded_graphics::{draw_target::DrawTarget, pixelcolor::RgbColor, prelude::*};
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::RgbColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Alignment, Text},
+};
+use wmidi::{Note, U7};
 
-use crate::app::{ActionMessage, State};
 use super::{Event, Screen};
+use crate::{
+    app::{ActionMessage, State},
+    sequencer::{Destination, Sequence, Step, MAX_STEPS, MIN_STEPS, NUM_PATTERNS, NUM_TRACKS},
+};
+
+/// Steps shown at once, the grid pages along as the cursor moves past them.
+const STEPS_PER_PAGE: usize = 16;
+const CELL_WIDTH: i32 = 320 / STEPS_PER_PAGE as i32;
+const CELL_HEIGHT: i32 = 36;
+const GRID_TOP: i32 = 30;
+/// How much each press changes the gate and probability of a step.
+const FRACTION_STEP: f64 = 0.125;
 
 #[derive(Debug, PartialEq)]
-pub(crate) struct ComposeScreen {}
+pub(crate) struct ComposeScreen {
+    pub(crate) selected_track: usize,
+    pub(crate) selected_step: usize,
+    pub(crate) selected_field: Field,
+    /// The pattern being edited, which doesn't have to be the one playing.
+    pub(crate) pattern: usize,
+}
+
+/// What Up and Down change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Field {
+    /// Up and Down move between tracks.
+    Cursor,
+    Note,
+    Velocity,
+    Gate,
+    Probability,
+    /// Length of the pattern in steps.
+    Length,
+    /// Which pattern is being edited.
+    Pattern,
+    /// Up adds the edited pattern to the end of the chain, Down removes the last one.
+    Chain,
+    /// Whether the track plays into the engine or out over MIDI.
+    Output,
+    /// Whether the track is silenced, its steps are kept.
+    Mute,
+    Record,
+    /// Up plays or stops, Down stops and goes back to the start.
+    Transport,
+}
+
+impl Field {
+    fn next(&self) -> Self {
+        use Field::*;
+        match *self {
+            Cursor => Note,
+            Note => Velocity,
+            Velocity => Gate,
+            Gate => Probability,
+            Probability => Length,
+            Length => Pattern,
+            Pattern => Chain,
+            Chain => Output,
+            Output => Mute,
+            Mute => Record,
+            Record => Transport,
+            Transport => Cursor,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::Cursor => write!(f, "Cursor"),
+            Field::Note => write!(f, "Note"),
+            Field::Velocity => write!(f, "Velocity"),
+            Field::Gate => write!(f, "Gate"),
+            Field::Probability => write!(f, "Probability"),
+            Field::Length => write!(f, "Length"),
+            Field::Pattern => write!(f, "Pattern"),
+            Field::Chain => write!(f, "Chain"),
+            Field::Output => write!(f, "Output"),
+            Field::Mute => write!(f, "Mute"),
+            Field::Record => write!(f, "Record"),
+            Field::Transport => write!(f, "Transport"),
+        }
+    }
+}
+
+impl Default for ComposeScreen {
+    fn default() -> Self {
+        Self {
+            selected_track: 0,
+            selected_step: 0,
+            selected_field: Field::Cursor,
+            pattern: 0,
+        }
+    }
+}
+
+impl ComposeScreen {
+    /// Changes whatever the selected field controls, `up` is true for Up and false for Down.
+    fn adjust(&mut self, state: &State, up: bool) {
+        let transport = &state.transport;
+        let mut sequence = state.sequencer.lock();
+        let direction = if up { 1 } else { -1 };
+        let length = sequence.patterns[self.pattern].length;
+
+        match self.selected_field {
+            Field::Cursor => {
+                self.selected_track = if up {
+                    (self.selected_track + NUM_TRACKS - 1) % NUM_TRACKS
+                } else {
+                    (self.selected_track + 1) % NUM_TRACKS
+                };
+            }
+            Field::Note | Field::Velocity | Field::Gate | Field::Probability => {
+                let field = self.selected_field;
+                if let Some(step) = self.step_mut(&mut sequence) {
+                    match field {
+                        Field::Note => {
+                            step.note = step.note.step(direction).unwrap_or(step.note);
+                        }
+                        Field::Velocity => {
+                            let velocity =
+                                i16::from(u8::from(step.velocity)) + i16::from(direction);
+                            step.velocity = U7::from_u8_lossy(velocity.clamp(1, 127) as u8);
+                        }
+                        Field::Gate => {
+                            step.gate = (step.gate + FRACTION_STEP * f64::from(direction))
+                                .clamp(FRACTION_STEP, 1.0);
+                        }
+                        _ => {
+                            step.probability = (step.probability
+                                + FRACTION_STEP * f64::from(direction))
+                            .clamp(0.0, 1.0);
+                        }
+                    }
+                }
+            }
+            Field::Length => {
+                let length = (length as i32 + i32::from(direction))
+                    .clamp(MIN_STEPS as i32, MAX_STEPS as i32);
+                sequence.patterns[self.pattern].length = length as usize;
+                self.selected_step = self.selected_step.min(length as usize - 1);
+            }
+            Field::Pattern => {
+                self.pattern = if up {
+                    (self.pattern + 1) % NUM_PATTERNS
+                } else {
+                    (self.pattern + NUM_PATTERNS - 1) % NUM_PATTERNS
+                };
+                self.selected_step = self
+                    .selected_step
+                    .min(sequence.patterns[self.pattern].length - 1);
+            }
+            Field::Chain => {
+                if up {
+                    sequence.chain.push(self.pattern);
+                } else if sequence.chain.len() > 1 {
+                    sequence.chain.pop();
+                }
+            }
+            Field::Output => {
+                let track = &mut sequence.patterns[self.pattern].tracks[self.selected_track];
+                track.destination = match track.destination {
+                    Destination::Engine => Destination::Midi,
+                    Destination::Midi => Destination::Engine,
+                };
+            }
+            Field::Mute => {
+                let track = &mut sequence.patterns[self.pattern].tracks[self.selected_track];
+                track.muted = !track.muted;
+            }
+            Field::Record => sequence.recording = !sequence.recording,
+            Field::Transport => {
+                if !up {
+                    transport.stop();
+                    transport.rewind();
+                } else if transport.is_playing() {
+                    transport.stop();
+                } else {
+                    transport.resume();
+                }
+            }
+        }
+    }
+
+    fn step_mut<'a>(&self, sequence: &'a mut Sequence) -> Option<&'a mut Step> {
+        sequence.patterns[self.pattern].tracks[self.selected_track].steps[self.selected_step]
+            .as_mut()
+    }
+
+    fn move_cursor(&mut self, state: &State, forward: bool) {
+        let length = state.sequencer.lock().patterns[self.pattern].length;
+        self.selected_step = if forward {
+            (self.selected_step + 1) % length
+        } else {
+            (self.selected_step + length - 1) % length
+        };
+    }
+
+    /// Records a note while playing, otherwise writes it on the cursor and moves on. Playing
+    /// the note already on the cursor clears the step instead.
+    fn note_on(&mut self, state: &State, note: Note, velocity: U7) {
+        let mut sequence = state.sequencer.lock();
+        if sequence.recording && state.transport.is_playing() {
+            sequence.record(
+                self.selected_track,
+                note,
+                velocity,
+                state.transport.position(),
+            );
+            return;
+        }
+
+        let step = &mut sequence.patterns[self.pattern].tracks[self.selected_track].steps
+            [self.selected_step];
+        if step.is_some_and(|step| step.note == note) {
+            *step = None;
+        } else {
+            *step = Some(Step::new(note, velocity));
+            drop(sequence);
+            self.move_cursor(state, true);
+        }
+    }
+}
 
 impl Screen for ComposeScreen {
     fn entry(&mut self) {}
@@ -18,17 +245,145 @@ impl Screen for ComposeScreen {
         D: DrawTarget,
         D::Color: RgbColor,
     {
-        target.clear(D::Color::BLACK);
+        let _ = target.clear(D::Color::BLACK);
+        let style = MonoTextStyle::new(&FONT_6X10, D::Color::BLUE);
+        let selected_style = MonoTextStyle::new(&FONT_6X10, D::Color::YELLOW);
+        let record_style = MonoTextStyle::new(&FONT_6X10, D::Color::RED);
+
+        let sequence = state.sequencer.lock();
+        let pattern = &sequence.patterns[self.pattern];
+        let page = self.selected_step / STEPS_PER_PAGE;
+        let first = page * STEPS_PER_PAGE;
+        // Only show the playhead while the pattern being edited is the one playing
+        let playing_step = sequence
+            .playhead
+            .filter(|_| sequence.playing_pattern() == self.pattern)
+            .map(|playhead| playhead.step);
+
+        let chain = sequence
+            .chain
+            .iter()
+            .map(|pattern| (pattern + 1).to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = Text::new(
+            &format!(
+                "Pattern {}  Steps {}-{} of {}  {:.0} BPM",
+                self.pattern + 1,
+                first + 1,
+                (first + STEPS_PER_PAGE).min(pattern.length),
+                pattern.length,
+                state.transport.tempo.value(),
+            ),
+            Point::new(4, 10),
+            style,
+        )
+        .draw(target);
+        let _ = Text::new(&format!("Chain {}", chain), Point::new(4, 22), style).draw(target);
+        if sequence.recording {
+            let _ =
+                Text::with_alignment("REC", Point::new(316, 22), record_style, Alignment::Right)
+                    .draw(target);
+        }
+
+        for (index, track) in pattern.tracks.iter().enumerate() {
+            let top = GRID_TOP + index as i32 * (CELL_HEIGHT + 4);
+            for column in 0..STEPS_PER_PAGE {
+                let step = first + column;
+                if step >= pattern.length {
+                    break;
+                }
+                let cell = Rectangle::new(
+                    Point::new(column as i32 * CELL_WIDTH + 1, top),
+                    Size::new(CELL_WIDTH as u32 - 2, CELL_HEIGHT as u32),
+                );
+                let color = if playing_step == Some(step) {
+                    D::Color::GREEN
+                } else if track.muted {
+                    D::Color::RED
+                } else {
+                    D::Color::BLUE
+                };
+                let cell_style = match track.steps[step] {
+                    Some(_) => PrimitiveStyle::with_fill(color),
+                    None => PrimitiveStyle::with_stroke(color, 1),
+                };
+                let _ = cell.into_styled(cell_style).draw(target);
+
+                // Beats are marked so steps are easier to count
+                if step.is_multiple_of(4) {
+                    let _ = Rectangle::new(
+                        Point::new(column as i32 * CELL_WIDTH + 1, top + CELL_HEIGHT + 1),
+                        Size::new(CELL_WIDTH as u32 - 2, 2),
+                    )
+                    .into_styled(PrimitiveStyle::with_fill(D::Color::WHITE))
+                    .draw(target);
+                }
+                if index == self.selected_track && step == self.selected_step {
+                    let _ = cell
+                        .offset(1)
+                        .into_styled(PrimitiveStyle::with_stroke(D::Color::YELLOW, 2))
+                        .draw(target);
+                }
+            }
+        }
+
+        let track = &pattern.tracks[self.selected_track];
+        let step = match track.steps[self.selected_step] {
+            Some(step) => format!(
+                "{} vel {} gate {:.0}% prob {:.0}%",
+                step.note,
+                u8::from(step.velocity),
+                step.gate * 100.0,
+                step.probability * 100.0
+            ),
+            None => "Empty".to_string(),
+        };
+        let output = match track.destination {
+            Destination::Engine => "Engine".to_string(),
+            Destination::Midi if state.midi_ports.output().is_none() => {
+                format!("MIDI ch {} (no port)", track.channel.number())
+            }
+            Destination::Midi => format!("MIDI ch {}", track.channel.number()),
+        };
+        let _ = Text::new(
+            &format!(
+                "Track {} step {}  {}{}",
+                self.selected_track + 1,
+                self.selected_step + 1,
+                output,
+                if track.muted { "  Muted" } else { "" }
+            ),
+            Point::new(4, 210),
+            style,
+        )
+        .draw(target);
+        let _ = Text::new(&step, Point::new(4, 222), style).draw(target);
+        let text = Text::new(
+            &format!("{}", self.selected_field),
+            Point::new(4, 234),
+            selected_style,
+        )
+        .draw(target);
+
+        match text {
+            Ok(_) => {}
+            Err(_) => panic!("Error drawing text"),
+        };
         Ok(())
     }
 
     fn update(&mut self, state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
-        while !actions.is_empty() {
-            if let Some(action) = actions.pop() {
-                match action {
-                    ActionMessage::X => return Some(Event::OpenModeMenu),
-                    _ => (),
-                };
+        while let Some(action) = actions.pop() {
+            match action {
+                ActionMessage::X => return Some(Event::OpenModeMenu),
+                ActionMessage::Y => self.selected_field = self.selected_field.next(),
+                ActionMessage::Left => self.move_cursor(state, false),
+                ActionMessage::Right => self.move_cursor(state, true),
+                ActionMessage::Up => self.adjust(state, true),
+                ActionMessage::Down => self.adjust(state, false),
+                ActionMessage::NoteOn(note, velocity) => self.note_on(state, note, velocity),
+                _ => (),
             }
         }
         None
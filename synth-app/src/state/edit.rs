@@ -1,35 +1,380 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{convert::Infallible, fmt, path::PathBuf, sync::Arc};
 
 use crossbeam::queue::SegQueue;
-use embedded_graphics::{draw_target::DrawTarget, pixelcolor::RgbColor, prelude::*};
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::RgbColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle},
+    text::Text,
+};
+use log::{info, warn};
 
-use crate::app::{ActionMessage, State};
 use super::{Event, Screen};
+use crate::{
+    app::{ActionMessage, State},
+    sample::{Sample, SAMPLES},
+};
+
+const WAVEFORM_CENTER: i32 = 100;
+/// The waveform is drawn across the whole display, a column of peaks per pixel.
+const WAVEFORM_WIDTH: usize = 320;
+const WAVEFORM_HEIGHT: i32 = 60;
+/// Up and Down move points by 1/COARSE of the sample, Left and Right by 1/FINE.
+const COARSE: usize = 100;
+const FINE: usize = 1000;
+/// Added to the name of an edited sample when it is saved, so the original isn't overwritten.
+const EDITED_SUFFIX: &str = "-edit";
 
 #[derive(Debug, PartialEq)]
-pub(crate) struct EditScreen {}
+pub(crate) struct EditScreen {
+    pub(crate) selected_field: Field,
+    /// WAV files in the samples directory.
+    files: Vec<PathBuf>,
+    selected_file: usize,
+    /// Result of the last load or save.
+    status: Option<String>,
+    /// Lowest and highest value in each column of the waveform, `None` once the sample has
+    /// changed until the next update works them out again.
+    peaks: Option<Vec<(f32, f32)>>,
+}
+
+/// What the buttons change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Field {
+    /// Up and Down choose a file, Right loads it and Left goes back to the oscillator.
+    File,
+    Start,
+    End,
+    LoopStart,
+    LoopEnd,
+    Loop,
+    Normalise,
+    Reverse,
+    Trim,
+    Save,
+}
+
+impl Field {
+    fn next(&self) -> Self {
+        use Field::*;
+        match *self {
+            File => Start,
+            Start => End,
+            End => LoopStart,
+            LoopStart => LoopEnd,
+            LoopEnd => Loop,
+            Loop => Normalise,
+            Normalise => Reverse,
+            Reverse => Trim,
+            Trim => Save,
+            Save => File,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::File => write!(f, "File"),
+            Field::Start => write!(f, "Start"),
+            Field::End => write!(f, "End"),
+            Field::LoopStart => write!(f, "Loop start"),
+            Field::LoopEnd => write!(f, "Loop end"),
+            Field::Loop => write!(f, "Loop"),
+            Field::Normalise => write!(f, "Normalise"),
+            Field::Reverse => write!(f, "Reverse"),
+            Field::Trim => write!(f, "Trim"),
+            Field::Save => write!(f, "Save"),
+        }
+    }
+}
+
+impl Default for EditScreen {
+    fn default() -> Self {
+        Self {
+            selected_field: Field::File,
+            files: Vec::new(),
+            selected_file: 0,
+            status: None,
+            peaks: None,
+        }
+    }
+}
+
+/// Formats a number of frames as milliseconds.
+fn millis(frames: usize, sample: &Sample) -> String {
+    format!(
+        "{:.0}ms",
+        frames as f64 * 1000.0 / f64::from(sample.sample_rate())
+    )
+}
+
+impl EditScreen {
+    fn refresh_files(&mut self) {
+        self.files = SAMPLES.list();
+        self.selected_file = self.selected_file.min(self.files.len().saturating_sub(1));
+    }
+
+    fn load(&mut self, state: &State) {
+        let Some(path) = self.files.get(self.selected_file) else {
+            return;
+        };
+        match Sample::load(path) {
+            Ok(sample) => {
+                info!("Loaded sample {}", path.display());
+                self.status = Some(format!("Loaded {} frames", sample.len()));
+                state.sample.set(Some(sample));
+            }
+            Err(e) => {
+                warn!("Failed to load sample: {:#}", e);
+                self.status = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    fn save(&mut self, state: &State) {
+        let Some(sample) = state.sample.get() else {
+            return;
+        };
+        let stem = sample
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let stem = if stem.ends_with(EDITED_SUFFIX) {
+            stem
+        } else {
+            format!("{}{}", stem, EDITED_SUFFIX)
+        };
+        let path = SAMPLES.path().join(format!("{}.wav", stem));
+
+        match sample.save(&path) {
+            Ok(()) => {
+                info!("Saved sample {}", path.display());
+                self.status = Some(format!("Saved {}", path.display()));
+                state.sample.edit(|sample| sample.path = path.clone());
+                self.refresh_files();
+                if let Some(index) = self.files.iter().position(|file| *file == path) {
+                    self.selected_file = index;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to save sample: {:#}", e);
+                self.status = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    /// Moves the selected point by `frames`, which is negative to move it earlier.
+    fn move_point(&self, state: &State, frames: impl Fn(usize) -> isize) {
+        let field = self.selected_field;
+        state.sample.edit(|sample| {
+            let delta = frames(sample.len());
+            let moved = |point: usize| point.saturating_add_signed(delta);
+            match field {
+                Field::Start => sample.set_start(moved(sample.start())),
+                Field::End => sample.set_end(moved(sample.end())),
+                Field::LoopStart => sample.set_loop_start(moved(sample.loop_start())),
+                Field::LoopEnd => sample.set_loop_end(moved(sample.loop_end())),
+                _ => (),
+            }
+        });
+    }
+
+    fn handle(&mut self, state: &State, action: ActionMessage) {
+        use ActionMessage::*;
+        // Anything that loads or edits the sample means the waveform is worked out again
+        if matches!(
+            (self.selected_field, action),
+            (Field::File, Right | Left)
+                | (
+                    Field::Start | Field::End | Field::LoopStart | Field::LoopEnd,
+                    Up | Down | Left | Right
+                )
+                | (Field::Normalise | Field::Reverse | Field::Trim, Right)
+        ) {
+            self.peaks = None;
+        }
+        let coarse = |len: usize| (len / COARSE).max(1) as isize;
+        let fine = |len: usize| (len / FINE).max(1) as isize;
+
+        match (self.selected_field, action) {
+            (Field::File, Up) => self.selected_file = self.selected_file.saturating_sub(1),
+            (Field::File, Down) => {
+                self.selected_file =
+                    (self.selected_file + 1).min(self.files.len().saturating_sub(1))
+            }
+            (Field::File, Right) => self.load(state),
+            (Field::File, Left) => {
+                state.sample.set(None);
+                self.status = None;
+            }
+            (Field::Start | Field::End | Field::LoopStart | Field::LoopEnd, Up) => {
+                self.move_point(state, coarse)
+            }
+            (Field::Start | Field::End | Field::LoopStart | Field::LoopEnd, Down) => {
+                self.move_point(state, |len| -coarse(len))
+            }
+            (Field::Start | Field::End | Field::LoopStart | Field::LoopEnd, Right) => {
+                self.move_point(state, fine)
+            }
+            (Field::Start | Field::End | Field::LoopStart | Field::LoopEnd, Left) => {
+                self.move_point(state, |len| -fine(len))
+            }
+            (Field::Loop, Up | Down | Right) => {
+                state.sample.edit(|sample| sample.looping = !sample.looping)
+            }
+            (Field::Normalise, Right) => state.sample.edit(Sample::normalise),
+            (Field::Reverse, Right) => state.sample.edit(Sample::reverse),
+            (Field::Trim, Right) => state.sample.edit(Sample::trim),
+            (Field::Save, Right) => self.save(state),
+            _ => (),
+        }
+    }
+}
 
 impl Screen for EditScreen {
-    fn entry(&mut self) {}
+    fn entry(&mut self) {
+        self.refresh_files();
+        // The sample may have been changed elsewhere, by loading a preset
+        self.peaks = None;
+    }
+
     fn exit(&mut self) {}
     fn draw<D>(&self, target: &mut D, state: &State) -> Result<(), Infallible>
     where
         D: DrawTarget,
         D::Color: RgbColor,
     {
-        target.clear(D::Color::BLACK);
+        let _ = target.clear(D::Color::BLACK);
+        let style = MonoTextStyle::new(&FONT_6X10, D::Color::BLUE);
+        let selected_style = MonoTextStyle::new(&FONT_6X10, D::Color::YELLOW);
+
+        let file = match self.files.get(self.selected_file) {
+            Some(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            None => format!("No WAV files in {}", SAMPLES.path().display()),
+        };
+        let _ = Text::new(
+            &format!("{} ({}/{})", file, self.selected_file + 1, self.files.len()),
+            Point::new(4, 10),
+            style,
+        )
+        .draw(target);
+
+        match state.sample.get() {
+            Some(sample) => {
+                let name = sample
+                    .path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let _ = Text::new(
+                    &format!(
+                        "Playing {}  {}  {} Hz",
+                        name,
+                        millis(sample.len(), &sample),
+                        sample.sample_rate()
+                    ),
+                    Point::new(4, 22),
+                    style,
+                )
+                .draw(target);
+
+                let column = |frame: usize| (frame * WAVEFORM_WIDTH / sample.len().max(1)) as i32;
+                let (start, end) = (column(sample.start()), column(sample.end()));
+                let peaks = self.peaks.as_deref().unwrap_or_default();
+                for (x, &(low, high)) in peaks.iter().enumerate() {
+                    let x = x as i32;
+                    let color = if x >= start && x < end {
+                        D::Color::CYAN
+                    } else {
+                        D::Color::BLUE
+                    };
+                    let _ = Line::new(
+                        Point::new(x, WAVEFORM_CENTER - (high * WAVEFORM_HEIGHT as f32) as i32),
+                        Point::new(x, WAVEFORM_CENTER - (low * WAVEFORM_HEIGHT as f32) as i32),
+                    )
+                    .into_styled(PrimitiveStyle::with_stroke(color, 1))
+                    .draw(target);
+                }
+
+                let mut markers = vec![(start, D::Color::GREEN), (end - 1, D::Color::GREEN)];
+                if sample.looping {
+                    markers.push((column(sample.loop_start()), D::Color::YELLOW));
+                    markers.push((column(sample.loop_end()) - 1, D::Color::YELLOW));
+                }
+                for (x, color) in markers {
+                    let _ = Line::new(
+                        Point::new(x, WAVEFORM_CENTER - WAVEFORM_HEIGHT),
+                        Point::new(x, WAVEFORM_CENTER + WAVEFORM_HEIGHT),
+                    )
+                    .into_styled(PrimitiveStyle::with_stroke(color, 1))
+                    .draw(target);
+                }
+
+                let _ = Text::new(
+                    &format!(
+                        "Start {}  End {}",
+                        millis(sample.start(), &sample),
+                        millis(sample.end(), &sample)
+                    ),
+                    Point::new(4, 186),
+                    style,
+                )
+                .draw(target);
+                let _ = Text::new(
+                    &format!(
+                        "Loop {} - {}  {}",
+                        millis(sample.loop_start(), &sample),
+                        millis(sample.loop_end(), &sample),
+                        if sample.looping { "On" } else { "Off" }
+                    ),
+                    Point::new(4, 198),
+                    style,
+                )
+                .draw(target);
+            }
+            None => {
+                let _ = Text::new("Playing the oscillator", Point::new(4, 22), style).draw(target);
+            }
+        }
+
+        if let Some(status) = &self.status {
+            let _ = Text::new(status, Point::new(4, 222), style).draw(target);
+        }
+        let text = Text::new(
+            &format!("{}", self.selected_field),
+            Point::new(4, 234),
+            selected_style,
+        )
+        .draw(target);
+
+        match text {
+            Ok(_) => {}
+            Err(_) => panic!("Error drawing text"),
+        };
         Ok(())
     }
 
     fn update(&mut self, state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
-        while !actions.is_empty() {
-            if let Some(action) = actions.pop() {
-                match action {
-                    ActionMessage::X => return Some(Event::OpenModeMenu),
-                    _ => (),
-                };
+        while let Some(action) = actions.pop() {
+            match action {
+                ActionMessage::X => return Some(Event::OpenModeMenu),
+                ActionMessage::Y => self.selected_field = self.selected_field.next(),
+                action => self.handle(state, action),
             }
         }
+        if self.peaks.is_none() {
+            self.peaks = state
+                .sample
+                .get()
+                .map(|sample| sample.peaks(WAVEFORM_WIDTH));
+        }
         None
     }
 }
@@ -10,8 +10,8 @@ use embedded_graphics::{
     text::Text,
 };
 
-use crate::app::{ActionMessage, State};
 use super::{Event, Screen};
+use crate::app::{ActionMessage, State};
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct ErrorScreen {
@@ -19,7 +19,7 @@ pub(crate) struct ErrorScreen {
 }
 
 impl Screen for ErrorScreen {
-    fn draw<D>(&self, target: &mut D, state: &State) -> Result<(), Infallible>
+    fn draw<D>(&self, target: &mut D, _state: &State) -> Result<(), Infallible>
     where
         D: DrawTarget,
         D::Color: RgbColor,
@@ -40,7 +40,12 @@ impl Screen for ErrorScreen {
         Ok(())
     }
 
-    fn update(&mut self, state: &State, _: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+    fn update(&mut self, _state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+        while let Some(action) = actions.pop() {
+            if action == ActionMessage::X {
+                return Some(Event::Quit);
+            }
+        }
         None
     }
 
@@ -3,13 +3,21 @@ pub mod edit;
 pub mod error;
 pub mod mode;
 pub mod play;
+pub mod presets;
 pub mod startup;
+pub mod visualiser;
 
 use crate::app::{ActionMessage, State};
 
 use self::{
-    compose::ComposeScreen, edit::EditScreen, error::ErrorScreen, mode::ModeScreen,
-    play::PlayScreen, startup::StartupScreen,
+    compose::ComposeScreen,
+    edit::EditScreen,
+    error::ErrorScreen,
+    mode::{Mode, ModeScreen},
+    play::PlayScreen,
+    presets::PresetScreen,
+    startup::StartupScreen,
+    visualiser::VisualiserScreen,
 };
 use crossbeam::queue::SegQueue;
 use embedded_graphics::{draw_target::DrawTarget, pixelcolor::RgbColor};
@@ -31,6 +39,8 @@ pub(crate) enum Machine {
     Mode(ModeScreen),
     Compose(ComposeScreen),
     Edit(EditScreen),
+    Visualise(VisualiserScreen),
+    Presets(PresetScreen),
     Error(ErrorScreen),
 }
 
@@ -41,12 +51,88 @@ impl fmt::Display for Machine {
             Machine::Mode(_) => write!(f, "Mode"),
             Machine::Compose(_) => write!(f, "Compose"),
             Machine::Edit(_) => write!(f, "Edit"),
+            Machine::Visualise(_) => write!(f, "Visualise"),
+            Machine::Presets(_) => write!(f, "Presets"),
             Machine::Play(_) => write!(f, "Play"),
             Machine::Error(ErrorScreen { message }) => write!(f, "Error: {}", message),
         }
     }
 }
 
+impl From<Mode> for Machine {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Play => Machine::Play(PlayScreen::default()),
+            Mode::Compose => Machine::Compose(ComposeScreen::default()),
+            Mode::Edit => Machine::Edit(EditScreen::default()),
+            Mode::Visualise => Machine::Visualise(VisualiserScreen::default()),
+            Mode::Presets => Machine::Presets(PresetScreen::default()),
+        }
+    }
+}
+
+impl Machine {
+    pub(crate) fn entry(&mut self) {
+        match self {
+            Machine::Startup(screen) => screen.entry(),
+            Machine::Play(screen) => screen.entry(),
+            Machine::Mode(screen) => screen.entry(),
+            Machine::Compose(screen) => screen.entry(),
+            Machine::Edit(screen) => screen.entry(),
+            Machine::Visualise(screen) => screen.entry(),
+            Machine::Presets(screen) => screen.entry(),
+            Machine::Error(screen) => screen.entry(),
+        }
+    }
+
+    pub(crate) fn exit(&mut self) {
+        match self {
+            Machine::Startup(screen) => screen.exit(),
+            Machine::Play(screen) => screen.exit(),
+            Machine::Mode(screen) => screen.exit(),
+            Machine::Compose(screen) => screen.exit(),
+            Machine::Edit(screen) => screen.exit(),
+            Machine::Visualise(screen) => screen.exit(),
+            Machine::Presets(screen) => screen.exit(),
+            Machine::Error(screen) => screen.exit(),
+        }
+    }
+
+    pub(crate) fn update(
+        &mut self,
+        shared: &State,
+        actions: Arc<SegQueue<ActionMessage>>,
+    ) -> Option<Event> {
+        match self {
+            Machine::Startup(screen) => screen.update(shared, actions),
+            Machine::Play(screen) => screen.update(shared, actions),
+            Machine::Mode(screen) => screen.update(shared, actions),
+            Machine::Compose(screen) => screen.update(shared, actions),
+            Machine::Edit(screen) => screen.update(shared, actions),
+            Machine::Visualise(screen) => screen.update(shared, actions),
+            Machine::Presets(screen) => screen.update(shared, actions),
+            Machine::Error(screen) => screen.update(shared, actions),
+        }
+    }
+
+    pub(crate) fn draw<D>(&self, target: &mut D, shared: &State) -> Result<(), Infallible>
+    where
+        D: DrawTarget,
+        D::Color: RgbColor,
+    {
+        match self {
+            Machine::Startup(screen) => screen.draw(target, shared),
+            Machine::Play(screen) => screen.draw(target, shared),
+            Machine::Mode(screen) => screen.draw(target, shared),
+            Machine::Compose(screen) => screen.draw(target, shared),
+            Machine::Edit(screen) => screen.draw(target, shared),
+            Machine::Visualise(screen) => screen.draw(target, shared),
+            Machine::Presets(screen) => screen.draw(target, shared),
+            Machine::Error(screen) => screen.draw(target, shared),
+        }
+    }
+}
+
 pub(crate) trait Screen {
     fn entry(&mut self);
     fn update(&mut self, shared: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event>;
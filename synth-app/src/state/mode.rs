@@ -13,14 +13,16 @@ use embedded_graphics::{
     text::{Alignment, Text},
 };
 
-use crate::app::{ActionMessage, State};
 use super::{Event, Screen};
+use crate::app::{ActionMessage, State};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Mode {
     Play,
     Compose,
     Edit,
+    Visualise,
+    Presets,
 }
 
 impl Mode {
@@ -29,7 +31,9 @@ impl Mode {
         match *self {
             Play => Compose,
             Compose => Edit,
-            Edit => Play,
+            Edit => Visualise,
+            Visualise => Presets,
+            Presets => Play,
         }
     }
     fn peek_next(&self) -> Self {
@@ -37,15 +41,19 @@ impl Mode {
         match self {
             Play => Compose,
             Compose => Edit,
-            Edit => Play,
+            Edit => Visualise,
+            Visualise => Presets,
+            Presets => Play,
         }
     }
     fn peek_prev(&self) -> Self {
         use Mode::*;
         match self {
-            Play => Edit,
+            Play => Presets,
             Compose => Play,
             Edit => Compose,
+            Visualise => Edit,
+            Presets => Visualise,
         }
     }
 }
@@ -61,6 +69,8 @@ impl fmt::Display for Mode {
             Mode::Play => write!(f, "Play"),
             Mode::Compose => write!(f, "Compose"),
             Mode::Edit => write!(f, "Edit"),
+            Mode::Visualise => write!(f, "Visualise"),
+            Mode::Presets => write!(f, "Presets"),
         }
     }
 }
@@ -68,18 +78,18 @@ impl fmt::Display for Mode {
 impl Screen for ModeScreen {
     fn entry(&mut self) {}
     fn exit(&mut self) {}
-    fn draw<D>(&self, target: &mut D, state: &State) -> Result<(), Infallible>
+    fn draw<D>(&self, target: &mut D, _state: &State) -> Result<(), Infallible>
     where
         D: DrawTarget,
         D::Color: RgbColor,
     {
-        target.clear(D::Color::BLACK);
+        let _ = target.clear(D::Color::BLACK);
 
         // Create a new character style
         let style = MonoTextStyle::new(&FONT_10X20, D::Color::BLUE);
         let dim_style = MonoTextStyle::new(&FONT_6X10, D::Color::WHITE);
 
-        let text = Text::with_alignment(
+        let _ = Text::with_alignment(
             &format!("{}", self.selected_mode),
             Point::new(320 / 2, 160 / 2),
             style,
@@ -87,7 +97,7 @@ impl Screen for ModeScreen {
         )
         .draw(target);
 
-        let text = Text::with_alignment(
+        let _ = Text::with_alignment(
             &format!("{}", self.selected_mode.peek_prev()),
             Point::new(320 / 2, 160 / 2 + 20),
             dim_style,
@@ -110,7 +120,7 @@ impl Screen for ModeScreen {
         Ok(())
     }
 
-    fn update(&mut self, state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+    fn update(&mut self, _state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
         while !actions.is_empty() {
             if let Some(action) = actions.pop() {
                 match action {
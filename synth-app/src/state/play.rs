@@ -10,33 +10,271 @@ use embedded_graphics::{
     primitives::{Line, Polyline, PrimitiveStyle, Rectangle},
     text::{Alignment, Text},
 };
+use fundsp::hacker::Shared;
+use synth_core::random::{self, xorshift};
+use wmidi::ControlFunction;
 
 use super::{Event, Screen};
-use crate::app::{ActionMessage, State};
+use crate::{
+    app::{ActionMessage, State},
+    engine::{
+        effects::{EffectKind, MAX_BITS, MAX_DELAY, MAX_DOWNSAMPLE, MAX_FEEDBACK, NUM_SLOTS},
+        filter::{MAX_CUTOFF, MIN_CUTOFF},
+        lfo::{DIVISIONS, MAX_RATE, MIN_RATE, NUM_LFOS},
+    },
+    midi::{input_ports, next_port, output_ports},
+    recorder::{RecordState, RECORDINGS},
+};
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct PlayScreen {
     pub(crate) selected_menu: EngineMenu,
+    /// The filter setting Up and Down change on the Filter page.
+    pub(crate) selected_filter_param: FilterParam,
+    /// The LFO shown on the LFO page, and the setting Up and Down change.
+    pub(crate) selected_lfo: usize,
+    pub(crate) selected_lfo_param: LfoParam,
+    /// The effects rack slot shown on the Effects page, and the index of the setting Up and Down
+    /// change in `effect_params` for the effect in it.
+    pub(crate) selected_slot: usize,
+    pub(crate) selected_effect_param: usize,
+    /// The port Up and Down change on the MIDI page.
+    pub(crate) selected_midi_param: MidiParam,
 }
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum EngineMenu {
     Control = 0,
-    ADSR = 1,
+    Adsr = 1,
     Filter = 2,
-    Effects = 3,
+    Lfo = 3,
+    Effects = 4,
+    Record = 5,
+    Midi = 6,
 }
 
 const MARGIN: i32 = 40;
+/// Range of the filter response plot in dB.
+const RESPONSE_MIN_DB: f64 = -36.0;
+const RESPONSE_MAX_DB: f64 = 24.0;
+/// Up and Down move the cutoff a semitone at a time.
+const CUTOFF_STEP: f64 = 1.0 / 12.0;
+const AMOUNT_STEP: f64 = 0.05;
+/// Up and Down move a free running LFO's rate a sixth of an octave at a time.
+const RATE_STEP: f64 = 1.0 / 6.0;
+/// Cycles of the LFO drawn across the LFO page.
+const LFO_CYCLES: f64 = 2.0;
+const SLOT_HEIGHT: i32 = 30;
+const ROW_HEIGHT: i32 = 12;
+/// The effect settings are listed under the page name.
+const EFFECT_PARAMS_TOP: i32 = 240 / 2 + 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FilterParam {
+    Mode,
+    Slope,
+    Cutoff,
+    Resonance,
+    Envelope,
+    KeyTracking,
+}
+
+impl FilterParam {
+    fn next(&self) -> Self {
+        use FilterParam::*;
+        match *self {
+            Mode => Slope,
+            Slope => Cutoff,
+            Cutoff => Resonance,
+            Resonance => Envelope,
+            Envelope => KeyTracking,
+            KeyTracking => Mode,
+        }
+    }
+
+    fn prev(&self) -> Self {
+        use FilterParam::*;
+        match *self {
+            Mode => KeyTracking,
+            Slope => Mode,
+            Cutoff => Slope,
+            Resonance => Cutoff,
+            Envelope => Resonance,
+            KeyTracking => Envelope,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LfoParam {
+    /// Which LFO the page shows.
+    Lfo,
+    Shape,
+    Destination,
+    Rate,
+    Sync,
+    Trigger,
+    Depth,
+}
+
+impl LfoParam {
+    fn next(&self) -> Self {
+        use LfoParam::*;
+        match *self {
+            Lfo => Shape,
+            Shape => Destination,
+            Destination => Rate,
+            Rate => Sync,
+            Sync => Trigger,
+            Trigger => Depth,
+            Depth => Lfo,
+        }
+    }
+
+    fn prev(&self) -> Self {
+        use LfoParam::*;
+        match *self {
+            Lfo => Depth,
+            Shape => Lfo,
+            Destination => Shape,
+            Rate => Destination,
+            Sync => Rate,
+            Trigger => Sync,
+            Depth => Trigger,
+        }
+    }
+}
+
+impl fmt::Display for LfoParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LfoParam::Lfo => write!(f, "LFO"),
+            LfoParam::Shape => write!(f, "Shape"),
+            LfoParam::Destination => write!(f, "Destination"),
+            LfoParam::Rate => write!(f, "Rate"),
+            LfoParam::Sync => write!(f, "Sync"),
+            LfoParam::Trigger => write!(f, "Trigger"),
+            LfoParam::Depth => write!(f, "Depth"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MidiParam {
+    ClockInput,
+    ClockOutput,
+    Output,
+}
+
+impl MidiParam {
+    fn next(&self) -> Self {
+        use MidiParam::*;
+        match *self {
+            ClockInput => ClockOutput,
+            ClockOutput => Output,
+            Output => ClockInput,
+        }
+    }
+
+    fn prev(&self) -> Self {
+        use MidiParam::*;
+        match *self {
+            ClockInput => Output,
+            ClockOutput => ClockInput,
+            Output => ClockOutput,
+        }
+    }
+}
+
+impl fmt::Display for MidiParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiParam::ClockInput => write!(f, "Clock in"),
+            MidiParam::ClockOutput => write!(f, "Clock out"),
+            MidiParam::Output => write!(f, "MIDI out"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EffectParam {
+    /// Which slot the page shows.
+    Slot,
+    /// Up and Down move the effect to a later or earlier slot.
+    Move,
+    Bypass,
+    Mix,
+    Time,
+    Feedback,
+    Sync,
+    Size,
+    Damping,
+    Rate,
+    Depth,
+    /// Chorus or flanger.
+    Mode,
+    Drive,
+    Bits,
+    Downsample,
+}
+
+impl fmt::Display for EffectParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EffectParam::Slot => write!(f, "Slot"),
+            EffectParam::Move => write!(f, "Move"),
+            EffectParam::Bypass => write!(f, "Bypass"),
+            EffectParam::Mix => write!(f, "Mix"),
+            EffectParam::Time => write!(f, "Time"),
+            EffectParam::Feedback => write!(f, "Feedback"),
+            EffectParam::Sync => write!(f, "Sync"),
+            EffectParam::Size => write!(f, "Size"),
+            EffectParam::Damping => write!(f, "Damping"),
+            EffectParam::Rate => write!(f, "Rate"),
+            EffectParam::Depth => write!(f, "Depth"),
+            EffectParam::Mode => write!(f, "Mode"),
+            EffectParam::Drive => write!(f, "Drive"),
+            EffectParam::Bits => write!(f, "Bits"),
+            EffectParam::Downsample => write!(f, "Downsample"),
+        }
+    }
+}
+
+/// The settings listed on the Effects page for each kind of effect.
+fn effect_params(kind: EffectKind) -> &'static [EffectParam] {
+    use EffectParam::*;
+    match kind {
+        EffectKind::Drive => &[Slot, Move, Bypass, Mix, Drive, Bits, Downsample],
+        EffectKind::Chorus => &[Slot, Move, Bypass, Mix, Mode, Rate, Depth, Feedback],
+        EffectKind::Delay => &[Slot, Move, Bypass, Mix, Sync, Time, Feedback],
+        EffectKind::Reverb => &[Slot, Move, Bypass, Mix, Size, Damping],
+    }
+}
+
+impl fmt::Display for FilterParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterParam::Mode => write!(f, "Mode"),
+            FilterParam::Slope => write!(f, "Slope"),
+            FilterParam::Cutoff => write!(f, "Cutoff"),
+            FilterParam::Resonance => write!(f, "Resonance"),
+            FilterParam::Envelope => write!(f, "Envelope"),
+            FilterParam::KeyTracking => write!(f, "Key tracking"),
+        }
+    }
+}
 
 impl EngineMenu {
     fn next(&self) -> Self {
         use EngineMenu::*;
         match *self {
-            Control => ADSR,
-            ADSR => Filter,
-            Filter => Effects,
-            Effects => Control,
+            Control => Adsr,
+            Adsr => Filter,
+            Filter => Lfo,
+            Lfo => Effects,
+            Effects => Record,
+            Record => Midi,
+            Midi => Control,
         }
     }
 }
@@ -45,9 +283,12 @@ impl fmt::Display for EngineMenu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             EngineMenu::Control => write!(f, "Control"),
-            EngineMenu::ADSR => write!(f, "ADSR"),
+            EngineMenu::Adsr => write!(f, "ADSR"),
             EngineMenu::Filter => write!(f, "Filter"),
+            EngineMenu::Lfo => write!(f, "LFO"),
             EngineMenu::Effects => write!(f, "Effects"),
+            EngineMenu::Record => write!(f, "Record"),
+            EngineMenu::Midi => write!(f, "MIDI"),
         }
     }
 }
@@ -56,17 +297,23 @@ impl Default for PlayScreen {
     fn default() -> Self {
         Self {
             selected_menu: EngineMenu::Control,
+            selected_filter_param: FilterParam::Cutoff,
+            selected_lfo: 0,
+            selected_lfo_param: LfoParam::Shape,
+            selected_slot: 0,
+            selected_effect_param: 0,
+            selected_midi_param: MidiParam::ClockInput,
         }
     }
 }
 
-fn bezier_curve<'a>(
+fn bezier_curve(
     start: Point,
     control1: Point,
     control2: Point,
     end: Point,
     steps: u32,
-) -> Polyline<'a> {
+) -> Vec<Point> {
     let mut points = Vec::with_capacity(steps as usize);
     for i in 0..steps {
         let t = i as f32 / steps as f32;
@@ -80,7 +327,132 @@ fn bezier_curve<'a>(
             + t.powi(3) * end.y as f32;
         points.push(Point::new(x.round() as i32, y.round() as i32));
     }
-    Polyline::new(Box::leak(points.into_boxed_slice()))
+    points
+}
+
+impl PlayScreen {
+    /// Changes the selected filter setting, `direction` is 1.0 for Up and -1.0 for Down.
+    fn adjust_filter(&self, shared: &State, direction: f64) {
+        let filter = &shared.filter;
+        match self.selected_filter_param {
+            FilterParam::Mode => filter.set_mode(filter.mode().next()),
+            FilterParam::Slope => filter.set_slope(filter.slope().next()),
+            FilterParam::Cutoff => {
+                filter.set_cutoff(filter.cutoff.value() * (CUTOFF_STEP * direction).exp2())
+            }
+            FilterParam::Resonance => filter
+                .resonance
+                .set_value((filter.resonance.value() + AMOUNT_STEP * direction).clamp(0.0, 1.0)),
+            FilterParam::Envelope => filter.envelope_amount.set_value(
+                (filter.envelope_amount.value() + AMOUNT_STEP * direction).clamp(-1.0, 1.0),
+            ),
+            FilterParam::KeyTracking => filter
+                .key_tracking
+                .set_value((filter.key_tracking.value() + AMOUNT_STEP * direction).clamp(0.0, 1.0)),
+        }
+    }
+
+    fn selected_effect(&self, shared: &State) -> (EffectKind, EffectParam) {
+        let kind = shared.effects.order()[self.selected_slot];
+        let params = effect_params(kind);
+        (
+            kind,
+            params[self.selected_effect_param.min(params.len() - 1)],
+        )
+    }
+
+    /// Moves to the next setting on the Effects page if `forward`, otherwise the previous one.
+    fn select_effect_param(&mut self, shared: &State, forward: bool) {
+        let count = effect_params(shared.effects.order()[self.selected_slot]).len();
+        let index = self.selected_effect_param.min(count - 1);
+        self.selected_effect_param = if forward {
+            (index + 1) % count
+        } else {
+            (index + count - 1) % count
+        };
+    }
+
+    /// Changes the selected effect setting, `direction` is 1.0 for Up and -1.0 for Down.
+    fn adjust_effect(&mut self, shared: &State, direction: f64) {
+        let effects = &shared.effects;
+        let (kind, param) = self.selected_effect(shared);
+        let step = |value: &Shared<f64>, amount: f64, min: f64, max: f64| {
+            value.set_value((value.value() + amount * direction).clamp(min, max))
+        };
+        let scale = |value: &Shared<f64>, min: f64, max: f64| {
+            value.set_value((value.value() * (RATE_STEP * direction).exp2()).clamp(min, max))
+        };
+
+        match (kind, param) {
+            (_, EffectParam::Slot) => {
+                self.selected_slot = if direction > 0.0 {
+                    (self.selected_slot + 1).min(NUM_SLOTS - 1)
+                } else {
+                    self.selected_slot.saturating_sub(1)
+                }
+            }
+            (_, EffectParam::Move) => {
+                self.selected_slot = effects.move_slot(self.selected_slot, direction > 0.0)
+            }
+            (_, EffectParam::Bypass) => effects.set_bypassed(kind, !effects.is_bypassed(kind)),
+            (_, EffectParam::Mix) => step(effects.mix(kind), AMOUNT_STEP, 0.0, 1.0),
+            (_, EffectParam::Sync) => effects.delay.set_synced(!effects.delay.is_synced()),
+            // Up is a longer delay, so more beats
+            (_, EffectParam::Time) if effects.delay.is_synced() => {
+                let division = effects.delay.division();
+                effects.delay.set_division(if direction > 0.0 {
+                    division + 1
+                } else {
+                    division.saturating_sub(1)
+                });
+            }
+            (_, EffectParam::Time) => scale(&effects.delay.time, 0.001, MAX_DELAY),
+            (EffectKind::Chorus, EffectParam::Feedback) => {
+                step(&effects.chorus.feedback, AMOUNT_STEP, 0.0, MAX_FEEDBACK)
+            }
+            (_, EffectParam::Feedback) => {
+                step(&effects.delay.feedback, AMOUNT_STEP, 0.0, MAX_FEEDBACK)
+            }
+            (_, EffectParam::Size) => step(&effects.reverb.size, AMOUNT_STEP, 0.0, 1.0),
+            (_, EffectParam::Damping) => step(&effects.reverb.damping, AMOUNT_STEP, 0.0, 1.0),
+            (_, EffectParam::Rate) => scale(&effects.chorus.rate, MIN_RATE, MAX_RATE),
+            (_, EffectParam::Depth) => step(&effects.chorus.depth, AMOUNT_STEP, 0.0, 1.0),
+            (_, EffectParam::Mode) => effects.chorus.set_flanger(!effects.chorus.is_flanger()),
+            (_, EffectParam::Drive) => step(&effects.drive.drive, AMOUNT_STEP, 0.0, 1.0),
+            (_, EffectParam::Bits) => step(&effects.drive.bits, 1.0, 1.0, MAX_BITS),
+            (_, EffectParam::Downsample) => {
+                step(&effects.drive.downsample, 1.0, 1.0, MAX_DOWNSAMPLE)
+            }
+        }
+    }
+
+    /// Changes the selected LFO setting, `direction` is 1.0 for Up and -1.0 for Down.
+    fn adjust_lfo(&mut self, shared: &State, direction: f64) {
+        let lfo = &shared.lfos[self.selected_lfo];
+        match self.selected_lfo_param {
+            LfoParam::Lfo if direction > 0.0 => {
+                self.selected_lfo = (self.selected_lfo + 1) % NUM_LFOS
+            }
+            LfoParam::Lfo => self.selected_lfo = (self.selected_lfo + NUM_LFOS - 1) % NUM_LFOS,
+            LfoParam::Shape => lfo.set_shape(lfo.shape().next()),
+            LfoParam::Destination => lfo.set_destination(lfo.destination().next()),
+            LfoParam::Rate if lfo.is_synced() => {
+                // Up is faster, so fewer beats per cycle
+                let division = lfo.division();
+                lfo.set_division(if direction > 0.0 {
+                    division.saturating_sub(1)
+                } else {
+                    division + 1
+                });
+            }
+            LfoParam::Rate => lfo.set_rate(lfo.rate.value() * (RATE_STEP * direction).exp2()),
+            LfoParam::Sync => lfo.set_synced(!lfo.is_synced()),
+            LfoParam::Trigger => lfo.set_retrigger(!lfo.retriggers()),
+            LfoParam::Depth => lfo
+                .depth
+                .set_value((lfo.depth.value() + AMOUNT_STEP * direction).clamp(0.0, 1.0)),
+        }
+    }
 }
 
 impl Screen for PlayScreen {
@@ -104,8 +476,31 @@ impl Screen for PlayScreen {
         .draw(target);
 
         match self.selected_menu {
-            EngineMenu::Control => {}
-            EngineMenu::ADSR => {
+            EngineMenu::Control => {
+                let transport = &shared.transport;
+                let status = match (transport.is_playing(), transport.is_external()) {
+                    (true, true) => "Playing (MIDI clock)",
+                    (true, false) => "Playing",
+                    (false, true) => "Stopped (MIDI clock)",
+                    (false, false) => "Stopped",
+                };
+                // Bars and beats, counting from 1
+                let beat = transport.position().floor() as u32;
+                let _ = Text::with_alignment(
+                    &format!(
+                        "{:.0} BPM  {}.{}  {}",
+                        transport.tempo.value(),
+                        beat / 4 + 1,
+                        beat % 4 + 1,
+                        status
+                    ),
+                    Point::new(320 / 2, 240 / 2 + 20),
+                    style,
+                    Alignment::Center,
+                )
+                .draw(target);
+            }
+            EngineMenu::Adsr => {
                 let attack_start = Point {
                     x: MARGIN,
                     y: 240 - MARGIN,
@@ -144,7 +539,7 @@ impl Screen for PlayScreen {
                         + Linear::ease_out(
                             1.0 - shared.sustain.value(),
                             0.0,
-                            (240.0 - f64::from(MARGIN) * 2.0),
+                            240.0 - f64::from(MARGIN) * 2.0,
                             1.0,
                         )
                         .round() as i32,
@@ -186,37 +581,325 @@ impl Screen for PlayScreen {
                         y: 0,
                     };
 
-                let _attack_curve = bezier_curve(
+                let _attack_curve = Polyline::new(&bezier_curve(
                     attack_start,
                     attack_control_1,
                     attack_control_2,
                     attack_end,
                     50,
-                )
+                ))
                 .into_styled(PrimitiveStyle::with_stroke(D::Color::BLUE, 1))
                 .draw(target);
 
-                let _decay_curve =
-                    bezier_curve(attack_end, decay_control_1, decay_control_2, decay_end, 50)
-                        .into_styled(PrimitiveStyle::with_stroke(D::Color::RED, 1))
-                        .draw(target);
+                let _decay_curve = Polyline::new(&bezier_curve(
+                    attack_end,
+                    decay_control_1,
+                    decay_control_2,
+                    decay_end,
+                    50,
+                ))
+                .into_styled(PrimitiveStyle::with_stroke(D::Color::RED, 1))
+                .draw(target);
 
                 let _ = Line::new(decay_end, sustain_end)
                     .into_styled(PrimitiveStyle::with_stroke(D::Color::GREEN, 1))
                     .draw(target);
 
-                let _release_curve = bezier_curve(
+                let _release_curve = Polyline::new(&bezier_curve(
                     sustain_end,
                     release_control_1,
                     release_control_2,
                     release_end,
                     50,
-                )
+                ))
                 .into_styled(PrimitiveStyle::with_stroke(D::Color::YELLOW, 1))
                 .draw(target);
             }
-            EngineMenu::Filter => {}
-            EngineMenu::Effects => {}
+            EngineMenu::Filter => {
+                let filter = &shared.filter;
+                let cutoff = filter.cutoff.value();
+                let width = 320 - MARGIN * 2;
+                let height = 240 - MARGIN * 2;
+                // Frequencies are spread logarithmically across the plot
+                let octaves = (MAX_CUTOFF / MIN_CUTOFF).log2();
+                let points: Vec<Point> = (0..=width)
+                    .map(|x| {
+                        let frequency =
+                            MIN_CUTOFF * (f64::from(x) / f64::from(width) * octaves).exp2();
+                        let db = 20.0 * filter.response(frequency, cutoff).max(1e-6).log10();
+                        let level = (db.clamp(RESPONSE_MIN_DB, RESPONSE_MAX_DB) - RESPONSE_MIN_DB)
+                            / (RESPONSE_MAX_DB - RESPONSE_MIN_DB);
+                        Point::new(
+                            MARGIN + x,
+                            MARGIN + height - (level * f64::from(height)).round() as i32,
+                        )
+                    })
+                    .collect();
+
+                // 0 dB line
+                let unity = MARGIN + height
+                    - (-RESPONSE_MIN_DB / (RESPONSE_MAX_DB - RESPONSE_MIN_DB) * f64::from(height))
+                        .round() as i32;
+                let _ = Line::new(Point::new(MARGIN, unity), Point::new(MARGIN + width, unity))
+                    .into_styled(PrimitiveStyle::with_stroke(D::Color::WHITE, 1))
+                    .draw(target);
+                let _ = Polyline::new(&points)
+                    .into_styled(PrimitiveStyle::with_stroke(D::Color::YELLOW, 1))
+                    .draw(target);
+
+                let value = match self.selected_filter_param {
+                    FilterParam::Mode => format!("{}", filter.mode()),
+                    FilterParam::Slope => format!("{}", filter.slope()),
+                    FilterParam::Cutoff => format!("{:.0} Hz", cutoff),
+                    FilterParam::Resonance => format!("{:.0}%", filter.resonance.value() * 100.0),
+                    FilterParam::Envelope => {
+                        format!("{:+.0}%", filter.envelope_amount.value() * 100.0)
+                    }
+                    FilterParam::KeyTracking => {
+                        format!("{:.0}%", filter.key_tracking.value() * 100.0)
+                    }
+                };
+                let _ = Text::with_alignment(
+                    &format!("{}: {}", self.selected_filter_param, value),
+                    Point::new(320 / 2, 240 - MARGIN / 2),
+                    style,
+                    Alignment::Center,
+                )
+                .draw(target);
+            }
+            EngineMenu::Lfo => {
+                let lfo = &shared.lfos[self.selected_lfo];
+                let shape = lfo.shape();
+                let depth = lfo.depth.value();
+                let width = 320 - MARGIN * 2;
+                let amplitude = f64::from(240 / 2 - MARGIN);
+                // Sample and hold levels for the plot, from a fixed seed so they don't flicker
+                let mut seed = random::SEED;
+                let held: Vec<f64> = (0..LFO_CYCLES as usize)
+                    .map(|_| f64::from(xorshift(&mut seed)) / f64::from(u32::MAX) * 2.0 - 1.0)
+                    .collect();
+
+                // The full waveform and the waveform scaled by the depth
+                for (scale, color) in [(1.0, D::Color::BLUE), (depth, D::Color::YELLOW)] {
+                    let points: Vec<Point> = (0..=width)
+                        .map(|x| {
+                            let position = f64::from(x) / f64::from(width) * LFO_CYCLES;
+                            let cycle = (position as usize).min(held.len() - 1);
+                            let value = shape.value(position.fract(), held[cycle]) * scale;
+                            Point::new(MARGIN + x, 240 / 2 - (value * amplitude).round() as i32)
+                        })
+                        .collect();
+                    let _ = Polyline::new(&points)
+                        .into_styled(PrimitiveStyle::with_stroke(color, 1))
+                        .draw(target);
+                }
+
+                let rate = if lfo.is_synced() {
+                    DIVISIONS[lfo.division()].1.to_string()
+                } else {
+                    format!("{:.2} Hz", lfo.rate.value())
+                };
+                let _ = Text::with_alignment(
+                    &format!(
+                        "LFO {}  {} > {}  {}  {}",
+                        self.selected_lfo + 1,
+                        shape,
+                        lfo.destination(),
+                        rate,
+                        if lfo.retriggers() {
+                            "Retrigger"
+                        } else {
+                            "Free"
+                        }
+                    ),
+                    Point::new(320 / 2, MARGIN / 2),
+                    style,
+                    Alignment::Center,
+                )
+                .draw(target);
+
+                let value = match self.selected_lfo_param {
+                    LfoParam::Lfo => format!("{}", self.selected_lfo + 1),
+                    LfoParam::Shape => format!("{}", shape),
+                    LfoParam::Destination => format!("{}", lfo.destination()),
+                    LfoParam::Rate => rate,
+                    LfoParam::Sync => (if lfo.is_synced() { "Tempo" } else { "Free" }).to_string(),
+                    LfoParam::Trigger => (if lfo.retriggers() {
+                        "Each note"
+                    } else {
+                        "Global"
+                    })
+                    .to_string(),
+                    LfoParam::Depth => format!("{:.0}%", depth * 100.0),
+                };
+                let _ = Text::with_alignment(
+                    &format!("{}: {}", self.selected_lfo_param, value),
+                    Point::new(320 / 2, 240 - MARGIN / 2),
+                    style,
+                    Alignment::Center,
+                )
+                .draw(target);
+            }
+            EngineMenu::Effects => {
+                let effects = &shared.effects;
+                let selected_style = MonoTextStyle::new(&FONT_6X10, D::Color::YELLOW);
+                let slot_width = (320 - MARGIN * 2) / NUM_SLOTS as i32;
+
+                // The rack in order, bypassed effects are outlined rather than filled
+                for (slot, kind) in effects.order().into_iter().enumerate() {
+                    let area = Rectangle::new(
+                        Point::new(MARGIN + slot as i32 * slot_width + 2, MARGIN),
+                        Size::new(slot_width as u32 - 4, SLOT_HEIGHT as u32),
+                    );
+                    let (area_style, text_color) = if effects.is_bypassed(kind) {
+                        (
+                            PrimitiveStyle::with_stroke(D::Color::BLUE, 1),
+                            D::Color::BLUE,
+                        )
+                    } else {
+                        (PrimitiveStyle::with_fill(D::Color::BLUE), D::Color::WHITE)
+                    };
+                    let _ = area.into_styled(area_style).draw(target);
+                    if slot == self.selected_slot {
+                        let _ = area
+                            .offset(1)
+                            .into_styled(PrimitiveStyle::with_stroke(D::Color::YELLOW, 1))
+                            .draw(target);
+                    }
+                    let _ = Text::with_alignment(
+                        &format!("{}", kind),
+                        area.center() + Point::new(0, 3),
+                        MonoTextStyle::new(&FONT_6X10, text_color),
+                        Alignment::Center,
+                    )
+                    .draw(target);
+                }
+
+                let (kind, selected) = self.selected_effect(shared);
+                let percent = |value: &Shared<f64>| format!("{:.0}%", value.value() * 100.0);
+                for (row, param) in effect_params(kind).iter().enumerate() {
+                    let value = match (kind, param) {
+                        (_, EffectParam::Slot) => format!("{}", self.selected_slot + 1),
+                        (_, EffectParam::Move) => format!("{}", kind),
+                        (_, EffectParam::Bypass) => (if effects.is_bypassed(kind) {
+                            "On"
+                        } else {
+                            "Off"
+                        })
+                        .to_string(),
+                        (_, EffectParam::Mix) => percent(effects.mix(kind)),
+                        (_, EffectParam::Sync) => (if effects.delay.is_synced() {
+                            "Tempo"
+                        } else {
+                            "Free"
+                        })
+                        .to_string(),
+                        (_, EffectParam::Time) if effects.delay.is_synced() => {
+                            DIVISIONS[effects.delay.division()].1.to_string()
+                        }
+                        (_, EffectParam::Time) => {
+                            format!("{:.0}ms", effects.delay.time.value() * 1000.0)
+                        }
+                        (EffectKind::Chorus, EffectParam::Feedback) => {
+                            percent(&effects.chorus.feedback)
+                        }
+                        (_, EffectParam::Feedback) => percent(&effects.delay.feedback),
+                        (_, EffectParam::Size) => percent(&effects.reverb.size),
+                        (_, EffectParam::Damping) => percent(&effects.reverb.damping),
+                        (_, EffectParam::Rate) => format!("{:.2} Hz", effects.chorus.rate.value()),
+                        (_, EffectParam::Depth) => percent(&effects.chorus.depth),
+                        (_, EffectParam::Mode) => (if effects.chorus.is_flanger() {
+                            "Flanger"
+                        } else {
+                            "Chorus"
+                        })
+                        .to_string(),
+                        (_, EffectParam::Drive) => percent(&effects.drive.drive),
+                        (_, EffectParam::Bits) => format!("{:.0}", effects.drive.bits.value()),
+                        (_, EffectParam::Downsample) => {
+                            format!("{:.0}x", effects.drive.downsample.value())
+                        }
+                    };
+                    let _ = Text::new(
+                        &format!("{}: {}", param, value),
+                        Point::new(MARGIN, EFFECT_PARAMS_TOP + row as i32 * ROW_HEIGHT),
+                        if *param == selected {
+                            selected_style
+                        } else {
+                            style
+                        },
+                    )
+                    .draw(target);
+                }
+            }
+            EngineMenu::Record => {
+                let recorder = &shared.recorder;
+                let status = match recorder.state() {
+                    RecordState::Recording => {
+                        let seconds = recorder.seconds() as u64;
+                        format!("Recording {}:{:02}", seconds / 60, seconds % 60)
+                    }
+                    state => format!("{}", state),
+                };
+                let file = recorder
+                    .path()
+                    .and_then(|path| path.file_name().map(|name| name.to_owned()))
+                    .map_or_else(
+                        || format!("Saves to {}", RECORDINGS.path().display()),
+                        |name| name.to_string_lossy().into_owned(),
+                    );
+                let midi = if recorder.records_midi() { "On" } else { "Off" };
+                let mut lines = vec![
+                    status,
+                    file,
+                    format!("{}  MIDI {}", recorder.format(), midi),
+                ];
+                if recorder.dropped() > 0 {
+                    lines.push(format!("{} frames dropped", recorder.dropped()));
+                }
+                for (row, line) in lines.iter().enumerate() {
+                    let _ = Text::with_alignment(
+                        line,
+                        Point::new(320 / 2, 240 / 2 + 20 + row as i32 * ROW_HEIGHT),
+                        style,
+                        Alignment::Center,
+                    )
+                    .draw(target);
+                }
+            }
+            EngineMenu::Midi => {
+                let ports = &shared.midi_ports;
+                let selected_style = MonoTextStyle::new(&FONT_6X10, D::Color::YELLOW);
+                let lines = [
+                    (
+                        MidiParam::ClockInput,
+                        ports
+                            .clock_input()
+                            .unwrap_or_else(|| "Internal".to_string()),
+                    ),
+                    (
+                        MidiParam::ClockOutput,
+                        ports.clock_output().unwrap_or_else(|| "None".to_string()),
+                    ),
+                    (
+                        MidiParam::Output,
+                        ports.output().unwrap_or_else(|| "None".to_string()),
+                    ),
+                ];
+                for (row, (param, port)) in lines.iter().enumerate() {
+                    let _ = Text::with_alignment(
+                        &format!("{}: {}", param, port),
+                        Point::new(320 / 2, 240 / 2 + 20 + row as i32 * ROW_HEIGHT),
+                        if *param == self.selected_midi_param {
+                            selected_style
+                        } else {
+                            style
+                        },
+                        Alignment::Center,
+                    )
+                    .draw(target);
+                }
+            }
         }
 
         match text {
@@ -227,11 +910,116 @@ impl Screen for PlayScreen {
     }
 
     fn update(&mut self, shared: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+        let transport = &shared.transport;
+        let recorder = &shared.recorder;
         while !actions.is_empty() {
             if let Some(action) = actions.pop() {
-                match action {
-                    ActionMessage::X => return Some(Event::OpenModeMenu),
-                    ActionMessage::Y => self.selected_menu = self.selected_menu.next(),
+                match (action, &self.selected_menu) {
+                    (ActionMessage::X, _) => return Some(Event::OpenModeMenu),
+                    (ActionMessage::Y, _) => self.selected_menu = self.selected_menu.next(),
+                    // The tempo follows incoming MIDI clock when there is any
+                    (ActionMessage::Up, EngineMenu::Control) if !transport.is_external() => {
+                        transport.set_tempo(transport.tempo.value().round() + 1.0)
+                    }
+                    (ActionMessage::Down, EngineMenu::Control) if !transport.is_external() => {
+                        transport.set_tempo(transport.tempo.value().round() - 1.0)
+                    }
+                    (ActionMessage::Right, EngineMenu::Control) => {
+                        if transport.is_playing() {
+                            transport.stop();
+                        } else {
+                            transport.resume();
+                        }
+                    }
+                    (ActionMessage::Left, EngineMenu::Control) => {
+                        transport.stop();
+                        transport.rewind();
+                    }
+                    (ActionMessage::Right, EngineMenu::Filter) => {
+                        self.selected_filter_param = self.selected_filter_param.next()
+                    }
+                    (ActionMessage::Left, EngineMenu::Filter) => {
+                        self.selected_filter_param = self.selected_filter_param.prev()
+                    }
+                    (ActionMessage::Up, EngineMenu::Filter) => self.adjust_filter(shared, 1.0),
+                    (ActionMessage::Down, EngineMenu::Filter) => self.adjust_filter(shared, -1.0),
+                    (ActionMessage::Right, EngineMenu::Lfo) => {
+                        self.selected_lfo_param = self.selected_lfo_param.next()
+                    }
+                    (ActionMessage::Left, EngineMenu::Lfo) => {
+                        self.selected_lfo_param = self.selected_lfo_param.prev()
+                    }
+                    (ActionMessage::Up, EngineMenu::Lfo) => self.adjust_lfo(shared, 1.0),
+                    (ActionMessage::Down, EngineMenu::Lfo) => self.adjust_lfo(shared, -1.0),
+                    (ActionMessage::Right, EngineMenu::Effects) => {
+                        self.select_effect_param(shared, true)
+                    }
+                    (ActionMessage::Left, EngineMenu::Effects) => {
+                        self.select_effect_param(shared, false)
+                    }
+                    (ActionMessage::Up, EngineMenu::Effects) => self.adjust_effect(shared, 1.0),
+                    (ActionMessage::Down, EngineMenu::Effects) => self.adjust_effect(shared, -1.0),
+                    // Right steps from stopped to armed to recording and back to stopped
+                    (ActionMessage::Right, EngineMenu::Record) => match recorder.state() {
+                        RecordState::Idle => recorder.arm(),
+                        RecordState::Armed => recorder.record(),
+                        RecordState::Recording => recorder.stop(),
+                    },
+                    (ActionMessage::Left, EngineMenu::Record) => recorder.stop(),
+                    (ActionMessage::Up, EngineMenu::Record) => {
+                        recorder.set_format(recorder.format().next())
+                    }
+                    (ActionMessage::Down, EngineMenu::Record) => {
+                        recorder.set_record_midi(!recorder.records_midi())
+                    }
+                    (ActionMessage::Right, EngineMenu::Midi) => {
+                        self.selected_midi_param = self.selected_midi_param.next()
+                    }
+                    (ActionMessage::Left, EngineMenu::Midi) => {
+                        self.selected_midi_param = self.selected_midi_param.prev()
+                    }
+                    // Up and Down go through the connected devices for the selected port
+                    (ActionMessage::Up | ActionMessage::Down, EngineMenu::Midi) => {
+                        let ports = &shared.midi_ports;
+                        let forward = action == ActionMessage::Up;
+                        match self.selected_midi_param {
+                            MidiParam::ClockInput => ports.set_clock_input(next_port(
+                                &input_ports(),
+                                ports.clock_input().as_deref(),
+                                forward,
+                            )),
+                            MidiParam::ClockOutput => ports.set_clock_output(next_port(
+                                &output_ports(),
+                                ports.clock_output().as_deref(),
+                                forward,
+                            )),
+                            MidiParam::Output => ports.set_output(next_port(
+                                &output_ports(),
+                                ports.output().as_deref(),
+                                forward,
+                            )),
+                        }
+                    }
+                    // Sound controllers 5 (brightness) and 2 (timbre) move the cutoff and
+                    // resonance from any page
+                    (
+                        ActionMessage::ControlChange(ControlFunction::SOUND_CONTROLLER_5, value),
+                        _,
+                    ) => {
+                        let octaves = (MAX_CUTOFF / MIN_CUTOFF).log2();
+                        shared.filter.set_cutoff(
+                            MIN_CUTOFF * (f64::from(u8::from(value)) / 127.0 * octaves).exp2(),
+                        );
+                    }
+                    (
+                        ActionMessage::ControlChange(ControlFunction::SOUND_CONTROLLER_2, value),
+                        _,
+                    ) => {
+                        shared
+                            .filter
+                            .resonance
+                            .set_value(f64::from(u8::from(value)) / 127.0);
+                    }
                     _ => (),
                 };
             }
@@ -0,0 +1,255 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crossbeam::queue::SegQueue;
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::RgbColor,
+    prelude::*,
+    text::Text,
+};
+use log::warn;
+
+use super::{Event, Screen};
+use crate::{
+    app::{ActionMessage, State},
+    preset::{PRESETS, PRESET_PREFIX},
+};
+
+const LIST_TOP: i32 = 26;
+const ROW_HEIGHT: i32 = 12;
+/// Presets listed at once, the list scrolls to keep the selected one in view.
+const VISIBLE_ROWS: usize = 15;
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct PresetScreen {
+    pub(crate) selected_field: Field,
+    /// Presets in the presets directory.
+    files: Vec<PathBuf>,
+    selected_file: usize,
+    /// Result of the last load or save.
+    status: Option<String>,
+}
+
+/// What the buttons change, Right carries out the selected action.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Field {
+    /// Up and Down choose a preset and Right loads it.
+    File,
+    /// Saves over the loaded preset, or as a new one if none is loaded.
+    Save,
+    SaveAs,
+    /// Starts again from the default sound.
+    Init,
+    /// Switches between the edited sound and the loaded preset.
+    Compare,
+    /// Throws away changes since the preset was loaded.
+    Revert,
+}
+
+impl Field {
+    fn next(&self) -> Self {
+        use Field::*;
+        match *self {
+            File => Save,
+            Save => SaveAs,
+            SaveAs => Init,
+            Init => Compare,
+            Compare => Revert,
+            Revert => File,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::File => write!(f, "File"),
+            Field::Save => write!(f, "Save"),
+            Field::SaveAs => write!(f, "Save as new"),
+            Field::Init => write!(f, "Init"),
+            Field::Compare => write!(f, "Compare"),
+            Field::Revert => write!(f, "Revert"),
+        }
+    }
+}
+
+impl Default for PresetScreen {
+    fn default() -> Self {
+        Self {
+            selected_field: Field::File,
+            files: Vec::new(),
+            selected_file: 0,
+            status: None,
+        }
+    }
+}
+
+/// Returns the name a preset is listed under.
+fn name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+impl PresetScreen {
+    fn refresh_files(&mut self) {
+        self.files = PRESETS.list();
+        self.selected_file = self.selected_file.min(self.files.len().saturating_sub(1));
+    }
+
+    fn load(&mut self, state: &State) {
+        let Some(path) = self.files.get(self.selected_file) else {
+            return;
+        };
+        match state.preset.load(path, state) {
+            Ok(()) => self.status = Some(format!("Loaded {}", name(path))),
+            Err(e) => {
+                warn!("Failed to load preset: {:#}", e);
+                self.status = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    fn save(&mut self, state: &State, path: PathBuf) {
+        match state.preset.save(&path, state) {
+            Ok(()) => {
+                self.status = Some(format!("Saved {}", name(&path)));
+                self.refresh_files();
+                if let Some(index) = self.files.iter().position(|file| *file == path) {
+                    self.selected_file = index;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to save preset: {:#}", e);
+                self.status = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    fn handle(&mut self, state: &State, action: ActionMessage) {
+        use ActionMessage::*;
+        match (self.selected_field, action) {
+            (Field::File, Up) => self.selected_file = self.selected_file.saturating_sub(1),
+            (Field::File, Down) => {
+                self.selected_file =
+                    (self.selected_file + 1).min(self.files.len().saturating_sub(1))
+            }
+            (Field::File, Right) => self.load(state),
+            (Field::Save, Right) => {
+                let path = state
+                    .preset
+                    .path()
+                    .unwrap_or_else(|| PRESETS.next_path(PRESET_PREFIX));
+                self.save(state, path)
+            }
+            (Field::SaveAs, Right) => self.save(state, PRESETS.next_path(PRESET_PREFIX)),
+            (Field::Init, Right) => {
+                state.preset.init(state);
+                self.status = Some("Started from the default sound".to_string());
+            }
+            (Field::Compare, Right) => state.preset.compare(state),
+            (Field::Revert, Right) => {
+                state.preset.revert(state);
+                self.status = None;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Screen for PresetScreen {
+    fn entry(&mut self) {
+        self.refresh_files();
+    }
+
+    fn exit(&mut self) {}
+
+    fn draw<D>(&self, target: &mut D, state: &State) -> Result<(), Infallible>
+    where
+        D: DrawTarget,
+        D::Color: RgbColor,
+    {
+        let _ = target.clear(D::Color::BLACK);
+        let style = MonoTextStyle::new(&FONT_6X10, D::Color::BLUE);
+        let selected_style = MonoTextStyle::new(&FONT_6X10, D::Color::YELLOW);
+
+        let loaded = state.preset.path();
+        let header = match &loaded {
+            Some(path) if state.preset.is_comparing() => format!("{}  Comparing", name(path)),
+            Some(path) if state.preset.is_modified(state) => format!("{}  Edited", name(path)),
+            Some(path) => name(path),
+            None => "No preset loaded".to_string(),
+        };
+        let _ = Text::new(&header, Point::new(4, 10), style).draw(target);
+
+        if self.files.is_empty() {
+            let _ = Text::new(
+                &format!("No presets in {}", PRESETS.path().display()),
+                Point::new(4, LIST_TOP),
+                style,
+            )
+            .draw(target);
+        }
+        let first = self
+            .selected_file
+            .saturating_sub(VISIBLE_ROWS / 2)
+            .min(self.files.len().saturating_sub(VISIBLE_ROWS));
+        for (row, (index, path)) in self
+            .files
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(VISIBLE_ROWS)
+            .enumerate()
+        {
+            let marker = if loaded.as_ref() == Some(path) {
+                "*"
+            } else {
+                " "
+            };
+            let _ = Text::new(
+                &format!("{} {}", marker, name(path)),
+                Point::new(4, LIST_TOP + row as i32 * ROW_HEIGHT),
+                if index == self.selected_file {
+                    selected_style
+                } else {
+                    style
+                },
+            )
+            .draw(target);
+        }
+
+        if let Some(status) = &self.status {
+            let _ = Text::new(status, Point::new(4, 222), style).draw(target);
+        }
+        let text = Text::new(
+            &format!("{}", self.selected_field),
+            Point::new(4, 234),
+            selected_style,
+        )
+        .draw(target);
+
+        match text {
+            Ok(_) => {}
+            Err(_) => panic!("Error drawing text"),
+        };
+        Ok(())
+    }
+
+    fn update(&mut self, state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+        while let Some(action) = actions.pop() {
+            match action {
+                ActionMessage::X => return Some(Event::OpenModeMenu),
+                ActionMessage::Y => self.selected_field = self.selected_field.next(),
+                action => self.handle(state, action),
+            }
+        }
+        None
+    }
+}
@@ -1,8 +1,4 @@
-use std::{
-    convert::Infallible,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{convert::Infallible, sync::Arc, time::Instant};
 
 use crossbeam::queue::SegQueue;
 use embedded_graphics::{
@@ -14,22 +10,16 @@ use embedded_graphics::{
     text::{Alignment, Text},
 };
 
-use crate::app::{ActionMessage, State};
 use super::{Event, Screen};
+use crate::app::{ActionMessage, State};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub(crate) struct StartupScreen {
     pub(crate) time_entry: Option<Instant>,
 }
 
 const DURATION: f64 = 1.0;
 
-impl Default for StartupScreen {
-    fn default() -> Self {
-        Self { time_entry: None }
-    }
-}
-
 impl Screen for StartupScreen {
     fn entry(&mut self) {
         self.time_entry = Some(Instant::now());
@@ -39,7 +29,7 @@ impl Screen for StartupScreen {
         self.time_entry = None;
     }
 
-    fn draw<D>(&self, target: &mut D, state: &State) -> Result<(), Infallible>
+    fn draw<D>(&self, target: &mut D, _state: &State) -> Result<(), Infallible>
     where
         D: DrawTarget,
         D::Color: RgbColor,
@@ -47,9 +37,9 @@ impl Screen for StartupScreen {
         // Create a new character style
         let style = MonoTextStyle::new(&FONT_6X10, D::Color::WHITE);
 
-        target.clear(D::Color::BLACK);
+        let _ = target.clear(D::Color::BLACK);
 
-        if let Some(_) = self.time_entry {
+        if self.time_entry.is_some() {
             let text = Text::with_alignment(
                 "booting...",
                 Point::new(320 / 2, 160 / 2),
@@ -65,7 +55,7 @@ impl Screen for StartupScreen {
         Ok(())
     }
 
-    fn update(&mut self, state: &State, _: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+    fn update(&mut self, _state: &State, _: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
         if let Some(time) = self.time_entry {
             if (Instant::now() - time).as_secs_f64() > DURATION {
                 return Some(Event::Initialized);
@@ -0,0 +1,383 @@
+use std::{convert::Infallible, fmt, sync::Arc, time::Instant};
+
+use crossbeam::queue::SegQueue;
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::RgbColor,
+    prelude::*,
+    primitives::{Line, Polyline, PrimitiveStyle, Rectangle},
+    text::{Alignment, Text},
+};
+
+use super::{Event, Screen};
+use crate::{
+    app::{ActionMessage, State},
+    spectrum::{Spectrum, FFT_SIZE, MIN_DB, NUM_BANDS},
+};
+
+/// Milliseconds per horizontal division, Up and Down step through them.
+const TIMES_PER_DIV: [f64; 7] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0];
+/// Vertical gains, Left and Right step through them.
+const ZOOMS: [f64; 5] = [1.0, 2.0, 4.0, 8.0, 16.0];
+const DIVS_ACROSS: i32 = 10;
+const DIVS_DOWN: i32 = 8;
+const SCOPE_TOP: i32 = 20;
+const DIV_HEIGHT: i32 = 25;
+/// Seconds the spectrum takes to fall back, Up and Down step through them.
+const SMOOTHINGS: [f32; 5] = [0.0, 0.1, 0.25, 0.5, 1.0];
+const SPECTRUM_HEIGHT: i32 = 180;
+/// Decibels between the spectrum's grid lines.
+const DB_PER_LINE: f32 = 15.0;
+/// Frequencies marked along the bottom of the spectrum.
+const FREQUENCY_MARKS: [(f32, &str); 3] = [(100.0, "100"), (1000.0, "1k"), (10_000.0, "10k")];
+
+/// What the screen shows, Y switches between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum View {
+    Oscilloscope,
+    Spectrum,
+}
+
+impl View {
+    fn next(&self) -> Self {
+        match *self {
+            View::Oscilloscope => View::Spectrum,
+            View::Spectrum => View::Oscilloscope,
+        }
+    }
+}
+
+impl fmt::Display for View {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            View::Oscilloscope => write!(f, "Oscilloscope"),
+            View::Spectrum => write!(f, "Spectrum"),
+        }
+    }
+}
+
+/// How the spectrum is drawn, Left and Right switch between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SpectrumStyle {
+    Bars,
+    Line,
+}
+
+impl SpectrumStyle {
+    fn next(&self) -> Self {
+        match *self {
+            SpectrumStyle::Bars => SpectrumStyle::Line,
+            SpectrumStyle::Line => SpectrumStyle::Bars,
+        }
+    }
+}
+
+impl fmt::Display for SpectrumStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpectrumStyle::Bars => write!(f, "Bars"),
+            SpectrumStyle::Line => write!(f, "Line"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct VisualiserScreen {
+    pub(crate) view: View,
+    /// Index into `TIMES_PER_DIV`
+    pub(crate) time_per_div: usize,
+    /// Index into `ZOOMS`
+    pub(crate) zoom: usize,
+    /// Index into `SMOOTHINGS`
+    pub(crate) smoothing: usize,
+    pub(crate) spectrum_style: SpectrumStyle,
+    spectrum: Spectrum,
+    /// When the spectrum was last updated, so it falls at the same speed whatever the frame rate.
+    analysed: Option<Instant>,
+}
+
+impl Default for VisualiserScreen {
+    fn default() -> Self {
+        Self {
+            view: View::Oscilloscope,
+            time_per_div: 2,
+            zoom: 0,
+            smoothing: 2,
+            spectrum_style: SpectrumStyle::Bars,
+            spectrum: Spectrum::default(),
+            analysed: None,
+        }
+    }
+}
+
+/// Returns where the last rising zero crossing is that still leaves `window` samples after it,
+/// so the waveform starts at the same point of its cycle every frame and stands still.
+fn trigger(samples: &[f32], window: usize) -> Option<usize> {
+    let last = samples.len().checked_sub(window)?;
+    (1..=last)
+        .rev()
+        .find(|&index| samples[index - 1] < 0.0 && samples[index] >= 0.0)
+}
+
+impl VisualiserScreen {
+    fn handle(&mut self, action: ActionMessage) {
+        use ActionMessage::*;
+        match (self.view, action) {
+            (View::Oscilloscope, Up) => {
+                self.time_per_div = (self.time_per_div + 1).min(TIMES_PER_DIV.len() - 1)
+            }
+            (View::Oscilloscope, Down) => self.time_per_div = self.time_per_div.saturating_sub(1),
+            (View::Oscilloscope, Right) => self.zoom = (self.zoom + 1).min(ZOOMS.len() - 1),
+            (View::Oscilloscope, Left) => self.zoom = self.zoom.saturating_sub(1),
+            (View::Spectrum, Up) => self.smoothing = (self.smoothing + 1).min(SMOOTHINGS.len() - 1),
+            (View::Spectrum, Down) => self.smoothing = self.smoothing.saturating_sub(1),
+            (View::Spectrum, Left | Right) => self.spectrum_style = self.spectrum_style.next(),
+            _ => (),
+        }
+    }
+
+    /// Runs the analyser over the latest output, only while the spectrum is on screen.
+    fn analyse(&mut self, state: &State) {
+        if self.view != View::Spectrum {
+            self.analysed = None;
+            return;
+        }
+        let now = Instant::now();
+        let elapsed = self
+            .analysed
+            .map_or(0.0, |analysed| (now - analysed).as_secs_f32());
+        self.analysed = Some(now);
+
+        let samples = state.tap.latest(FFT_SIZE);
+        self.spectrum.update(
+            &samples,
+            state.tap.sample_rate(),
+            elapsed,
+            SMOOTHINGS[self.smoothing],
+        );
+    }
+
+    fn draw_scope<D>(&self, target: &mut D, state: &State) -> bool
+    where
+        D: DrawTarget,
+        D::Color: RgbColor,
+    {
+        let width = target.bounding_box().size.width.max(1) as i32;
+        let div_width = width / DIVS_ACROSS;
+        let height = DIV_HEIGHT * DIVS_DOWN;
+        let center = SCOPE_TOP + height / 2;
+
+        let grid = PrimitiveStyle::with_stroke(D::Color::BLUE, 1);
+        for div in 0..=DIVS_ACROSS {
+            let x = (div * div_width).min(width - 1);
+            let _ = Line::new(Point::new(x, SCOPE_TOP), Point::new(x, SCOPE_TOP + height))
+                .into_styled(grid)
+                .draw(target);
+        }
+        for div in 0..=DIVS_DOWN {
+            let y = SCOPE_TOP + div * DIV_HEIGHT;
+            let _ = Line::new(Point::new(0, y), Point::new(width - 1, y))
+                .into_styled(grid)
+                .draw(target);
+        }
+
+        // Read two screens' worth so there is a whole window left after any crossing found
+        let sample_rate = f64::from(state.tap.sample_rate());
+        let window = ((TIMES_PER_DIV[self.time_per_div] / 1000.0
+            * f64::from(DIVS_ACROSS)
+            * sample_rate) as usize)
+            .max(1);
+        let samples = state.tap.latest(window * 2);
+        if samples.len() < window {
+            return false;
+        }
+        let triggered = trigger(&samples, window);
+        let start = triggered.unwrap_or(samples.len() - window);
+
+        let gain = ZOOMS[self.zoom] * f64::from(height / 2);
+        let points: Vec<Point> = (0..width)
+            .map(|x| {
+                let sample = samples[start + x as usize * window / width as usize];
+                let y = center - (f64::from(sample) * gain) as i32;
+                Point::new(x, y.clamp(SCOPE_TOP, SCOPE_TOP + height))
+            })
+            .collect();
+        let _ = Polyline::new(&points)
+            .into_styled(PrimitiveStyle::with_stroke(D::Color::GREEN, 1))
+            .draw(target);
+
+        triggered.is_some()
+    }
+
+    fn draw_spectrum<D>(&self, target: &mut D)
+    where
+        D: DrawTarget,
+        D::Color: RgbColor,
+    {
+        let width = target.bounding_box().size.width.max(1) as i32;
+        let bottom = SCOPE_TOP + SPECTRUM_HEIGHT;
+        let y = |level: f32| {
+            bottom
+                - ((level - MIN_DB) / -MIN_DB * SPECTRUM_HEIGHT as f32)
+                    .clamp(0.0, SPECTRUM_HEIGHT as f32) as i32
+        };
+        let band_width = width / NUM_BANDS as i32;
+        // Where `frequency` Hz sits across the bands
+        let x = |frequency: f32| {
+            (self.spectrum.position_of(frequency) * (band_width * NUM_BANDS as i32) as f32) as i32
+        };
+
+        let grid = PrimitiveStyle::with_stroke(D::Color::BLUE, 1);
+        let label_style = MonoTextStyle::new(&FONT_6X10, D::Color::BLUE);
+        let mut level = 0.0;
+        while level >= MIN_DB {
+            let _ = Line::new(Point::new(0, y(level)), Point::new(width - 1, y(level)))
+                .into_styled(grid)
+                .draw(target);
+            level -= DB_PER_LINE;
+        }
+        for (frequency, label) in FREQUENCY_MARKS {
+            let _ = Line::new(
+                Point::new(x(frequency), SCOPE_TOP),
+                Point::new(x(frequency), bottom),
+            )
+            .into_styled(grid)
+            .draw(target);
+            let _ = Text::with_alignment(
+                label,
+                Point::new(x(frequency), bottom + 12),
+                label_style,
+                Alignment::Center,
+            )
+            .draw(target);
+        }
+
+        let levels = self.spectrum.levels();
+        match self.spectrum_style {
+            SpectrumStyle::Bars => {
+                for (band, &level) in levels.iter().enumerate() {
+                    let top = y(level);
+                    let _ = Rectangle::new(
+                        Point::new(band as i32 * band_width, top),
+                        Size::new((band_width - 1).max(1) as u32, (bottom - top) as u32),
+                    )
+                    .into_styled(PrimitiveStyle::with_fill(D::Color::GREEN))
+                    .draw(target);
+                }
+            }
+            SpectrumStyle::Line => {
+                let points: Vec<Point> = levels
+                    .iter()
+                    .enumerate()
+                    .map(|(band, &level)| {
+                        Point::new(band as i32 * band_width + band_width / 2, y(level))
+                    })
+                    .collect();
+                let _ = Polyline::new(&points)
+                    .into_styled(PrimitiveStyle::with_stroke(D::Color::GREEN, 1))
+                    .draw(target);
+            }
+        }
+
+        for (band, &peak) in self.spectrum.peaks().iter().enumerate() {
+            if peak <= MIN_DB {
+                continue;
+            }
+            let left = band as i32 * band_width;
+            let _ = Line::new(
+                Point::new(left, y(peak)),
+                Point::new(left + band_width - 2, y(peak)),
+            )
+            .into_styled(PrimitiveStyle::with_stroke(D::Color::RED, 1))
+            .draw(target);
+        }
+    }
+}
+
+impl Screen for VisualiserScreen {
+    fn entry(&mut self) {}
+
+    fn exit(&mut self) {}
+
+    fn draw<D>(&self, target: &mut D, state: &State) -> Result<(), Infallible>
+    where
+        D: DrawTarget,
+        D::Color: RgbColor,
+    {
+        let _ = target.clear(D::Color::BLACK);
+        let style = MonoTextStyle::new(&FONT_6X10, D::Color::BLUE);
+        let selected_style = MonoTextStyle::new(&FONT_6X10, D::Color::YELLOW);
+
+        let _ =
+            Text::new(&format!("{}", self.view), Point::new(4, 10), selected_style).draw(target);
+        let settings = match self.view {
+            View::Oscilloscope => {
+                let triggered = self.draw_scope(target, state);
+                let _ = Text::with_alignment(
+                    if triggered { "Trig'd" } else { "Auto" },
+                    Point::new(316, 10),
+                    style,
+                    Alignment::Right,
+                )
+                .draw(target);
+                format!(
+                    "{} ms/div  x{}",
+                    TIMES_PER_DIV[self.time_per_div], ZOOMS[self.zoom]
+                )
+            }
+            View::Spectrum => {
+                self.draw_spectrum(target);
+                let _ = Text::with_alignment(
+                    &format!("{} dB/div", DB_PER_LINE),
+                    Point::new(316, 10),
+                    style,
+                    Alignment::Right,
+                )
+                .draw(target);
+                format!(
+                    "{}  Smoothing {} ms",
+                    self.spectrum_style,
+                    SMOOTHINGS[self.smoothing] * 1000.0
+                )
+            }
+        };
+        let text = Text::new(&settings, Point::new(4, 234), style).draw(target);
+
+        match text {
+            Ok(_) => {}
+            Err(_) => panic!("Error drawing text"),
+        };
+        Ok(())
+    }
+
+    fn update(&mut self, state: &State, actions: Arc<SegQueue<ActionMessage>>) -> Option<Event> {
+        while let Some(action) = actions.pop() {
+            match action {
+                ActionMessage::X => return Some(Event::OpenModeMenu),
+                ActionMessage::Y => self.view = self.view.next(),
+                action => self.handle(action),
+            }
+        }
+        self.analyse(state);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trigger_finds_the_last_rising_crossing_with_room_after_it() {
+        let samples = [-1.0, 0.5, 1.0, -0.5, 0.0, 0.5, -1.0, 1.0, 0.5];
+        // The crossing at 7 would leave too few samples, so the one at 4 is used
+        assert_eq!(trigger(&samples, 3), Some(4));
+        assert_eq!(trigger(&samples, 2), Some(7));
+        // Falling crossings don't count
+        assert_eq!(trigger(&samples, 6), Some(1));
+        assert_eq!(trigger(&samples, 9), None);
+        assert_eq!(trigger(&samples, 20), None);
+        assert_eq!(trigger(&[0.5, 1.0, 0.5, 0.2], 1), None);
+    }
+}
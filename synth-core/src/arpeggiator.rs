@@ -0,0 +1,442 @@
+use core::time::Duration;
+
+use crate::key_code::{KeyCode, NUM_KEYS};
+use crate::random::{self, xorshift};
+use crate::state::SentNote;
+
+/// Most octaves a pattern can span.
+pub const MAX_OCTAVES: u8 = 4;
+/// Gate lengths that can be stepped through, as a percentage of a step.
+pub const GATES: [u8; 4] = [25, 50, 75, 100];
+pub const DEFAULT_TEMPO: u16 = 120;
+pub const MIN_TEMPO: u16 = 20;
+pub const MAX_TEMPO: u16 = 300;
+/// Steps are sixteenth notes.
+const STEPS_PER_BEAT: u32 = 4;
+/// MIDI clock pulses in a step, at 24 pulses per quarter note.
+const PULSES_PER_STEP: u8 = 6;
+
+/// Order the held notes are played in.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pattern {
+    #[default]
+    Up,
+    Down,
+    /// Up then back down, without repeating the top and bottom notes.
+    UpDown,
+    Random,
+    /// The order the keys were pressed in.
+    AsPlayed,
+}
+
+impl Pattern {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Up => Self::Down,
+            Self::Down => Self::UpDown,
+            Self::UpDown => Self::Random,
+            Self::Random => Self::AsPlayed,
+            Self::AsPlayed => Self::Up,
+        }
+    }
+}
+
+/// What times the steps.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockSource {
+    /// Steps at `tempo`.
+    #[default]
+    Internal,
+    /// Steps follow MIDI clock from the host.
+    Midi,
+}
+
+impl ClockSource {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Internal => Self::Midi,
+            Self::Midi => Self::Internal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ArpEvent {
+    /// A note to play and its velocity.
+    NoteOn(SentNote, u8),
+    NoteOff(SentNote),
+}
+
+/// Plays the held notes one at a time.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Arpeggiator {
+    pub enabled: bool,
+    pub pattern: Pattern,
+    /// How many octaves the held notes are repeated over, 1 to `MAX_OCTAVES`.
+    pub octaves: u8,
+    /// How long each note is held, as a percentage of a step.
+    pub gate: u8,
+    /// Beats per minute of the internal clock.
+    pub tempo: u16,
+    pub clock: ClockSource,
+    /// Held keys in the order they were pressed, with their note and velocity.
+    held: [(KeyCode, u8, u8); NUM_KEYS],
+    len: usize,
+    /// Steps played since the first key was pressed.
+    step: usize,
+    /// Xorshift state for the random pattern.
+    random: u32,
+    playing: Option<SentNote>,
+    note_off_at: Duration,
+    /// When the internal clock plays the next step, `None` plays it straight away.
+    next_step: Option<Duration>,
+    /// MIDI clock pulses since the last step.
+    pulses: u8,
+    /// Whether the MIDI clock is running, stopped by a MIDI Stop.
+    running: bool,
+    /// Whether a MIDI clock step is waiting to be played.
+    step_due: bool,
+    last_pulse_step: Option<Duration>,
+    /// Length of a step measured from the MIDI clock, used for the gate.
+    midi_step_length: Duration,
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pattern: Pattern::default(),
+            octaves: 1,
+            gate: GATES[1],
+            tempo: DEFAULT_TEMPO,
+            clock: ClockSource::default(),
+            held: [(KeyCode::SHIFT, 0, 0); NUM_KEYS],
+            len: 0,
+            step: 0,
+            random: random::SEED,
+            playing: None,
+            note_off_at: Duration::ZERO,
+            next_step: None,
+            pulses: 0,
+            running: true,
+            step_due: false,
+            last_pulse_step: None,
+            midi_step_length: step_length(DEFAULT_TEMPO),
+        }
+    }
+}
+
+impl Arpeggiator {
+    /// Adds a held key to the pattern.
+    pub fn press(&mut self, key: KeyCode, note: u8, velocity: u8) {
+        if self.len >= NUM_KEYS || self.held().iter().any(|(held, ..)| *held == key) {
+            return;
+        }
+        self.held[self.len] = (key, note, velocity);
+        self.len += 1;
+    }
+
+    /// Removes a key from the pattern, once every key is released the pattern starts again from
+    /// the beginning.
+    pub fn release(&mut self, key: KeyCode) {
+        let Some(index) = self.held().iter().position(|(held, ..)| *held == key) else {
+            return;
+        };
+        self.held.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        if self.len == 0 {
+            self.step = 0;
+            self.next_step = None;
+        }
+    }
+
+    /// Forgets every held key, returns the note that is playing so it can be turned off.
+    pub fn clear(&mut self) -> Option<SentNote> {
+        self.len = 0;
+        self.step = 0;
+        self.next_step = None;
+        self.playing.take()
+    }
+
+    pub fn next_octaves(&mut self) {
+        self.octaves = self.octaves % MAX_OCTAVES + 1;
+    }
+
+    pub fn next_gate(&mut self) {
+        let index = GATES
+            .iter()
+            .position(|gate| *gate == self.gate)
+            .unwrap_or(0);
+        self.gate = GATES[(index + 1) % GATES.len()];
+    }
+
+    pub fn set_tempo(&mut self, tempo: u16) {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+    }
+
+    /// Counts a MIDI Timing Clock received at `now`.
+    pub fn clock_pulse(&mut self, now: Duration) {
+        if !self.running {
+            return;
+        }
+        if self.pulses == 0 {
+            if let Some(last) = self.last_pulse_step {
+                self.midi_step_length = now.saturating_sub(last);
+            }
+            self.last_pulse_step = Some(now);
+            self.step_due = true;
+        }
+        self.pulses = (self.pulses + 1) % PULSES_PER_STEP;
+    }
+
+    /// Handles MIDI Start, the next clock pulse plays the start of the pattern.
+    pub fn start(&mut self) {
+        self.step = 0;
+        self.pulses = 0;
+        self.last_pulse_step = None;
+        self.running = true;
+    }
+
+    /// Handles MIDI Continue, steps carry on from where they stopped.
+    pub fn resume(&mut self) {
+        self.running = true;
+    }
+
+    /// Handles MIDI Stop.
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.step_due = false;
+    }
+
+    /// Returns the next note on or note off due at `now`, call until it returns `None`. New
+    /// notes are sent on `channel`.
+    pub fn poll(&mut self, now: Duration, channel: u8) -> Option<ArpEvent> {
+        if let Some(note) = self.playing {
+            if now >= self.note_off_at {
+                self.playing = None;
+                return Some(ArpEvent::NoteOff(note));
+            }
+        }
+        if !self.enabled || self.len == 0 {
+            self.step_due = false;
+            return None;
+        }
+
+        let due = match self.clock {
+            ClockSource::Internal => self.next_step.is_none_or(|next| now >= next),
+            ClockSource::Midi => self.step_due,
+        };
+        if !due {
+            return None;
+        }
+        // A gate of 100% still needs a note off before the next note on, the step stays due
+        if let Some(note) = self.playing.take() {
+            return Some(ArpEvent::NoteOff(note));
+        }
+
+        let length = match self.clock {
+            ClockSource::Internal => {
+                let length = step_length(self.tempo);
+                // Steps keep to the beat unless they fell more than a step behind
+                let start = self
+                    .next_step
+                    .filter(|next| now.saturating_sub(*next) < length)
+                    .unwrap_or(now);
+                self.next_step = Some(start + length);
+                length
+            }
+            ClockSource::Midi => {
+                self.step_due = false;
+                self.midi_step_length
+            }
+        };
+
+        let (note, velocity) = self.next_note();
+        let sent = SentNote { channel, note };
+        self.playing = Some(sent);
+        self.note_off_at = now + length * u32::from(self.gate) / 100;
+        Some(ArpEvent::NoteOn(sent, velocity))
+    }
+
+    fn held(&self) -> &[(KeyCode, u8, u8)] {
+        &self.held[..self.len]
+    }
+
+    /// Picks the note for the current step and moves on to the next one.
+    fn next_note(&mut self) -> (u8, u8) {
+        let count = self.len * usize::from(self.octaves.max(1));
+        let step = self.step;
+        self.step = self.step.wrapping_add(1);
+        let index = match self.pattern {
+            Pattern::Up | Pattern::AsPlayed => step % count,
+            Pattern::Down => count - 1 - step % count,
+            Pattern::UpDown => {
+                let period = (2 * count).saturating_sub(2).max(1);
+                let position = step % period;
+                if position < count {
+                    position
+                } else {
+                    period - position
+                }
+            }
+            Pattern::Random => xorshift(&mut self.random) as usize % count,
+        };
+
+        let octave = (index / self.len) as u8;
+        let mut notes = [(0, 0); NUM_KEYS];
+        for (note, (_, held, velocity)) in notes.iter_mut().zip(self.held()) {
+            *note = (*held, *velocity);
+        }
+        let notes = &mut notes[..self.len];
+        if self.pattern != Pattern::AsPlayed {
+            notes.sort_unstable();
+        }
+        let (note, velocity) = notes[index % self.len];
+        (note.saturating_add(octave * 12).min(127), velocity)
+    }
+}
+
+/// Length of a sixteenth note at `tempo`.
+fn step_length(tempo: u16) -> Duration {
+    Duration::from_secs(60) / (u32::from(tempo.max(1)) * STEPS_PER_BEAT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the arpeggiator on its internal clock, returning the notes played in `steps` steps.
+    fn play(arpeggiator: &mut Arpeggiator, steps: u32) -> Vec<u8> {
+        let length = step_length(arpeggiator.tempo);
+        let mut notes = Vec::new();
+        for i in 0..steps * 4 {
+            while let Some(event) = arpeggiator.poll(length / 4 * i, 0) {
+                if let ArpEvent::NoteOn(sent, _) = event {
+                    notes.push(sent.note);
+                }
+            }
+        }
+        notes
+    }
+
+    fn arpeggiator(pattern: Pattern, octaves: u8) -> Arpeggiator {
+        let mut arpeggiator = Arpeggiator {
+            enabled: true,
+            pattern,
+            octaves,
+            ..Default::default()
+        };
+        arpeggiator.press(KeyCode::E1, 64, 100);
+        arpeggiator.press(KeyCode::C1, 60, 100);
+        arpeggiator.press(KeyCode::G1, 67, 100);
+        arpeggiator
+    }
+
+    #[test]
+    fn patterns() {
+        let notes = |pattern| play(&mut arpeggiator(pattern, 1), 6);
+        assert_eq!(notes(Pattern::Up), [60, 64, 67, 60, 64, 67]);
+        assert_eq!(notes(Pattern::Down), [67, 64, 60, 67, 64, 60]);
+        assert_eq!(notes(Pattern::UpDown), [60, 64, 67, 64, 60, 64]);
+        assert_eq!(notes(Pattern::AsPlayed), [64, 60, 67, 64, 60, 67]);
+        let random = notes(Pattern::Random);
+        assert!(random.iter().all(|note| [60, 64, 67].contains(note)));
+    }
+
+    #[test]
+    fn octave_range() {
+        let notes = play(&mut arpeggiator(Pattern::Up, 2), 6);
+        assert_eq!(notes, [60, 64, 67, 72, 76, 79]);
+        let notes = play(&mut arpeggiator(Pattern::Down, 2), 3);
+        assert_eq!(notes, [79, 76, 72]);
+    }
+
+    #[test]
+    fn released_keys_leave_the_pattern() {
+        let mut arpeggiator = arpeggiator(Pattern::Up, 1);
+        arpeggiator.release(KeyCode::C1);
+        assert_eq!(play(&mut arpeggiator, 4), [64, 67, 64, 67]);
+        arpeggiator.release(KeyCode::E1);
+        arpeggiator.release(KeyCode::G1);
+        assert!(play(&mut arpeggiator, 4).is_empty());
+    }
+
+    #[test]
+    fn gate_sets_note_length() {
+        let mut arpeggiator = arpeggiator(Pattern::Up, 1);
+        arpeggiator.gate = 25;
+        let length = step_length(arpeggiator.tempo);
+        assert!(matches!(
+            arpeggiator.poll(Duration::ZERO, 0),
+            Some(ArpEvent::NoteOn(..))
+        ));
+        assert_eq!(arpeggiator.poll(length / 5, 0), None);
+        assert!(matches!(
+            arpeggiator.poll(length / 4, 0),
+            Some(ArpEvent::NoteOff(..))
+        ));
+    }
+
+    #[test]
+    fn full_gate_turns_the_last_note_off_first() {
+        let mut arpeggiator = arpeggiator(Pattern::Up, 1);
+        arpeggiator.gate = 100;
+        let length = step_length(arpeggiator.tempo);
+        let events: Vec<_> = [Duration::ZERO, length, length]
+            .into_iter()
+            .filter_map(|now| arpeggiator.poll(now, 0))
+            .collect();
+        let c = SentNote {
+            channel: 0,
+            note: 60,
+        };
+        let e = SentNote {
+            channel: 0,
+            note: 64,
+        };
+        assert_eq!(
+            events,
+            [
+                ArpEvent::NoteOn(c, 100),
+                ArpEvent::NoteOff(c),
+                ArpEvent::NoteOn(e, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_midi_clock() {
+        let mut arpeggiator = arpeggiator(Pattern::Up, 1);
+        arpeggiator.clock = ClockSource::Midi;
+        arpeggiator.start();
+        let pulse = Duration::from_millis(20);
+        let mut notes = Vec::new();
+        for i in 0..24 {
+            arpeggiator.clock_pulse(pulse * i);
+            while let Some(event) = arpeggiator.poll(pulse * i, 0) {
+                if let ArpEvent::NoteOn(sent, _) = event {
+                    notes.push((i, sent.note));
+                }
+            }
+        }
+        assert_eq!(notes, [(0, 60), (6, 64), (12, 67), (18, 60)]);
+
+        arpeggiator.stop();
+        arpeggiator.clock_pulse(pulse * 24);
+        assert!(!matches!(
+            arpeggiator.poll(pulse * 24, 0),
+            Some(ArpEvent::NoteOn(..))
+        ));
+    }
+
+    #[test]
+    fn disabled_arpeggiator_is_silent() {
+        let mut arpeggiator = arpeggiator(Pattern::Up, 1);
+        arpeggiator.enabled = false;
+        assert!(play(&mut arpeggiator, 4).is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::key_code::NUM_KEYS;
+
+/// Normalised travel of a fully pressed key.
+pub const FULL_TRAVEL: u16 = 1000;
+/// Smallest difference between rest and bottom for a key's calibration to be accepted.
+pub const MIN_RANGE: u16 = 100;
+
+const DEFAULT_REST: u16 = 2000;
+const DEFAULT_BOTTOM: u16 = 1000;
+
+const MAGIC: [u8; 4] = *b"CALB";
+const VERSION: u8 = 3;
+/// Size of the calibration when stored, magic + version + rest and bottom for each key + checksum.
+pub const SERIALIZED_SIZE: usize = MAGIC.len() + 1 + NUM_KEYS * 4 + 1;
+
+/// ADC readings of each key at rest and when fully pressed. Readings drop as a key is pressed.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Calibration {
+    pub rest: [u16; NUM_KEYS],
+    pub bottom: [u16; NUM_KEYS],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            rest: [DEFAULT_REST; NUM_KEYS],
+            bottom: [DEFAULT_BOTTOM; NUM_KEYS],
+        }
+    }
+}
+
+impl Calibration {
+    /// Returns how far a key is pressed, from 0 at rest to `FULL_TRAVEL` at the bottom.
+    pub fn travel(&self, index: usize, position: u16) -> u16 {
+        let rest = self.rest[index];
+        let range = rest.saturating_sub(self.bottom[index]).max(1);
+        let travel = rest.saturating_sub(position).min(range);
+        (u32::from(travel) * u32::from(FULL_TRAVEL) / u32::from(range)) as u16
+    }
+
+    /// Returns true if a key has been pressed far enough from rest to be calibrated.
+    pub fn is_valid(&self, index: usize) -> bool {
+        self.rest[index].saturating_sub(self.bottom[index]) >= MIN_RANGE
+    }
+
+    pub fn to_bytes(&self) -> [u8; SERIALIZED_SIZE] {
+        let mut bytes = [0; SERIALIZED_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        for i in 0..NUM_KEYS {
+            let offset = 5 + i * 4;
+            bytes[offset..offset + 2].copy_from_slice(&self.rest[i].to_le_bytes());
+            bytes[offset + 2..offset + 4].copy_from_slice(&self.bottom[i].to_le_bytes());
+        }
+        bytes[SERIALIZED_SIZE - 1] = checksum(&bytes[..SERIALIZED_SIZE - 1]);
+        bytes
+    }
+
+    /// Reads a calibration written by `to_bytes`, returns `None` if the bytes are erased flash, from
+    /// a different version or corrupt.
+    pub fn from_bytes(bytes: &[u8; SERIALIZED_SIZE]) -> Option<Self> {
+        if bytes[..4] != MAGIC
+            || bytes[4] != VERSION
+            || bytes[SERIALIZED_SIZE - 1] != checksum(&bytes[..SERIALIZED_SIZE - 1])
+        {
+            return None;
+        }
+
+        let mut calibration = Self::default();
+        for i in 0..NUM_KEYS {
+            let offset = 5 + i * 4;
+            calibration.rest[i] = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            calibration.bottom[i] = u16::from_le_bytes([bytes[offset + 2], bytes[offset + 3]]);
+        }
+        Some(calibration)
+    }
+}
+
+/// Sum of the bytes, used to check settings read back from flash aren't corrupt.
+pub(crate) fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
@@ -0,0 +1,204 @@
+use crate::scanner::{Adc, Multiplexer};
+
+/// Largest reading from the 12 bit ADC.
+pub const ADC_MAX: u16 = 4095;
+/// Pitch bend value with the stick at rest.
+pub const PITCH_BEND_CENTER: u16 = 8192;
+pub const PITCH_BEND_MAX: u16 = 16383;
+pub const CC_MAX: u16 = 127;
+/// Readings either side of the centre that still count as centred, so a spring that doesn't
+/// return exactly to the middle doesn't leave the pitch bent.
+pub const DEADZONE: u16 = 80;
+/// Readings this close to either end count as fully off or fully on, so pots that don't quite
+/// reach the rails still reach both ends.
+pub const END_MARGIN: u16 = 40;
+/// How far the smoothed reading has to move from the last one sent before a new value is sent,
+/// this stops noise flickering between two values.
+pub const THRESHOLD: u16 = 12;
+/// Each reading moves the smoothed reading 1/2^SMOOTHING of the way towards it.
+const SMOOTHING: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ControllerKind {
+    /// A spring loaded joystick or wheel that rests in the middle, sent as pitch bend.
+    PitchBend,
+    /// Sent as CC 1.
+    ModWheel,
+    /// A pedal plugged into the expression jack, sent as CC 11.
+    Expression,
+}
+
+/// An analog controller read through a multiplexer channel, or straight from an ADC pin if
+/// `channel` is `None`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Controller {
+    pub kind: ControllerKind,
+    pub bank: u8,
+    pub channel: Option<u8>,
+    /// Reading with a pitch bend controller at rest.
+    center: u16,
+    /// Running average of the readings, scaled up by 2^SMOOTHING to keep the fraction.
+    smoothed: u32,
+    /// The smoothed reading and value last sent.
+    sent: Option<(u16, u16)>,
+}
+
+impl Controller {
+    pub const fn new(kind: ControllerKind, bank: u8, channel: Option<u8>) -> Self {
+        let center = ADC_MAX / 2;
+        Self {
+            kind,
+            bank,
+            channel,
+            center,
+            smoothed: (center as u32) << SMOOTHING,
+            sent: None,
+        }
+    }
+
+    /// Takes a reading with the controller at rest. Pitch bend controllers spring back to the
+    /// centre, so this is done at boot before they can be touched.
+    pub fn calibrate(&mut self, reading: u16) {
+        if self.kind == ControllerKind::PitchBend {
+            self.center = reading.min(ADC_MAX);
+        }
+        self.smoothed = u32::from(reading) << SMOOTHING;
+    }
+
+    /// Reads the controller, returns the value to send if it changed.
+    pub async fn read<A: Adc, M: Multiplexer>(
+        &mut self,
+        adc: &mut A,
+        multiplexer: &mut M,
+    ) -> Option<u16> {
+        if let Some(channel) = self.channel {
+            multiplexer.set_channel(channel);
+        }
+        let reading = adc.read(usize::from(self.bank)).await?;
+        self.update(reading)
+    }
+
+    /// Smooths a new reading and returns the value to send, either 0 to `PITCH_BEND_MAX` or 0 to
+    /// `CC_MAX`, if it changed by more than the threshold or came to rest.
+    pub fn update(&mut self, reading: u16) -> Option<u16> {
+        self.smoothed =
+            self.smoothed - (self.smoothed >> SMOOTHING) + u32::from(reading.min(ADC_MAX));
+        let smoothed = (self.smoothed >> SMOOTHING) as u16;
+        let value = self.value(smoothed);
+
+        if let Some((sent_reading, sent_value)) = self.sent {
+            // Values at rest or at either end are always sent so the last one isn't lost under
+            // the threshold
+            let at_rest = value == self.rest_value() || value == 0 || value == self.max_value();
+            if value == sent_value || (smoothed.abs_diff(sent_reading) < THRESHOLD && !at_rest) {
+                return None;
+            }
+        }
+        self.sent = Some((smoothed, value));
+        Some(value)
+    }
+
+    fn max_value(&self) -> u16 {
+        match self.kind {
+            ControllerKind::PitchBend => PITCH_BEND_MAX,
+            ControllerKind::ModWheel | ControllerKind::Expression => CC_MAX,
+        }
+    }
+
+    fn rest_value(&self) -> u16 {
+        match self.kind {
+            ControllerKind::PitchBend => PITCH_BEND_CENTER,
+            ControllerKind::ModWheel | ControllerKind::Expression => 0,
+        }
+    }
+
+    fn value(&self, reading: u16) -> u16 {
+        let top = ADC_MAX - END_MARGIN;
+        match self.kind {
+            ControllerKind::PitchBend => {
+                let up = self.center.saturating_add(DEADZONE);
+                let down = self.center.saturating_sub(DEADZONE);
+                if reading > up {
+                    PITCH_BEND_CENTER + scale(reading - up, top.saturating_sub(up), 8191)
+                } else if reading < down {
+                    PITCH_BEND_CENTER - scale(down - reading, down.saturating_sub(END_MARGIN), 8192)
+                } else {
+                    PITCH_BEND_CENTER
+                }
+            }
+            ControllerKind::ModWheel | ControllerKind::Expression => {
+                scale(reading.saturating_sub(END_MARGIN), top - END_MARGIN, CC_MAX)
+            }
+        }
+    }
+}
+
+/// Maps `distance` out of `range` onto 0 to `max`, clamped at `max`.
+fn scale(distance: u16, range: u16, max: u16) -> u16 {
+    let value = u32::from(distance) * u32::from(max) / u32::from(range.max(1));
+    value.min(u32::from(max)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the same reading until the smoothing settles, returns the last value sent.
+    fn settle(controller: &mut Controller, reading: u16) -> Option<u16> {
+        (0..32).filter_map(|_| controller.update(reading)).last()
+    }
+
+    #[test]
+    fn pitch_bend_is_centred_where_the_stick_rests() {
+        let mut controller = Controller::new(ControllerKind::PitchBend, 0, Some(3));
+        controller.calibrate(1800);
+        assert_eq!(controller.update(1800), Some(PITCH_BEND_CENTER));
+        assert_eq!(settle(&mut controller, 1800 + DEADZONE / 2), None);
+        assert_eq!(settle(&mut controller, 1800 - DEADZONE / 2), None);
+    }
+
+    #[test]
+    fn pitch_bend_reaches_both_ends() {
+        let mut controller = Controller::new(ControllerKind::PitchBend, 0, Some(3));
+        controller.calibrate(1800);
+        assert_eq!(settle(&mut controller, ADC_MAX), Some(PITCH_BEND_MAX));
+        assert_eq!(settle(&mut controller, 0), Some(0));
+        assert_eq!(settle(&mut controller, 1800), Some(PITCH_BEND_CENTER));
+    }
+
+    #[test]
+    fn cc_ranges_past_the_margins() {
+        let mut controller = Controller::new(ControllerKind::ModWheel, 1, Some(11));
+        controller.calibrate(0);
+        assert_eq!(controller.update(0), Some(0));
+        assert_eq!(
+            settle(&mut controller, ADC_MAX - END_MARGIN / 2),
+            Some(CC_MAX)
+        );
+        assert_eq!(settle(&mut controller, ADC_MAX / 2), Some(63));
+        assert_eq!(settle(&mut controller, END_MARGIN / 2), Some(0));
+    }
+
+    #[test]
+    fn noise_under_the_threshold_is_not_sent() {
+        let mut controller = Controller::new(ControllerKind::Expression, 2, None);
+        controller.calibrate(2000);
+        assert!(controller.update(2000).is_some());
+        let noise = [2004, 1996, 2006, 1995, 2003];
+        assert_eq!(
+            noise.iter().filter_map(|r| controller.update(*r)).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn single_spikes_are_smoothed() {
+        let mut controller = Controller::new(ControllerKind::Expression, 2, None);
+        controller.calibrate(2000);
+        let sent = controller.update(2000).unwrap();
+        let spike = controller.update(2400).unwrap();
+        assert!(spike > sent && spike < controller.value(2400));
+    }
+}
@@ -0,0 +1,165 @@
+use core::time::Duration;
+
+/// Quadrature transitions between two detents.
+const STEPS_PER_DETENT: i8 = 4;
+/// How long a button has to stay at a new level before the change is reported.
+pub const DEBOUNCE: Duration = Duration::from_millis(5);
+
+/// Direction of each transition, indexed by the previous and current A/B levels. Transitions
+/// that skip a state are noise or missed reads and count for nothing.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// How a turn of an encoder is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EncoderMode {
+    /// Relative CC, 1 to 63 clockwise and 127 down to 65 anticlockwise.
+    #[default]
+    TwosComplement,
+    /// Relative CC, 65 upwards clockwise and 63 downwards anticlockwise.
+    BinaryOffset,
+    /// Data increment and decrement for the encoder's NRPN.
+    Nrpn,
+}
+
+impl EncoderMode {
+    /// Returns the relative CC value for `delta` detents, `None` in NRPN mode.
+    pub fn relative_value(self, delta: i8) -> Option<u8> {
+        let delta = delta.clamp(-63, 63);
+        match self {
+            Self::TwosComplement => Some((delta as u8) & 0x7F),
+            Self::BinaryOffset => Some((64 + delta) as u8),
+            Self::Nrpn => None,
+        }
+    }
+}
+
+/// Decodes the A and B pins of a rotary encoder into detents.
+#[derive(Debug, Default)]
+pub struct Quadrature {
+    previous: u8,
+    steps: i8,
+}
+
+impl Quadrature {
+    /// Takes the current pin levels, returns 1 for a detent clockwise, -1 anticlockwise and 0
+    /// otherwise.
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let current = (u8::from(a) << 1) | u8::from(b);
+        self.steps += TRANSITIONS[usize::from(self.previous << 2 | current)];
+        self.previous = current;
+
+        if self.steps >= STEPS_PER_DETENT {
+            self.steps = 0;
+            1
+        } else if self.steps <= -STEPS_PER_DETENT {
+            self.steps = 0;
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+/// Debounces a push button.
+#[derive(Debug, Default)]
+pub struct Button {
+    pressed: bool,
+    /// The level that is waiting to settle and when it was first seen.
+    pending: Option<(bool, Duration)>,
+}
+
+impl Button {
+    /// Takes the current level at `now`, returns the new state once it has been stable for
+    /// `DEBOUNCE`.
+    pub fn update(&mut self, pressed: bool, now: Duration) -> Option<bool> {
+        if pressed == self.pressed {
+            self.pending = None;
+            return None;
+        }
+        match self.pending {
+            Some((level, since)) if level == pressed => {
+                if now.saturating_sub(since) < DEBOUNCE {
+                    return None;
+                }
+                self.pressed = pressed;
+                self.pending = None;
+                Some(pressed)
+            }
+            _ => {
+                self.pending = Some((pressed, now));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(true, false), (true, true), (false, true), (false, false)];
+
+    fn turn(quadrature: &mut Quadrature, levels: impl Iterator<Item = (bool, bool)>) -> i8 {
+        levels.map(|(a, b)| quadrature.update(a, b)).sum()
+    }
+
+    #[test]
+    fn counts_detents_in_both_directions() {
+        let mut quadrature = Quadrature::default();
+        let clockwise = CLOCKWISE.into_iter().cycle().take(8);
+        assert_eq!(turn(&mut quadrature, clockwise), 2);
+        let anticlockwise = CLOCKWISE.into_iter().rev().cycle().skip(1).take(12);
+        assert_eq!(turn(&mut quadrature, anticlockwise), -3);
+    }
+
+    #[test]
+    fn half_a_detent_and_back_does_not_count() {
+        let mut quadrature = Quadrature::default();
+        let levels = [(true, false), (true, true), (true, false), (false, false)];
+        assert_eq!(turn(&mut quadrature, levels.into_iter()), 0);
+        assert_eq!(turn(&mut quadrature, CLOCKWISE.into_iter()), 1);
+    }
+
+    #[test]
+    fn bouncing_contacts_do_not_count() {
+        let mut quadrature = Quadrature::default();
+        let bouncing = [
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, true),
+            (true, false),
+            (true, true),
+            (false, true),
+            (false, false),
+        ];
+        assert_eq!(turn(&mut quadrature, bouncing.into_iter()), 1);
+    }
+
+    #[test]
+    fn relative_values() {
+        assert_eq!(EncoderMode::TwosComplement.relative_value(1), Some(1));
+        assert_eq!(EncoderMode::TwosComplement.relative_value(-1), Some(127));
+        assert_eq!(EncoderMode::TwosComplement.relative_value(-3), Some(125));
+        assert_eq!(EncoderMode::BinaryOffset.relative_value(1), Some(65));
+        assert_eq!(EncoderMode::BinaryOffset.relative_value(-1), Some(63));
+        assert_eq!(EncoderMode::BinaryOffset.relative_value(100), Some(127));
+        assert_eq!(EncoderMode::Nrpn.relative_value(1), None);
+    }
+
+    #[test]
+    fn buttons_are_debounced() {
+        let ms = Duration::from_millis;
+        let mut button = Button::default();
+        assert_eq!(button.update(true, ms(0)), None);
+        assert_eq!(button.update(false, ms(1)), None);
+        assert_eq!(button.update(true, ms(2)), None);
+        assert_eq!(button.update(true, ms(6)), None);
+        assert_eq!(button.update(true, ms(7)), Some(true));
+        assert_eq!(button.update(true, ms(20)), None);
+        assert_eq!(button.update(false, ms(21)), None);
+        assert_eq!(button.update(false, ms(26)), Some(false));
+    }
+}
@@ -0,0 +1,122 @@
+use wmidi::Note;
+
+/// Number of key codes, arrays indexed by key code are this long.
+pub const NUM_KEYS: usize = KeyCode::ALL.len();
+pub const MIN_OCTAVE: u8 = 1;
+pub const MAX_OCTAVE: u8 = 8;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyCode {
+    SHIFT = 0,
+    UP = 1,
+    DOWN = 2,
+    C1 = 3,
+    CSharp1 = 4,
+    D1 = 5,
+    DSharp1 = 6,
+    E1 = 7,
+    F1 = 8,
+    FSharp1 = 9,
+    G1 = 10,
+    GSharp1 = 11,
+    A1 = 12,
+    ASharp1 = 13,
+    B1 = 14,
+    C2 = 15,
+    CSharp2 = 16,
+    D2 = 17,
+    DSharp2 = 18,
+    E2 = 19,
+    F2 = 20,
+    FSharp2 = 21,
+    G2 = 22,
+    GSharp2 = 23,
+    A2 = 24,
+    ASharp2 = 25,
+    B2 = 26,
+    /// Holds notes like a sustain pedal while pressed.
+    SUSTAIN = 27,
+    /// Turns off every note on every channel.
+    PANIC = 28,
+}
+
+impl KeyCode {
+    /// Every key code, in order.
+    pub const ALL: [Self; 29] = [
+        Self::SHIFT,
+        Self::UP,
+        Self::DOWN,
+        Self::C1,
+        Self::CSharp1,
+        Self::D1,
+        Self::DSharp1,
+        Self::E1,
+        Self::F1,
+        Self::FSharp1,
+        Self::G1,
+        Self::GSharp1,
+        Self::A1,
+        Self::ASharp1,
+        Self::B1,
+        Self::C2,
+        Self::CSharp2,
+        Self::D2,
+        Self::DSharp2,
+        Self::E2,
+        Self::F2,
+        Self::FSharp2,
+        Self::G2,
+        Self::GSharp2,
+        Self::A2,
+        Self::ASharp2,
+        Self::B2,
+        Self::SUSTAIN,
+        Self::PANIC,
+    ];
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+
+    /// Returns the note for this key in the given octave, e.g. `C1` in octave 4 is `C4` and `C2`
+    /// is `C5`.
+    pub fn to_note(self, octave: u8) -> Option<Note> {
+        if !(MIN_OCTAVE..=MAX_OCTAVE).contains(&octave) {
+            return None;
+        }
+
+        if let Some(note) = match self {
+            Self::C1 => Some(Note::C1),
+            Self::CSharp1 => Some(Note::CSharp1),
+            Self::D1 => Some(Note::D1),
+            Self::DSharp1 => Some(Note::DSharp1),
+            Self::E1 => Some(Note::E1),
+            Self::F1 => Some(Note::F1),
+            Self::FSharp1 => Some(Note::FSharp1),
+            Self::G1 => Some(Note::G1),
+            Self::GSharp1 => Some(Note::GSharp1),
+            Self::A1 => Some(Note::A1),
+            Self::ASharp1 => Some(Note::ASharp1),
+            Self::B1 => Some(Note::B1),
+            Self::C2 => Some(Note::C2),
+            Self::CSharp2 => Some(Note::CSharp2),
+            Self::D2 => Some(Note::D2),
+            Self::DSharp2 => Some(Note::DSharp2),
+            Self::E2 => Some(Note::E2),
+            Self::F2 => Some(Note::F2),
+            Self::FSharp2 => Some(Note::FSharp2),
+            Self::G2 => Some(Note::G2),
+            Self::GSharp2 => Some(Note::GSharp2),
+            Self::A2 => Some(Note::A2),
+            Self::ASharp2 => Some(Note::ASharp2),
+            Self::B2 => Some(Note::B2),
+            _ => None,
+        } {
+            let steps: i8 = (octave as i8 - 1) * 12;
+            return note.step(steps).ok();
+        }
+        None
+    }
+}
@@ -0,0 +1,264 @@
+use crate::calibration::checksum;
+use crate::key_code::KeyCode;
+
+/// Number of multiplexers, each read through its own ADC pin.
+pub const NUM_BANKS: usize = 2;
+/// Number of channels on each multiplexer.
+pub const NUM_CHANNELS: usize = 16;
+/// Most keys a key map can hold, one on every channel of every bank.
+pub const MAX_KEYS: usize = NUM_BANKS * NUM_CHANNELS;
+
+const MAGIC: [u8; 4] = *b"KMAP";
+const VERSION: u8 = 1;
+/// Size of a key map when stored, magic + version + number of keys + each key + checksum.
+pub const SERIALIZED_SIZE: usize = MAGIC.len() + 2 + MAX_KEYS * 3 + 1;
+
+/// A key and the multiplexer bank and channel it is wired to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Key {
+    pub bank: u8,
+    pub channel: u8,
+    pub code: KeyCode,
+}
+
+impl Key {
+    const fn new(bank: u8, channel: u8, code: KeyCode) -> Self {
+        Self {
+            bank,
+            channel,
+            code,
+        }
+    }
+}
+
+/// Function keys and the first octave are read through `AM1_COM`, the second octave through
+/// `AM2_COM`.
+pub const DEFAULT_KEYS: [Key; 27] = [
+    Key::new(0, 6, KeyCode::SHIFT),
+    Key::new(0, 7, KeyCode::UP),
+    Key::new(0, 8, KeyCode::DOWN),
+    Key::new(0, 9, KeyCode::C1),
+    Key::new(0, 5, KeyCode::CSharp1),
+    Key::new(0, 10, KeyCode::D1),
+    Key::new(0, 4, KeyCode::DSharp1),
+    Key::new(0, 11, KeyCode::E1),
+    Key::new(0, 12, KeyCode::F1),
+    Key::new(0, 2, KeyCode::FSharp1),
+    Key::new(0, 13, KeyCode::G1),
+    Key::new(0, 1, KeyCode::GSharp1),
+    Key::new(0, 14, KeyCode::A1),
+    Key::new(0, 0, KeyCode::ASharp1),
+    Key::new(0, 15, KeyCode::B1),
+    Key::new(1, 0, KeyCode::C2),
+    Key::new(1, 15, KeyCode::CSharp2),
+    Key::new(1, 1, KeyCode::D2),
+    Key::new(1, 14, KeyCode::DSharp2),
+    Key::new(1, 2, KeyCode::E2),
+    Key::new(1, 4, KeyCode::F2),
+    Key::new(1, 10, KeyCode::FSharp2),
+    Key::new(1, 5, KeyCode::G2),
+    Key::new(1, 9, KeyCode::GSharp2),
+    Key::new(1, 6, KeyCode::A2),
+    Key::new(1, 8, KeyCode::ASharp2),
+    Key::new(1, 7, KeyCode::B2),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    TooManyKeys,
+    /// The SysEx data isn't a whole number of triplets.
+    Incomplete,
+    InvalidBank(u8),
+    InvalidChannel(u8),
+    InvalidKeyCode(u8),
+    /// Two keys are on the same bank and channel.
+    DuplicateChannel {
+        bank: u8,
+        channel: u8,
+    },
+    /// The same key code is on two channels.
+    DuplicateKey(KeyCode),
+}
+
+/// Which key function each multiplexer channel is wired to. Channels that aren't in the map
+/// aren't scanned.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyMap {
+    keys: [Key; MAX_KEYS],
+    len: usize,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::new(&DEFAULT_KEYS).expect("default key map is valid")
+    }
+}
+
+impl KeyMap {
+    /// Checks every key is on a bank and channel that exists, and that no channel or key code is
+    /// used twice.
+    pub fn new(keys: &[Key]) -> Result<Self, Error> {
+        if keys.len() > MAX_KEYS {
+            return Err(Error::TooManyKeys);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            if usize::from(key.bank) >= NUM_BANKS {
+                return Err(Error::InvalidBank(key.bank));
+            }
+            if usize::from(key.channel) >= NUM_CHANNELS {
+                return Err(Error::InvalidChannel(key.channel));
+            }
+            for other in &keys[i + 1..] {
+                if other.bank == key.bank && other.channel == key.channel {
+                    return Err(Error::DuplicateChannel {
+                        bank: key.bank,
+                        channel: key.channel,
+                    });
+                }
+                if other.code == key.code {
+                    return Err(Error::DuplicateKey(key.code));
+                }
+            }
+        }
+
+        let mut map = Self {
+            keys: [Key::new(0, 0, KeyCode::SHIFT); MAX_KEYS],
+            len: keys.len(),
+        };
+        map.keys[..keys.len()].copy_from_slice(keys);
+        Ok(map)
+    }
+
+    /// Reads a key map from `bank channel code` triplets, as sent over SysEx.
+    pub fn from_triplets(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() > MAX_KEYS * 3 {
+            return Err(Error::TooManyKeys);
+        }
+        let triplets = bytes.chunks_exact(3);
+        if !triplets.remainder().is_empty() {
+            return Err(Error::Incomplete);
+        }
+        let mut keys = [Key::new(0, 0, KeyCode::SHIFT); MAX_KEYS];
+        let len = triplets.len();
+        for (key, triplet) in keys.iter_mut().zip(triplets) {
+            let code = KeyCode::from_index(triplet[2]).ok_or(Error::InvalidKeyCode(triplet[2]))?;
+            *key = Key::new(triplet[0], triplet[1], code);
+        }
+        Self::new(&keys[..len])
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys[..self.len]
+    }
+
+    /// Returns where a key is wired to.
+    pub fn find(&self, code: KeyCode) -> Option<&Key> {
+        self.keys().iter().find(|key| key.code == code)
+    }
+
+    /// Returns whether a key is wired to a bank and channel.
+    pub fn is_wired(&self, bank: u8, channel: u8) -> bool {
+        self.keys()
+            .iter()
+            .any(|key| key.bank == bank && key.channel == channel)
+    }
+
+    pub fn to_bytes(&self) -> [u8; SERIALIZED_SIZE] {
+        let mut bytes = [0; SERIALIZED_SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.len as u8;
+        for (i, key) in self.keys().iter().enumerate() {
+            let offset = 6 + i * 3;
+            bytes[offset..offset + 3].copy_from_slice(&[key.bank, key.channel, key.code as u8]);
+        }
+        bytes[SERIALIZED_SIZE - 1] = checksum(&bytes[..SERIALIZED_SIZE - 1]);
+        bytes
+    }
+
+    /// Reads a key map written by `to_bytes`, returns `None` if the bytes are erased flash, from a
+    /// different version, corrupt or not a valid key map.
+    pub fn from_bytes(bytes: &[u8; SERIALIZED_SIZE]) -> Option<Self> {
+        if bytes[..4] != MAGIC
+            || bytes[4] != VERSION
+            || bytes[SERIALIZED_SIZE - 1] != checksum(&bytes[..SERIALIZED_SIZE - 1])
+        {
+            return None;
+        }
+        let len = usize::from(bytes[5]).min(MAX_KEYS);
+        Self::from_triplets(&bytes[6..6 + len * 3]).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_maps_every_key_on_the_board() {
+        let map = KeyMap::default();
+        assert_eq!(map.keys().len(), DEFAULT_KEYS.len());
+        for code in &KeyCode::ALL[..DEFAULT_KEYS.len()] {
+            assert!(map.find(*code).is_some(), "{:?} is not mapped", code);
+        }
+    }
+
+    #[test]
+    fn duplicate_channels_are_rejected() {
+        let keys = [
+            Key::new(0, 3, KeyCode::SHIFT),
+            Key::new(1, 3, KeyCode::UP),
+            Key::new(0, 3, KeyCode::C1),
+        ];
+        assert_eq!(
+            KeyMap::new(&keys),
+            Err(Error::DuplicateChannel {
+                bank: 0,
+                channel: 3
+            })
+        );
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        let keys = [Key::new(0, 1, KeyCode::C1), Key::new(1, 1, KeyCode::C1)];
+        assert_eq!(KeyMap::new(&keys), Err(Error::DuplicateKey(KeyCode::C1)));
+    }
+
+    #[test]
+    fn keys_must_exist_on_the_board() {
+        assert_eq!(
+            KeyMap::from_triplets(&[2, 0, 0]),
+            Err(Error::InvalidBank(2))
+        );
+        assert_eq!(
+            KeyMap::from_triplets(&[0, 16, 0]),
+            Err(Error::InvalidChannel(16))
+        );
+        assert_eq!(
+            KeyMap::from_triplets(&[0, 0, 100]),
+            Err(Error::InvalidKeyCode(100))
+        );
+    }
+
+    #[test]
+    fn function_keys_can_be_remapped() {
+        let map = KeyMap::from_triplets(&[0, 3, KeyCode::PANIC as u8, 1, 12, 3]).unwrap();
+        assert_eq!(
+            map.find(KeyCode::PANIC),
+            Some(&Key::new(0, 3, KeyCode::PANIC))
+        );
+        assert_eq!(map.find(KeyCode::C1), Some(&Key::new(1, 12, KeyCode::C1)));
+        assert_eq!(map.find(KeyCode::SHIFT), None);
+    }
+
+    #[test]
+    fn round_trips_through_flash() {
+        let map = KeyMap::default();
+        assert_eq!(KeyMap::from_bytes(&map.to_bytes()), Some(map));
+        assert_eq!(KeyMap::from_bytes(&[0xFF; SERIALIZED_SIZE]), None);
+    }
+}
@@ -0,0 +1,16 @@
+//! Logic shared by the firmware and the app that doesn't depend on any hardware, so it can be
+//! tested on the host with `cargo test`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod arpeggiator;
+pub mod calibration;
+pub mod controller;
+pub mod encoder;
+pub mod key_code;
+pub mod key_map;
+pub mod pressure;
+pub mod random;
+pub mod scanner;
+pub mod state;
+pub mod usb_midi;
+pub mod velocity;
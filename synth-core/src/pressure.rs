@@ -0,0 +1,43 @@
+use core::time::Duration;
+
+use crate::calibration::FULL_TRAVEL;
+
+/// Shortest time between two pressure messages for the same key, so holding a key doesn't flood
+/// the USB endpoint.
+pub const PRESSURE_INTERVAL: Duration = Duration::from_millis(20);
+/// Manager channel of the MPE lower zone (0 indexed).
+pub const MPE_MANAGER_CHANNEL: u8 = 0;
+/// First and last MPE member channels (0 indexed).
+pub const MPE_FIRST_CHANNEL: u8 = 1;
+pub const MPE_LAST_CHANNEL: u8 = 15;
+
+/// How the depth of a held key is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PressureMode {
+    /// Only note on and note off are sent.
+    #[default]
+    Off,
+    /// Each key sends polyphonic key pressure on the keyboard's channel.
+    PolyAftertouch,
+    /// Each note is played on its own member channel, and its depth is sent as channel pressure
+    /// on that channel.
+    Mpe,
+}
+
+impl PressureMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::PolyAftertouch,
+            Self::PolyAftertouch => Self::Mpe,
+            Self::Mpe => Self::Off,
+        }
+    }
+}
+
+/// Maps how far a key is pressed past `press_threshold` onto a pressure from 0 to 127.
+pub fn pressure(travel: u16, press_threshold: u16) -> u8 {
+    let range = u32::from(FULL_TRAVEL.saturating_sub(press_threshold).max(1));
+    let depth = u32::from(travel.saturating_sub(press_threshold)).min(range);
+    (depth * 127 / range) as u8
+}
@@ -0,0 +1,35 @@
+//! Cheap pseudo random numbers for musical randomness such as random arpeggios, step probability
+//! and sample and hold LFOs, where being fast and the same on every target matters more than
+//! quality.
+
+/// Seed for generators that don't need to differ from one run to the next, any non-zero value
+/// would do.
+pub const SEED: u32 = 0x2545_f491;
+
+/// Moves xorshift32 `state` on and returns the new value. A state of zero stays zero forever, so
+/// start from `SEED` or another non-zero value.
+pub fn xorshift(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_sequence() {
+        let mut state = 1;
+        assert_eq!(xorshift(&mut state), 270_369);
+        assert_eq!(xorshift(&mut state), 67_634_689);
+        assert_eq!(state, 67_634_689);
+    }
+
+    #[test]
+    fn never_reaches_zero_from_a_non_zero_seed() {
+        let mut state = SEED;
+        assert!((0..100_000).all(|_| xorshift(&mut state) != 0));
+    }
+}
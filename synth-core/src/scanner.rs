@@ -0,0 +1,283 @@
+use core::time::Duration;
+
+use crate::calibration::Calibration;
+use crate::key_code::{KeyCode, NUM_KEYS};
+use crate::key_map::Key;
+
+// Thresholds are normalised travel from rest, see `Calibration::travel`
+/// Travel where a key starts being timed for velocity.
+pub const START_THRESHOLD: u16 = 50;
+/// Travel where a key is pressed.
+pub const PRESS_THRESHOLD: u16 = 400;
+/// Travel a pressed key has to come back above to be released, this is lower than the press
+/// threshold so a key resting near it doesn't retrigger.
+pub const RELEASE_THRESHOLD: u16 = 300;
+
+/// Reads the position of whichever key the multiplexers are set to.
+#[allow(async_fn_in_trait)]
+pub trait Adc {
+    /// Reads the multiplexer of `bank`, or for banks past the multiplexers a pin wired straight to
+    /// the ADC. Returns `None` if the read failed.
+    async fn read(&mut self, bank: usize) -> Option<u16>;
+}
+
+/// Connects one key in each bank to the ADC, the banks share select lines so they are all set
+/// to the same channel.
+pub trait Multiplexer {
+    fn set_channel(&mut self, channel: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyEvent {
+    /// The key went past the press threshold, `elapsed` is how long it took to get there from
+    /// the start threshold.
+    Pressed {
+        code: KeyCode,
+        elapsed: Duration,
+    },
+    Released {
+        code: KeyCode,
+    },
+    /// The key is still pressed, sent on every scan with how far down it is.
+    Held {
+        code: KeyCode,
+        travel: u16,
+    },
+}
+
+/// Tracks the travel of every key to find when they are pressed and released, and how fast.
+pub struct Scanner {
+    pub calibration: Calibration,
+    /// The last position read for each key.
+    pub positions: [u16; NUM_KEYS],
+    pressed: [bool; NUM_KEYS],
+    /// When each key passed the start threshold on its way down, used to measure velocity.
+    travel_start: [Option<Duration>; NUM_KEYS],
+}
+
+impl Scanner {
+    pub fn new(calibration: Calibration) -> Self {
+        Self {
+            positions: calibration.rest,
+            calibration,
+            pressed: [false; NUM_KEYS],
+            travel_start: [None; NUM_KEYS],
+        }
+    }
+
+    pub fn is_pressed(&self, code: KeyCode) -> bool {
+        self.pressed[code as usize]
+    }
+
+    /// Forgets a key was pressed, if it is still held it is pressed again on the next update.
+    pub fn reset(&mut self, code: KeyCode) {
+        self.pressed[code as usize] = false;
+        self.travel_start[code as usize] = None;
+    }
+
+    /// Reads a key and updates it. If the read fails the key keeps its last position.
+    pub async fn read<A: Adc, M: Multiplexer>(
+        &mut self,
+        key: &Key,
+        adc: &mut A,
+        multiplexer: &mut M,
+        now: Duration,
+    ) -> Option<KeyEvent> {
+        multiplexer.set_channel(key.channel);
+        let position = adc
+            .read(usize::from(key.bank))
+            .await
+            .unwrap_or(self.positions[key.code as usize]);
+        self.update(key.code, position, now)
+    }
+
+    /// Updates a key with a new position read at `now`, the time since some fixed point such as
+    /// boot.
+    pub fn update(&mut self, code: KeyCode, position: u16, now: Duration) -> Option<KeyEvent> {
+        let index = code as usize;
+        self.positions[index] = position;
+        let travel = self.calibration.travel(index, position);
+
+        if self.pressed[index] {
+            if travel < RELEASE_THRESHOLD {
+                self.pressed[index] = false;
+                return Some(KeyEvent::Released { code });
+            }
+            return Some(KeyEvent::Held { code, travel });
+        }
+
+        if travel >= PRESS_THRESHOLD {
+            // If the key went past both thresholds between two scans it was as fast as we can
+            // measure
+            let elapsed = self.travel_start[index]
+                .take()
+                .map(|start| now.saturating_sub(start))
+                .unwrap_or_default();
+            self.pressed[index] = true;
+            Some(KeyEvent::Pressed { code, elapsed })
+        } else {
+            if travel < START_THRESHOLD {
+                self.travel_start[index] = None;
+            } else if self.travel_start[index].is_none() {
+                self.travel_start[index] = Some(now);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_map::DEFAULT_KEYS;
+    use crate::velocity::VelocityCurve;
+    use embassy_futures::block_on;
+
+    /// Replays a trace of positions for one bank, one per read.
+    struct Trace<'a> {
+        bank: usize,
+        positions: core::slice::Iter<'a, Option<u16>>,
+    }
+
+    impl Adc for Trace<'_> {
+        async fn read(&mut self, bank: usize) -> Option<u16> {
+            assert_eq!(bank, self.bank);
+            self.positions.next().copied().flatten()
+        }
+    }
+
+    #[derive(Default)]
+    struct Channel(Option<u8>);
+
+    impl Multiplexer for Channel {
+        fn set_channel(&mut self, channel: u8) {
+            self.0 = Some(channel);
+        }
+    }
+
+    const SCAN_INTERVAL: Duration = Duration::from_millis(1);
+
+    /// Feeds normalised travels to the first note key, one every `SCAN_INTERVAL`, and returns
+    /// the events.
+    fn replay(travels: &[u16]) -> impl Iterator<Item = KeyEvent> + '_ {
+        let mut scanner = Scanner::new(Calibration::default());
+        let rest = scanner.calibration.rest[KeyCode::C1 as usize];
+        let bottom = scanner.calibration.bottom[KeyCode::C1 as usize];
+        travels.iter().enumerate().filter_map(move |(i, travel)| {
+            let position = rest - (u32::from(rest - bottom) * u32::from(*travel) / 1000) as u16;
+            scanner.update(KeyCode::C1, position, SCAN_INTERVAL * i as u32)
+        })
+    }
+
+    fn notes(travels: &[u16]) -> impl Iterator<Item = KeyEvent> + '_ {
+        replay(travels).filter(|event| !matches!(event, KeyEvent::Held { .. }))
+    }
+
+    #[test]
+    fn press_and_release() {
+        let events: Vec<_> = notes(&[0, 100, 300, 500, 1000, 800, 200, 0]).collect();
+        assert_eq!(
+            events,
+            [
+                KeyEvent::Pressed {
+                    code: KeyCode::C1,
+                    elapsed: SCAN_INTERVAL * 2
+                },
+                KeyEvent::Released { code: KeyCode::C1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn bouncing_around_the_press_threshold_does_not_retrigger() {
+        let travels = [0, 200, 420, 380, 410, 320, 450, 390, 0];
+        assert_eq!(notes(&travels).count(), 2);
+    }
+
+    #[test]
+    fn partial_presses_are_ignored() {
+        let travels = [0, 100, 350, 390, 200, 60, 0];
+        assert_eq!(notes(&travels).count(), 0);
+    }
+
+    #[test]
+    fn held_keys_report_travel() {
+        let events: Vec<_> = replay(&[500, 700, 1000]).collect();
+        assert_eq!(
+            &events[1..],
+            [
+                KeyEvent::Held {
+                    code: KeyCode::C1,
+                    travel: 700
+                },
+                KeyEvent::Held {
+                    code: KeyCode::C1,
+                    travel: 1000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn faster_presses_are_louder() {
+        let velocity = |travels: &[u16]| match notes(travels).next() {
+            Some(KeyEvent::Pressed { elapsed, .. }) => VelocityCurve::Linear.velocity(elapsed),
+            event => panic!("expected a press, got {:?}", event),
+        };
+
+        let slow: Vec<u16> = (0..=40).map(|i| i * 10).collect();
+        let medium: Vec<u16> = (0..=10).map(|i| i * 40).collect();
+        let instant = [0, 1000];
+        assert!(velocity(&slow) < velocity(&medium));
+        assert!(velocity(&medium) < velocity(&instant));
+        assert_eq!(velocity(&instant), 127);
+    }
+
+    #[test]
+    fn dropping_below_the_start_threshold_resets_the_timing() {
+        // A key that drifts down and back up before being pressed is timed from its last start
+        let events: Vec<_> = notes(&[0, 100, 200, 0, 0, 100, 500]).collect();
+        assert_eq!(
+            events,
+            [KeyEvent::Pressed {
+                code: KeyCode::C1,
+                elapsed: SCAN_INTERVAL
+            }]
+        );
+    }
+
+    #[test]
+    fn reads_through_the_multiplexer() {
+        let key = &DEFAULT_KEYS[18];
+        let mut scanner = Scanner::new(Calibration::default());
+        let mut multiplexer = Channel::default();
+        let trace = [Some(2000), Some(1000), None, Some(2000)];
+        let mut adc = Trace {
+            bank: 1,
+            positions: trace.iter(),
+        };
+        let events: Vec<_> = (0..trace.len())
+            .filter_map(|i| {
+                block_on(scanner.read(key, &mut adc, &mut multiplexer, SCAN_INTERVAL * i as u32))
+            })
+            .collect();
+
+        assert_eq!(multiplexer.0, Some(key.channel));
+        // The failed read keeps the key held rather than releasing it
+        assert_eq!(
+            events,
+            [
+                KeyEvent::Pressed {
+                    code: key.code,
+                    elapsed: Duration::ZERO
+                },
+                KeyEvent::Held {
+                    code: key.code,
+                    travel: 1000
+                },
+                KeyEvent::Released { code: key.code },
+            ]
+        );
+    }
+}
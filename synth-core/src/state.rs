@@ -0,0 +1,133 @@
+use crate::arpeggiator::Arpeggiator;
+use crate::encoder::EncoderMode;
+use crate::key_code::{KeyCode, MAX_OCTAVE, MIN_OCTAVE, NUM_KEYS};
+use crate::pressure::{PressureMode, MPE_FIRST_CHANNEL, MPE_LAST_CHANNEL, MPE_MANAGER_CHANNEL};
+use crate::velocity::VelocityCurve;
+use core::time::Duration;
+use wmidi::Note;
+
+/// How far the keyboard can be transposed in semitones, either way.
+pub const MAX_TRANSPOSE: i8 = 12;
+
+/// A note that has been sent and the channel it was sent on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SentNote {
+    pub channel: u8,
+    pub note: u8,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct State {
+    /// Channel notes are sent on when not in MPE mode (0 indexed).
+    pub channel: u8,
+    pub octave: u8,
+    pub transpose: i8,
+    pub shift: bool,
+    /// The note that was sent when each key was pressed, so the matching note off is sent even if
+    /// the octave or transpose changed while the key was held.
+    pub notes_sent: [Option<SentNote>; NUM_KEYS],
+    pub velocity_curve: VelocityCurve,
+    pub pressure_mode: PressureMode,
+    /// The last pressure sent for each key and when it was sent.
+    pub pressures: [u8; NUM_KEYS],
+    pub pressure_sent: [Option<Duration>; NUM_KEYS],
+    /// The member channel that will be tried first for the next MPE note.
+    pub next_mpe_channel: u8,
+    pub encoder_mode: EncoderMode,
+    /// Held note keys go to the arpeggiator instead of being played when it's enabled.
+    pub arpeggiator: Arpeggiator,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            channel: 0,
+            octave: 4,
+            transpose: 0,
+            shift: false,
+            notes_sent: [None; NUM_KEYS],
+            velocity_curve: VelocityCurve::default(),
+            pressure_mode: PressureMode::default(),
+            pressures: [0; NUM_KEYS],
+            pressure_sent: [None; NUM_KEYS],
+            next_mpe_channel: MPE_FIRST_CHANNEL,
+            encoder_mode: EncoderMode::default(),
+            arpeggiator: Arpeggiator::default(),
+        }
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the note a key plays with the current octave and transpose.
+    pub fn note(&self, key: KeyCode) -> Option<Note> {
+        key.to_note(self.octave)?.step(self.transpose).ok()
+    }
+
+    /// Returns the channel a new note should be sent on. In MPE mode each held note gets its own
+    /// member channel, if they are all in use the channels are reused in turn.
+    pub fn note_channel(&mut self) -> u8 {
+        if self.pressure_mode != PressureMode::Mpe {
+            return self.channel;
+        }
+
+        let in_use = |channel: u8| {
+            self.notes_sent
+                .iter()
+                .flatten()
+                .any(|sent| sent.channel == channel)
+        };
+        let mut channel = self.next_mpe_channel;
+        for _ in MPE_FIRST_CHANNEL..=MPE_LAST_CHANNEL {
+            if !in_use(channel) {
+                break;
+            }
+            channel = if channel >= MPE_LAST_CHANNEL {
+                MPE_FIRST_CHANNEL
+            } else {
+                channel + 1
+            };
+        }
+        self.next_mpe_channel = if channel >= MPE_LAST_CHANNEL {
+            MPE_FIRST_CHANNEL
+        } else {
+            channel + 1
+        };
+        channel
+    }
+
+    /// Returns the channel for messages that apply to every note, the manager channel in MPE mode.
+    pub fn control_channel(&self) -> u8 {
+        if self.pressure_mode == PressureMode::Mpe {
+            MPE_MANAGER_CHANNEL
+        } else {
+            self.channel
+        }
+    }
+
+    /// Sets the octave, clamped to the range the keys can play.
+    pub fn set_octave(&mut self, octave: u8) {
+        self.octave = octave.clamp(MIN_OCTAVE, MAX_OCTAVE);
+    }
+
+    pub fn octave_up(&mut self) {
+        self.octave = (self.octave + 1).min(MAX_OCTAVE);
+    }
+
+    pub fn octave_down(&mut self) {
+        self.octave = (self.octave - 1).max(MIN_OCTAVE);
+    }
+
+    pub fn transpose_up(&mut self) {
+        self.transpose = (self.transpose + 1).min(MAX_TRANSPOSE);
+    }
+
+    pub fn transpose_down(&mut self) {
+        self.transpose = (self.transpose - 1).max(-MAX_TRANSPOSE);
+    }
+}
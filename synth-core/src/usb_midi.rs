@@ -0,0 +1,434 @@
+//! USB MIDI 1.0 event packets.
+//!
+//! Every MIDI message is sent over USB as one or more four byte packets. The first byte holds the
+//! cable number in the high nibble and the code index number (CIN) in the low nibble, the CIN
+//! says what kind of message follows and so how many of the next three bytes are used.
+
+use wmidi::MidiMessage;
+
+/// Size of a single USB MIDI event packet.
+pub const PACKET_SIZE: usize = 4;
+/// Largest USB transfer on a full speed bulk endpoint.
+pub const MAX_TRANSFER_SIZE: usize = 64;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+// Code index numbers, see table 4-1 of the USB MIDI 1.0 specification
+const CIN_SYSTEM_COMMON_2: u8 = 0x2;
+const CIN_SYSTEM_COMMON_3: u8 = 0x3;
+const CIN_SYSEX_CONTINUE: u8 = 0x4;
+/// Ends a SysEx message with one byte, or a single byte system common message.
+const CIN_SINGLE_BYTE_END: u8 = 0x5;
+const CIN_SYSEX_END_2: u8 = 0x6;
+const CIN_SYSEX_END_3: u8 = 0x7;
+const CIN_SINGLE_BYTE: u8 = 0xF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The message doesn't fit in what is left of the transfer.
+    Full,
+    /// The bytes aren't a complete MIDI message.
+    Invalid,
+}
+
+/// Returns the code index number for a message starting with `status`, SysEx is split across
+/// packets so has no single CIN.
+pub fn code_index_number(status: u8) -> Option<u8> {
+    match status {
+        0x80..=0xEF => Some(status >> 4),
+        0xF1 | 0xF3 => Some(CIN_SYSTEM_COMMON_2),
+        0xF2 => Some(CIN_SYSTEM_COMMON_3),
+        0xF6 => Some(CIN_SINGLE_BYTE_END),
+        0xF8..=0xFF => Some(CIN_SINGLE_BYTE),
+        _ => None,
+    }
+}
+
+/// Returns how many bytes of a packet with this code index number are part of the message.
+fn message_size(cin: u8) -> usize {
+    match cin {
+        CIN_SINGLE_BYTE_END | CIN_SINGLE_BYTE => 1,
+        CIN_SYSTEM_COMMON_2 | CIN_SYSEX_END_2 | 0xC | 0xD => 2,
+        CIN_SYSTEM_COMMON_3 | CIN_SYSEX_CONTINUE | CIN_SYSEX_END_3 | 0x8..=0xB | 0xE => 3,
+        // 0x0 and 0x1 are reserved
+        _ => 0,
+    }
+}
+
+/// Packs messages into a single USB transfer, so several events can be sent at once.
+pub struct Encoder {
+    buffer: [u8; MAX_TRANSFER_SIZE],
+    len: usize,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_TRANSFER_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Adds a complete MIDI message on `cable` (0-15). SysEx, including the start and end bytes,
+    /// is split into three byte packets. Either the whole message is added or none of it.
+    pub fn push(&mut self, cable: u8, bytes: &[u8]) -> Result<(), Error> {
+        let cable = (cable & 0x0F) << 4;
+        match bytes {
+            [SYSEX_START, .., SYSEX_END] => {
+                if self.remaining() < bytes.len().div_ceil(3) * PACKET_SIZE {
+                    return Err(Error::Full);
+                }
+                let mut chunks = bytes.chunks(3).peekable();
+                while let Some(chunk) = chunks.next() {
+                    let cin = match (chunks.peek(), chunk.len()) {
+                        (Some(_), _) => CIN_SYSEX_CONTINUE,
+                        (None, 1) => CIN_SINGLE_BYTE_END,
+                        (None, 2) => CIN_SYSEX_END_2,
+                        (None, _) => CIN_SYSEX_END_3,
+                    };
+                    self.push_packet(cable | cin, chunk);
+                }
+                Ok(())
+            }
+            [status, ..] => {
+                let cin = code_index_number(*status).ok_or(Error::Invalid)?;
+                if bytes.len() != message_size(cin) {
+                    return Err(Error::Invalid);
+                }
+                if self.remaining() < PACKET_SIZE {
+                    return Err(Error::Full);
+                }
+                self.push_packet(cable | cin, bytes);
+                Ok(())
+            }
+            [] => Err(Error::Invalid),
+        }
+    }
+
+    /// Adds a message on `cable`, see [`Encoder::push`].
+    pub fn push_message(&mut self, cable: u8, message: &MidiMessage) -> Result<(), Error> {
+        let mut bytes = [0; MAX_TRANSFER_SIZE];
+        let n = message.bytes_size();
+        message
+            .copy_to_slice(bytes.get_mut(..n).ok_or(Error::Full)?)
+            .map_err(|_| Error::Invalid)?;
+        self.push(cable, &bytes[..n])
+    }
+
+    fn push_packet(&mut self, header: u8, data: &[u8]) {
+        let packet = &mut self.buffer[self.len..self.len + PACKET_SIZE];
+        packet.fill(0);
+        packet[0] = header;
+        packet[1..1 + data.len()].copy_from_slice(data);
+        self.len += PACKET_SIZE;
+    }
+
+    /// Bytes left in the transfer.
+    pub fn remaining(&self) -> usize {
+        MAX_TRANSFER_SIZE - self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The packets added so far, ready to be written to the endpoint.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// A complete MIDI message read from USB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event<'a> {
+    pub cable: u8,
+    /// The message bytes, SysEx includes the start and end bytes.
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Event<'a> {
+    pub fn message(&self) -> Result<MidiMessage<'a>, wmidi::FromBytesError> {
+        MidiMessage::try_from(self.bytes)
+    }
+}
+
+/// Turns USB MIDI packets back into MIDI messages, reassembling SysEx of up to `N` bytes.
+///
+/// Real time messages can arrive in the middle of SysEx and are returned straight away. SysEx
+/// longer than `N` bytes is dropped, as are SysEx packets that don't follow a start byte.
+pub struct Decoder<const N: usize> {
+    sysex: [u8; N],
+    sysex_len: usize,
+    /// Whether a SysEx start byte has been read and its end hasn't.
+    in_sysex: bool,
+    message: [u8; 3],
+}
+
+impl<const N: usize> Default for Decoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Decoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            sysex: [0; N],
+            sysex_len: 0,
+            in_sysex: false,
+            message: [0; 3],
+        }
+    }
+
+    /// Decodes one packet, returning a message once it is complete.
+    pub fn decode(&mut self, packet: &[u8; PACKET_SIZE]) -> Option<Event<'_>> {
+        let cable = packet[0] >> 4;
+        let cin = packet[0] & 0x0F;
+        let data = &packet[1..1 + message_size(cin)];
+
+        let sysex_end = match cin {
+            CIN_SYSEX_CONTINUE => false,
+            CIN_SYSEX_END_2 | CIN_SYSEX_END_3 => true,
+            CIN_SINGLE_BYTE_END if data[0] == SYSEX_END => true,
+            _ => {
+                if data.is_empty() {
+                    return None;
+                }
+                self.message[..data.len()].copy_from_slice(data);
+                return Some(Event {
+                    cable,
+                    bytes: &self.message[..data.len()],
+                });
+            }
+        };
+
+        if data[0] == SYSEX_START {
+            self.sysex_len = 0;
+            self.in_sysex = true;
+        } else if !self.in_sysex {
+            return None;
+        }
+        // Keep counting past the end of the buffer so an overflowing message is dropped
+        if let Some(slot) = self
+            .sysex
+            .get_mut(self.sysex_len..self.sysex_len + data.len())
+        {
+            slot.copy_from_slice(data);
+        }
+        self.sysex_len += data.len();
+        if !sysex_end {
+            return None;
+        }
+
+        self.in_sysex = false;
+        let len = core::mem::take(&mut self.sysex_len);
+        if len > N {
+            return None;
+        }
+        Some(Event {
+            cable,
+            bytes: &self.sysex[..len],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wmidi::{Channel, Note, U7};
+
+    fn packets(bytes: &[u8]) -> impl Iterator<Item = &[u8; PACKET_SIZE]> {
+        bytes
+            .chunks_exact(PACKET_SIZE)
+            .map(|packet| packet.try_into().unwrap())
+    }
+
+    #[test]
+    fn channel_messages_use_the_status_nibble() {
+        let mut encoder = Encoder::new();
+        let note_on = MidiMessage::NoteOn(Channel::Ch2, Note::C4, U7::MAX);
+        encoder.push_message(0, &note_on).unwrap();
+        encoder
+            .push_message(
+                0,
+                &MidiMessage::ProgramChange(Channel::Ch1, U7::from_u8_lossy(5)),
+            )
+            .unwrap();
+        assert_eq!(encoder.as_bytes(), &[0x09, 0x91, 60, 127, 0x0C, 0xC0, 5, 0]);
+    }
+
+    #[test]
+    fn cable_number_is_in_the_high_nibble() {
+        let mut encoder = Encoder::new();
+        encoder.push(3, &[0x80, 60, 64]).unwrap();
+        assert_eq!(encoder.as_bytes(), &[0x38, 0x80, 60, 64]);
+    }
+
+    #[test]
+    fn system_messages() {
+        let mut encoder = Encoder::new();
+        encoder.push(0, &[0xF8]).unwrap();
+        encoder.push(0, &[0xF2, 0x10, 0x00]).unwrap();
+        encoder.push(0, &[0xF3, 0x01]).unwrap();
+        assert_eq!(
+            encoder.as_bytes(),
+            &[0x0F, 0xF8, 0, 0, 0x03, 0xF2, 0x10, 0, 0x02, 0xF3, 0x01, 0]
+        );
+    }
+
+    #[test]
+    fn sysex_is_split_into_three_byte_packets() {
+        for (sysex, expected) in [
+            (&[0xF0, 0x7D, 0xF7][..], &[0x07, 0xF0, 0x7D, 0xF7][..]),
+            (
+                &[0xF0, 0x7D, 0x01, 0xF7],
+                &[0x04, 0xF0, 0x7D, 0x01, 0x05, 0xF7, 0, 0],
+            ),
+            (
+                &[0xF0, 0x7D, 0x01, 0x02, 0xF7],
+                &[0x04, 0xF0, 0x7D, 0x01, 0x06, 0x02, 0xF7, 0],
+            ),
+        ] {
+            let mut encoder = Encoder::new();
+            encoder.push(0, sysex).unwrap();
+            assert_eq!(encoder.as_bytes(), expected);
+        }
+    }
+
+    #[test]
+    fn messages_are_batched_until_full() {
+        let mut encoder = Encoder::new();
+        for _ in 0..MAX_TRANSFER_SIZE / PACKET_SIZE {
+            encoder.push(0, &[0x90, 60, 100]).unwrap();
+        }
+        assert_eq!(encoder.remaining(), 0);
+        assert_eq!(encoder.push(0, &[0x80, 60, 64]), Err(Error::Full));
+
+        encoder.clear();
+        assert!(encoder.is_empty());
+        encoder.push(0, &[0x90, 60, 100]).unwrap();
+        // SysEx that doesn't fit is left out entirely
+        let mut sysex = [0x00; 49];
+        sysex[0] = 0xF0;
+        sysex[48] = 0xF7;
+        assert_eq!(encoder.push(0, &sysex), Err(Error::Full));
+        assert_eq!(encoder.as_bytes().len(), PACKET_SIZE);
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        let mut encoder = Encoder::new();
+        assert_eq!(encoder.push(0, &[]), Err(Error::Invalid));
+        assert_eq!(encoder.push(0, &[0x90, 60]), Err(Error::Invalid));
+        assert_eq!(encoder.push(0, &[0xF7]), Err(Error::Invalid));
+        assert_eq!(encoder.push(0, &[60, 100]), Err(Error::Invalid));
+        assert!(encoder.is_empty());
+    }
+
+    #[test]
+    fn decodes_what_is_encoded() {
+        let messages: [&[u8]; 5] = [
+            &[0x90, 60, 100],
+            &[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7],
+            &[0xD3, 42],
+            &[0xF8],
+            &[0xF0, 0x7D, 0xF7],
+        ];
+        let mut encoder = Encoder::new();
+        for message in messages {
+            encoder.push(1, message).unwrap();
+        }
+
+        let mut decoder = Decoder::<16>::new();
+        let mut decoded = messages.iter();
+        for packet in packets(encoder.as_bytes()) {
+            if let Some(event) = decoder.decode(packet) {
+                assert_eq!(event.cable, 1);
+                assert_eq!(event.bytes, *decoded.next().unwrap());
+            }
+        }
+        assert!(decoded.next().is_none());
+    }
+
+    #[test]
+    fn real_time_messages_interrupt_sysex() {
+        let mut decoder = Decoder::<16>::new();
+        assert_eq!(decoder.decode(&[0x04, 0xF0, 0x7D, 0x01]), None);
+        assert_eq!(
+            decoder.decode(&[0x0F, 0xF8, 0, 0]).map(|event| event.bytes),
+            Some(&[0xF8][..])
+        );
+        assert_eq!(
+            decoder
+                .decode(&[0x06, 0x02, 0xF7, 0])
+                .map(|event| event.bytes),
+            Some(&[0xF0, 0x7D, 0x01, 0x02, 0xF7][..])
+        );
+    }
+
+    #[test]
+    fn long_sysex_is_dropped() {
+        let mut decoder = Decoder::<4>::new();
+        assert_eq!(decoder.decode(&[0x04, 0xF0, 0x7D, 0x01]), None);
+        assert_eq!(decoder.decode(&[0x06, 0x02, 0xF7, 0]), None);
+        // The next message is still read
+        assert_eq!(
+            decoder
+                .decode(&[0x07, 0xF0, 0x7D, 0xF7])
+                .map(|event| event.bytes),
+            Some(&[0xF0, 0x7D, 0xF7][..])
+        );
+    }
+
+    #[test]
+    fn sysex_without_a_start_is_dropped() {
+        let mut decoder = Decoder::<16>::new();
+        assert_eq!(
+            decoder
+                .decode(&[0x07, 0xF0, 0x7D, 0xF7])
+                .map(|event| event.bytes),
+            Some(&[0xF0, 0x7D, 0xF7][..])
+        );
+        // The last message's start byte is still in the buffer but this isn't part of it
+        assert_eq!(decoder.decode(&[0x04, 0x7D, 0x01, 0x02]), None);
+        assert_eq!(decoder.decode(&[0x06, 0x03, 0xF7, 0]), None);
+        assert_eq!(decoder.decode(&[0x05, 0xF7, 0, 0]), None);
+        assert_eq!(
+            decoder
+                .decode(&[0x06, 0xF0, 0xF7, 0])
+                .map(|event| event.bytes),
+            Some(&[0xF0, 0xF7][..])
+        );
+    }
+
+    #[test]
+    fn events_parse_as_midi_messages() {
+        let mut decoder = Decoder::<16>::new();
+        let event = decoder.decode(&[0x0C, 0xC2, 7, 0]).unwrap();
+        assert_eq!(
+            event.message(),
+            Ok(MidiMessage::ProgramChange(
+                Channel::Ch3,
+                U7::from_u8_lossy(7)
+            ))
+        );
+    }
+
+    #[test]
+    fn reserved_packets_are_ignored() {
+        let mut decoder = Decoder::<16>::new();
+        assert_eq!(decoder.decode(&[0x00, 0, 0, 0]), None);
+        assert_eq!(decoder.decode(&[0x01, 0x90, 60, 100]), None);
+    }
+}
@@ -0,0 +1,41 @@
+use core::time::Duration;
+
+/// Travel time between the two thresholds at or below which a note gets full velocity.
+const FASTEST: Duration = Duration::from_millis(2);
+/// Travel time between the two thresholds at or above which a note gets the lowest velocity.
+const SLOWEST: Duration = Duration::from_millis(120);
+
+/// Maps how fast a key was pressed onto a MIDI velocity.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VelocityCurve {
+    /// Velocity is proportional to speed.
+    #[default]
+    Linear,
+    /// Gentle presses come out louder, good for a light touch.
+    Soft,
+    /// Hard presses are needed to get loud notes.
+    Hard,
+    /// Every note is sent with the same velocity.
+    Fixed(u8),
+}
+
+impl VelocityCurve {
+    /// Returns a velocity between 1 and 127 for a key that took `elapsed` to travel between the
+    /// start and press thresholds.
+    pub fn velocity(self, elapsed: Duration) -> u8 {
+        let elapsed = elapsed.clamp(FASTEST, SLOWEST);
+        // How fast the key was pressed, from 0.0 (slowest) to 1.0 (fastest)
+        let speed =
+            1.0 - (elapsed - FASTEST).as_micros() as f32 / (SLOWEST - FASTEST).as_micros() as f32;
+
+        let scaled = match self {
+            Self::Linear => speed,
+            Self::Soft => 1.0 - (1.0 - speed) * (1.0 - speed),
+            Self::Hard => speed * speed,
+            Self::Fixed(velocity) => return velocity.clamp(1, 127),
+        };
+
+        (1.0 + scaled * 126.0) as u8
+    }
+}
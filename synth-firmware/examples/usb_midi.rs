@@ -12,6 +12,7 @@ use embassy_time::{Duration, Timer};
 use embassy_usb::class::midi::MidiClass;
 use embassy_usb::driver::EndpointError;
 use embassy_usb::{Builder, Config};
+use synth_core::usb_midi::Encoder;
 use wmidi::{Channel, MidiMessage, Note, Velocity};
 use {defmt_rtt as _, panic_probe as _};
 
@@ -49,7 +50,6 @@ async fn main(_spawner: Spawner) {
 
     // Create embassy-usb DeviceBuilder using the driver and config.
     // It needs some buffers for building the descriptors.
-    let mut device_descriptor = [0; 256];
     let mut config_descriptor = [0; 256];
     let mut bos_descriptor = [0; 256];
     let mut control_buf = [0; MAX_PACKET_SIZE];
@@ -101,46 +101,26 @@ impl From<EndpointError> for Disconnected {
     }
 }
 
-fn midi_to_bytes(message: wmidi::MidiMessage<'_>) -> ([u8; MAX_PACKET_SIZE], usize) {
-    // For some reason the first byte has to be 0x8 or 0x9, otherwise the message is not sent.
-    let mut buffer = [0x8; MAX_PACKET_SIZE];
-    let n = message.bytes_size();
-    if n > buffer.len() {
-        panic!("MIDI message too large");
-    }
-    message.copy_to_slice(&mut buffer[1..n + 1]).unwrap();
-    (buffer, n + 1)
-}
-
-// Decoding messages from bytes.
-fn handle_midi_message(bytes: &[u8]) -> Result<MidiMessage, wmidi::FromBytesError> {
-    match wmidi::MidiMessage::try_from(bytes) {
-        Ok(message) => Ok(message),
-        Err(e) => Err(e),
-    }
-}
-
 async fn midi_echo<'d, T: Instance + 'd>(
     class: &mut MidiClass<'d, Driver<'d, T>>,
     led: &mut Output<'_>,
 ) -> Result<(), Disconnected> {
+    let mut encoder = Encoder::new();
     loop {
         led.set_high();
         defmt::info!("Sending MIDI message: NoteOn");
         let message = MidiMessage::NoteOn(Channel::Ch1, Note::C4, Velocity::MAX);
-        let (buffer, n) = midi_to_bytes(message);
-        let data = &buffer[0..n];
-        handle_midi_message(&data[1..]).unwrap();
-        class.write_packet(data).await?;
+        encoder.push_message(0, &message).unwrap();
+        class.write_packet(encoder.as_bytes()).await?;
+        encoder.clear();
         Timer::after(Duration::from_millis(1000)).await;
 
         led.set_low();
         defmt::info!("Sending MIDI message: NoteOff");
         let message = MidiMessage::NoteOff(Channel::Ch1, Note::C4, Velocity::MAX);
-        let (buffer, n) = midi_to_bytes(message);
-        let data = &buffer[0..n];
-
-        class.write_packet(data).await?;
+        encoder.push_message(0, &message).unwrap();
+        class.write_packet(encoder.as_bytes()).await?;
+        encoder.clear();
         Timer::after(Duration::from_millis(3000)).await;
     }
 }
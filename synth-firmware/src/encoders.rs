@@ -0,0 +1,41 @@
+use core::time::Duration;
+use embassy_rp::gpio::{Input, Pin, Pull};
+use embassy_rp::Peripheral;
+use synth_core::encoder::{Button, Quadrature};
+
+/// Number of encoders on the board.
+pub const NUM_ENCODERS: usize = 4;
+
+/// A rotary encoder with a push button. All three pins are pulled up and switched to ground, so
+/// a pressed button reads low.
+pub struct RotaryEncoder<'d> {
+    a: Input<'d>,
+    b: Input<'d>,
+    switch: Input<'d>,
+    quadrature: Quadrature,
+    button: Button,
+}
+
+impl<'d> RotaryEncoder<'d> {
+    pub fn new(
+        a: impl Peripheral<P = impl Pin> + 'd,
+        b: impl Peripheral<P = impl Pin> + 'd,
+        switch: impl Peripheral<P = impl Pin> + 'd,
+    ) -> Self {
+        Self {
+            a: Input::new(a, Pull::Up),
+            b: Input::new(b, Pull::Up),
+            switch: Input::new(switch, Pull::Up),
+            quadrature: Quadrature::default(),
+            button: Button::default(),
+        }
+    }
+
+    /// Reads the pins at `now`, returns the detents turned since the last poll and whether the
+    /// button was pressed or released.
+    pub fn poll(&mut self, now: Duration) -> (i8, Option<bool>) {
+        let delta = self.quadrature.update(self.a.is_low(), self.b.is_low());
+        let button = self.button.update(self.switch.is_low(), now);
+        (delta, button)
+    }
+}
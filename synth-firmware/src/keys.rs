@@ -0,0 +1,43 @@
+use analog_multiplexer::Output;
+use defmt::error;
+use embassy_rp::adc;
+use synth_core::key_map::NUM_BANKS;
+use synth_core::scanner::{Adc, Multiplexer};
+
+/// The multiplexer banks followed by the expression pedal jack.
+pub const NUM_INPUTS: usize = NUM_BANKS + 1;
+/// The input the expression pedal jack is read through.
+pub const EXPRESSION_INPUT: u8 = NUM_BANKS as u8;
+
+/// The ADC pins each bank's multiplexer common output is wired to, and the expression pedal jack.
+pub struct KeyAdc<'d> {
+    adc: adc::Adc<'d, adc::Async>,
+    channels: [adc::Channel<'d>; NUM_INPUTS],
+}
+
+impl<'d> KeyAdc<'d> {
+    pub fn new(adc: adc::Adc<'d, adc::Async>, channels: [adc::Channel<'d>; NUM_INPUTS]) -> Self {
+        Self { adc, channels }
+    }
+}
+
+impl Adc for KeyAdc<'_> {
+    async fn read(&mut self, bank: usize) -> Option<u16> {
+        match self.adc.read(&mut self.channels[bank]).await {
+            Ok(value) => Some(value),
+            Err(e) => {
+                error!("ADC read error: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Analog multiplexers driven by the shared select pins.
+pub struct KeyMultiplexer<P: Output>(pub analog_multiplexer::Multiplexer<P>);
+
+impl<P: Output> Multiplexer for KeyMultiplexer<P> {
+    fn set_channel(&mut self, channel: u8) {
+        self.0.set_channel(channel);
+    }
+}
@@ -2,28 +2,44 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
-mod key_code;
-mod key_map;
-mod state;
+mod encoders;
+mod keys;
+mod storage;
+mod sysex;
 
-use defmt::{error, info};
+use defmt::{error, info, warn};
 use defmt_rtt as _;
 use panic_probe as _;
 
-use key_code::KeyCode;
-use state::State;
+use encoders::{RotaryEncoder, NUM_ENCODERS};
+use keys::{KeyAdc, KeyMultiplexer, EXPRESSION_INPUT};
+use storage::Storage;
+use sysex::SysEx;
 
-use analog_multiplexer::{DummyPin, Multiplexer};
+use analog_multiplexer::DummyPin;
 use assign_resources::assign_resources;
 use embassy_executor::Spawner;
 use embassy_futures::join;
 use embassy_rp::{adc, bind_interrupts, gpio, peripherals, usb};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel;
-use embassy_time::{Duration, Timer};
-use embassy_usb::class::midi::MidiClass;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::midi::{MidiClass, Receiver, Sender};
+use embassy_usb::driver::EndpointError;
 use embassy_usb::{Builder, Config};
-use wmidi::{MidiMessage, U7};
+use synth_core::arpeggiator::ArpEvent;
+use synth_core::calibration::{self, Calibration};
+use synth_core::controller::{Controller, ControllerKind};
+use synth_core::key_code::{self, KeyCode};
+use synth_core::key_map::{self, Key, KeyMap};
+use synth_core::pressure::{self, PressureMode};
+use synth_core::scanner::{
+    Adc, KeyEvent, Multiplexer, Scanner, PRESS_THRESHOLD, RELEASE_THRESHOLD,
+};
+use synth_core::state::{SentNote, State};
+use synth_core::usb_midi::{self, Decoder, Encoder};
+use synth_core::velocity::VelocityCurve;
+use wmidi::{Channel, ControlFunction, MidiMessage, Note, U14, U7};
 
 assign_resources! {
     usb: UsbResources {
@@ -31,11 +47,26 @@ assign_resources! {
     }
     scan: ScanResources {
         adc: ADC,
+        flash: FLASH,
         SELECT_2: PIN_1,
         SELECT_3: PIN_2,
         SELECT_1: PIN_3,
         SELECT_0: PIN_4,
         AM1_COM: PIN_26,
+        AM2_COM: PIN_27,
+        EXPRESSION: PIN_28,
+        ENCODER_1_A: PIN_6,
+        ENCODER_1_B: PIN_7,
+        ENCODER_1_SW: PIN_8,
+        ENCODER_2_A: PIN_9,
+        ENCODER_2_B: PIN_10,
+        ENCODER_2_SW: PIN_11,
+        ENCODER_3_A: PIN_12,
+        ENCODER_3_B: PIN_13,
+        ENCODER_3_SW: PIN_14,
+        ENCODER_4_A: PIN_15,
+        ENCODER_4_B: PIN_16,
+        ENCODER_4_SW: PIN_17,
     }
 }
 
@@ -45,15 +76,84 @@ bind_interrupts!(struct Irqs {
 });
 
 const MAX_PACKET_SIZE: usize = 64;
-const DEBOUNCE_THRESHOLD: u16 = 50;
+/// Number of readings averaged to find a key's rest position when calibrating.
+const CALIBRATION_SAMPLES: u32 = 32;
+/// Note off velocity, the keys only measure how fast they are pressed.
+const RELEASE_VELOCITY: u8 = 64;
+/// Velocity used by the fixed velocity curve.
+const FIXED_VELOCITY: u8 = 100;
+/// Settings recalled by Program Change, indexed by program number.
+const PROGRAMS: [(VelocityCurve, PressureMode); 5] = [
+    (VelocityCurve::Linear, PressureMode::Off),
+    (VelocityCurve::Soft, PressureMode::Off),
+    (VelocityCurve::Hard, PressureMode::Off),
+    (VelocityCurve::Linear, PressureMode::PolyAftertouch),
+    (VelocityCurve::Linear, PressureMode::Mpe),
+];
+/// USB MIDI cable (virtual port) everything is sent on.
+const CABLE: u8 = 0;
+/// Controllers each encoder sends relative changes on, undefined in the MIDI spec so they are
+/// free to be mapped by the host.
+const ENCODER_CONTROLS: [u8; NUM_ENCODERS] = [20, 21, 22, 23];
+/// Controllers each encoder's button sends on, 127 when pressed and 0 when released.
+const BUTTON_CONTROLS: [u8; NUM_ENCODERS] = [24, 25, 26, 27];
+/// NRPN MSB the encoders use in NRPN mode, the LSB is the encoder's index.
+const ENCODER_NRPN_MSB: u8 = 0;
 
 enum Events {
-    KeyPressed(KeyCode, u8),
-    KeyReleased(KeyCode, u8),
+    NoteOn(Channel, Note, u8),
+    NoteOff(Channel, Note, u8),
+    KeyPressure(Channel, Note, u8),
+    ChannelPressure(Channel, u8),
+    ControlChange(Channel, ControlFunction, u8),
+    PitchBend(Channel, u16),
+    /// A complete SysEx message, including the start and end bytes.
+    SysEx(&'static [u8]),
+}
+
+impl Events {
+    /// Adds the event to the next USB transfer.
+    fn encode(&self, encoder: &mut Encoder) -> Result<(), usb_midi::Error> {
+        let message = match *self {
+            Events::NoteOn(channel, note, velocity) => {
+                MidiMessage::NoteOn(channel, note, U7::from_u8_lossy(velocity))
+            }
+            Events::NoteOff(channel, note, velocity) => {
+                MidiMessage::NoteOff(channel, note, U7::from_u8_lossy(velocity))
+            }
+            Events::KeyPressure(channel, note, pressure) => {
+                MidiMessage::PolyphonicKeyPressure(channel, note, U7::from_u8_lossy(pressure))
+            }
+            Events::ChannelPressure(channel, pressure) => {
+                MidiMessage::ChannelPressure(channel, U7::from_u8_lossy(pressure))
+            }
+            Events::ControlChange(channel, function, value) => {
+                MidiMessage::ControlChange(channel, function, U7::from_u8_lossy(value))
+            }
+            Events::PitchBend(channel, value) => {
+                MidiMessage::PitchBendChange(channel, U14::try_from(value).unwrap_or(U14::MAX))
+            }
+            Events::SysEx(bytes) => return encoder.push(CABLE, bytes),
+        };
+        encoder.push_message(CABLE, &message)
+    }
+}
+
+/// Changes requested by the host, applied by the scan task between scans.
+enum Commands {
+    Configure(SysEx),
+    ProgramChange(Channel, u8),
+    /// MIDI clock and transport, followed by the arpeggiator.
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
 }
 
 static EVENT_CHANNEL: channel::Channel<CriticalSectionRawMutex, Events, 10> =
     channel::Channel::new();
+static COMMAND_CHANNEL: channel::Channel<CriticalSectionRawMutex, Commands, 4> =
+    channel::Channel::new();
 
 #[embassy_executor::main]
 async fn main(s: Spawner) -> () {
@@ -68,10 +168,16 @@ async fn main(s: Spawner) -> () {
 }
 
 #[embassy_executor::task]
-async fn scan(_s: Spawner, r: ScanResources) -> () {
+async fn scan(_s: Spawner, r: ScanResources) {
     let mut state = State::new();
-    let mut adc = adc::Adc::new(r.adc, Irqs, Default::default());
-    let mut adc_channel = adc::Channel::new_pin(r.AM1_COM, gpio::Pull::Down);
+    let mut adc = KeyAdc::new(
+        adc::Adc::new(r.adc, Irqs, Default::default()),
+        [
+            adc::Channel::new_pin(r.AM1_COM, gpio::Pull::Down),
+            adc::Channel::new_pin(r.AM2_COM, gpio::Pull::Down),
+            adc::Channel::new_pin(r.EXPRESSION, gpio::Pull::Down),
+        ],
+    );
     let pins = (
         gpio::Output::new(r.SELECT_0, gpio::Level::Low),
         gpio::Output::new(r.SELECT_1, gpio::Level::Low),
@@ -79,54 +185,557 @@ async fn scan(_s: Spawner, r: ScanResources) -> () {
         gpio::Output::new(r.SELECT_3, gpio::Level::Low),
         DummyPin {},
     );
-    let mut multiplexer = Multiplexer::new(pins);
+    let mut multiplexer = KeyMultiplexer(analog_multiplexer::Multiplexer::new(pins));
+    let mut encoders = [
+        RotaryEncoder::new(r.ENCODER_1_A, r.ENCODER_1_B, r.ENCODER_1_SW),
+        RotaryEncoder::new(r.ENCODER_2_A, r.ENCODER_2_B, r.ENCODER_2_SW),
+        RotaryEncoder::new(r.ENCODER_3_A, r.ENCODER_3_B, r.ENCODER_3_SW),
+        RotaryEncoder::new(r.ENCODER_4_A, r.ENCODER_4_B, r.ENCODER_4_SW),
+    ];
+
+    let mut storage = Storage::new(r.flash);
+    let mut calibration = load_calibration(&mut storage);
+    let mut key_map = load_key_map(&mut storage);
+
+    // Holding shift at boot enters calibration mode
+    let shift = KeyCode::SHIFT as usize;
+    if let Some(shift_key) = key_map.find(KeyCode::SHIFT) {
+        multiplexer.set_channel(shift_key.channel);
+        let position = adc
+            .read(usize::from(shift_key.bank))
+            .await
+            .unwrap_or(calibration.rest[shift]);
+        if calibration.travel(shift, position) >= PRESS_THRESHOLD {
+            calibration = calibrate(
+                &mut adc,
+                &mut multiplexer,
+                &key_map,
+                shift_key,
+                &calibration,
+            )
+            .await;
+            match storage.write(storage::CALIBRATION_OFFSET, &calibration.to_bytes()) {
+                Ok(_) => info!("Saved calibration: {}", calibration),
+                Err(e) => error!("Failed to save calibration: {:?}", e),
+            }
+        }
+    }
+    let mut scanner = Scanner::new(calibration);
+
+    // The controllers are assumed to be at rest at boot, which centres the pitch bend
+    let mut controllers = [
+        Controller::new(ControllerKind::PitchBend, 0, Some(3)),
+        Controller::new(ControllerKind::ModWheel, 1, Some(11)),
+        Controller::new(ControllerKind::Expression, EXPRESSION_INPUT, None),
+    ];
+    for controller in &mut controllers {
+        if let Some(channel) = controller.channel {
+            multiplexer.set_channel(channel);
+        }
+        if let Some(reading) = adc.read(usize::from(controller.bank)).await {
+            controller.calibrate(reading);
+        }
+    }
+
     loop {
-        for key in key_map::LEFT_KEYS.iter() {
-            multiplexer.set_channel(key.channel);
+        while let Ok(command) = COMMAND_CHANNEL.try_receive() {
+            let new_map = match command {
+                Commands::Configure(SysEx::SetKeyMap(map)) => map,
+                Commands::Configure(SysEx::ResetKeyMap) => KeyMap::default(),
+                command => {
+                    apply(&mut state, command).await;
+                    continue;
+                }
+            };
+            set_key_map(&mut state, &mut scanner, &mut key_map, new_map).await;
+            match storage.write(storage::KEY_MAP_OFFSET, &key_map.to_bytes()) {
+                Ok(_) => info!("Saved key map: {}", key_map),
+                Err(e) => error!("Failed to save key map: {:?}", e),
+            }
+        }
 
-            let previous = state.positions[key.code as usize];
-            let initial = match adc.read(&mut adc_channel).await {
-                Ok(value) => value,
-                Err(e) => {
-                    error!("ADC read error: {:?}", e);
-                    2000
+        while let Some(event) = state.arpeggiator.poll(now(), state.channel) {
+            send_arpeggiator(event).await;
+        }
+
+        for key in key_map.keys() {
+            match scanner.read(key, &mut adc, &mut multiplexer, now()).await {
+                Some(KeyEvent::Pressed { code, elapsed }) => {
+                    let velocity = state.velocity_curve.velocity(elapsed);
+                    press(&mut state, code, velocity).await;
                 }
+                Some(KeyEvent::Released { code }) => {
+                    release(&mut state, code, RELEASE_VELOCITY).await
+                }
+                Some(KeyEvent::Held { code, travel }) => send_pressure(&mut state, code, travel),
+                None => {}
+            }
+        }
+
+        for controller in &mut controllers {
+            // A key map can put keys on the controllers' channels, then the key wins
+            if controller
+                .channel
+                .is_some_and(|channel| key_map.is_wired(controller.bank, channel))
+            {
+                continue;
+            }
+            if let Some(value) = controller.read(&mut adc, &mut multiplexer).await {
+                send_controller(&state, controller.kind, value).await;
+            }
+        }
+
+        for (index, encoder) in encoders.iter_mut().enumerate() {
+            let (delta, button) = encoder.poll(now());
+            if delta != 0 {
+                send_encoder(&state, index, delta).await;
+            }
+            if let Some(pressed) = button {
+                EVENT_CHANNEL
+                    .send(Events::ControlChange(
+                        midi_channel(state.channel),
+                        ControlFunction::from(U7::from_u8_lossy(BUTTON_CONTROLS[index])),
+                        if pressed { 127 } else { 0 },
+                    ))
+                    .await;
+            }
+        }
+    }
+}
+
+/// Time since boot, the clock the core crate measures key travel with.
+fn now() -> core::time::Duration {
+    core::time::Duration::from_micros(Instant::now().as_micros())
+}
+
+fn load_calibration(storage: &mut Storage) -> Calibration {
+    let mut bytes = [0; calibration::SERIALIZED_SIZE];
+    if let Err(e) = storage.read(storage::CALIBRATION_OFFSET, &mut bytes) {
+        error!("Failed to read calibration: {:?}", e);
+    }
+    match Calibration::from_bytes(&bytes) {
+        Some(calibration) => {
+            info!("Loaded calibration: {}", calibration);
+            calibration
+        }
+        None => {
+            warn!("No calibration found, using defaults");
+            Calibration::default()
+        }
+    }
+}
+
+fn load_key_map(storage: &mut Storage) -> KeyMap {
+    let mut bytes = [0; key_map::SERIALIZED_SIZE];
+    if let Err(e) = storage.read(storage::KEY_MAP_OFFSET, &mut bytes) {
+        error!("Failed to read key map: {:?}", e);
+    }
+    match KeyMap::from_bytes(&bytes) {
+        Some(key_map) => {
+            info!("Loaded key map: {}", key_map);
+            key_map
+        }
+        None => {
+            info!("No key map found, using the default");
+            KeyMap::default()
+        }
+    }
+}
+
+/// Switches to a new key map. Held keys that are no longer on the same channel are released so
+/// their notes don't get stuck.
+async fn set_key_map(state: &mut State, scanner: &mut Scanner, key_map: &mut KeyMap, new: KeyMap) {
+    for key in key_map.keys() {
+        if scanner.is_pressed(key.code) && new.find(key.code) != Some(key) {
+            scanner.reset(key.code);
+            release(state, key.code, RELEASE_VELOCITY).await;
+        }
+    }
+    *key_map = new;
+}
+
+/// Records the rest and bottom positions of every key.
+///
+/// Release shift once calibration has started so every key can be sampled at rest, then press
+/// each key all the way down. Press and release shift to finish. Keys that were never pressed
+/// keep their `previous` calibration.
+async fn calibrate<A: Adc, M: Multiplexer>(
+    adc: &mut A,
+    multiplexer: &mut M,
+    key_map: &KeyMap,
+    shift_key: &Key,
+    previous: &Calibration,
+) -> Calibration {
+    let shift = KeyCode::SHIFT as usize;
+    let mut calibration = previous.clone();
+
+    let shift_bank = usize::from(shift_key.bank);
+
+    info!("Calibrating, release shift...");
+    multiplexer.set_channel(shift_key.channel);
+    while previous.travel(
+        shift,
+        adc.read(shift_bank).await.unwrap_or(previous.rest[shift]),
+    ) >= RELEASE_THRESHOLD
+    {
+        Timer::after(Duration::from_millis(10)).await;
+    }
+    Timer::after(Duration::from_millis(500)).await;
+
+    for key in key_map.keys() {
+        multiplexer.set_channel(key.channel);
+        let index = key.code as usize;
+        let mut sum: u32 = 0;
+        for _ in 0..CALIBRATION_SAMPLES {
+            sum += u32::from(
+                adc.read(usize::from(key.bank))
+                    .await
+                    .unwrap_or(previous.rest[index]),
+            );
+        }
+        calibration.rest[index] = (sum / CALIBRATION_SAMPLES) as u16;
+        calibration.bottom[index] = calibration.rest[index];
+    }
+
+    info!("Press every key all the way down, then press and release shift to finish...");
+    let mut shift_pressed = false;
+    loop {
+        for key in key_map.keys() {
+            multiplexer.set_channel(key.channel);
+            let index = key.code as usize;
+            let position = adc
+                .read(usize::from(key.bank))
+                .await
+                .unwrap_or(calibration.rest[index]);
+            calibration.bottom[index] = calibration.bottom[index].min(position);
+        }
+
+        let travel = calibration.rest[shift].saturating_sub(calibration.bottom[shift]);
+        multiplexer.set_channel(shift_key.channel);
+        let position = adc
+            .read(shift_bank)
+            .await
+            .unwrap_or(calibration.rest[shift]);
+        let depth = calibration.rest[shift].saturating_sub(position);
+        if depth >= calibration::MIN_RANGE {
+            shift_pressed = true;
+        } else if shift_pressed && depth < travel / 4 {
+            break;
+        }
+    }
+
+    for index in 0..key_code::NUM_KEYS {
+        if !calibration.is_valid(index) {
+            warn!(
+                "Key {} was not pressed, keeping previous calibration",
+                index
+            );
+            calibration.rest[index] = previous.rest[index];
+            calibration.bottom[index] = previous.bottom[index];
+        }
+    }
+    calibration
+}
+
+/// Function keys change the octave or transpose, note keys send a note on for the current octave.
+/// Holding shift turns some of the note keys into function keys too.
+async fn press(state: &mut State, key: KeyCode, velocity: u8) {
+    match key {
+        KeyCode::SHIFT => state.shift = true,
+        KeyCode::SUSTAIN => {
+            EVENT_CHANNEL
+                .send(Events::ControlChange(
+                    midi_channel(state.channel),
+                    ControlFunction::DAMPER_PEDAL,
+                    127,
+                ))
+                .await
+        }
+        KeyCode::PANIC => {
+            if let Some(sent) = state.arpeggiator.clear() {
+                send_arpeggiator(ArpEvent::NoteOff(sent)).await;
+            }
+            for channel in 0..16 {
+                EVENT_CHANNEL
+                    .send(Events::ControlChange(
+                        midi_channel(channel),
+                        ControlFunction::ALL_NOTES_OFF,
+                        0,
+                    ))
+                    .await;
+            }
+            info!("All notes off");
+        }
+        KeyCode::UP | KeyCode::DOWN => {
+            match (key, state.shift) {
+                (KeyCode::UP, false) => state.octave_up(),
+                (KeyCode::DOWN, false) => state.octave_down(),
+                (KeyCode::UP, true) => state.transpose_up(),
+                _ => state.transpose_down(),
+            }
+            info!("Octave: {}, transpose: {}", state.octave, state.transpose);
+        }
+        KeyCode::C1 | KeyCode::D1 | KeyCode::E1 | KeyCode::F1 if state.shift => {
+            state.velocity_curve = match key {
+                KeyCode::C1 => VelocityCurve::Linear,
+                KeyCode::D1 => VelocityCurve::Soft,
+                KeyCode::E1 => VelocityCurve::Hard,
+                _ => VelocityCurve::Fixed(FIXED_VELOCITY),
             };
-            if initial.abs_diff(previous) > DEBOUNCE_THRESHOLD {
-                Timer::after(Duration::from_millis(10)).await;
-                let next = match adc.read(&mut adc_channel).await {
-                    Ok(value) => value,
-                    Err(e) => {
-                        error!("ADC read error: {:?}", e);
-                        2000
-                    }
-                };
-                if next.abs_diff(previous) > DEBOUNCE_THRESHOLD {
-                    let velocity = next.abs_diff(previous).clamp(0, 127) as u8;
-                    if previous > next {
-                        if !state.notes_on[key.code as usize] {
-                            state.notes_on[key.code as usize] = true;
-                            EVENT_CHANNEL
-                                .send(Events::KeyPressed(key.code, velocity))
-                                .await;
-                        }
-                    } else {
-                        if state.notes_on[key.code as usize] {
-                            state.notes_on[key.code as usize] = false;
-                            EVENT_CHANNEL
-                                .send(Events::KeyReleased(key.code, velocity))
-                                .await;
-                        }
-                    }
+            info!("Velocity curve: {}", state.velocity_curve);
+        }
+        KeyCode::G1 if state.shift => set_pressure_mode(state, state.pressure_mode.next()).await,
+        KeyCode::A1 if state.shift => {
+            state.arpeggiator.enabled = !state.arpeggiator.enabled;
+            if !state.arpeggiator.enabled {
+                if let Some(sent) = state.arpeggiator.clear() {
+                    send_arpeggiator(ArpEvent::NoteOff(sent)).await;
                 }
-                state.positions[key.code as usize] = next;
+            }
+            info!("Arpeggiator: {}", state.arpeggiator.enabled);
+        }
+        KeyCode::B1 if state.shift => {
+            state.arpeggiator.pattern = state.arpeggiator.pattern.next();
+            info!("Arpeggiator pattern: {}", state.arpeggiator.pattern);
+        }
+        KeyCode::ASharp1 if state.shift => {
+            state.arpeggiator.next_octaves();
+            info!("Arpeggiator octaves: {}", state.arpeggiator.octaves);
+        }
+        KeyCode::GSharp1 if state.shift => {
+            state.arpeggiator.next_gate();
+            info!("Arpeggiator gate: {}%", state.arpeggiator.gate);
+        }
+        KeyCode::FSharp1 if state.shift => {
+            state.arpeggiator.clock = state.arpeggiator.clock.next();
+            info!("Arpeggiator clock: {}", state.arpeggiator.clock);
+        }
+        _ if state.arpeggiator.enabled => {
+            if let Some(note) = state.note(key) {
+                state.arpeggiator.press(key, note.into(), velocity);
+            }
+        }
+        _ => {
+            if let Some(note) = state.note(key) {
+                let channel = state.note_channel();
+                state.notes_sent[key as usize] = Some(SentNote {
+                    channel,
+                    note: note.into(),
+                });
+                state.pressures[key as usize] = 0;
+                EVENT_CHANNEL
+                    .send(Events::NoteOn(midi_channel(channel), note, velocity))
+                    .await;
             }
         }
     }
 }
 
+/// Changes the pressure mode, telling the host when MPE is turned on or off.
+async fn set_pressure_mode(state: &mut State, mode: PressureMode) {
+    let was_mpe = state.pressure_mode == PressureMode::Mpe;
+    state.pressure_mode = mode;
+    let is_mpe = state.pressure_mode == PressureMode::Mpe;
+    if was_mpe != is_mpe {
+        let members = if is_mpe {
+            pressure::MPE_LAST_CHANNEL - pressure::MPE_FIRST_CHANNEL + 1
+        } else {
+            0
+        };
+        send_mpe_configuration(members).await;
+    }
+    info!("Pressure mode: {}", state.pressure_mode);
+}
+
+/// Applies a setting sent by the host. Held keys keep the note and channel they were sent with.
+async fn apply(state: &mut State, command: Commands) {
+    match command {
+        Commands::Configure(SysEx::SetChannel(channel)) => {
+            state.channel = channel;
+            info!("Channel: {}", state.channel + 1);
+        }
+        Commands::Configure(SysEx::SetOctave(octave)) => {
+            state.set_octave(octave);
+            info!("Octave: {}", state.octave);
+        }
+        Commands::Configure(SysEx::SetVelocityCurve(curve)) => {
+            state.velocity_curve = curve;
+            info!("Velocity curve: {}", state.velocity_curve);
+        }
+        Commands::Configure(SysEx::SetTempo(tempo)) => {
+            state.arpeggiator.set_tempo(tempo);
+            info!("Tempo: {}", state.arpeggiator.tempo);
+        }
+        Commands::TimingClock => state.arpeggiator.clock_pulse(now()),
+        Commands::Start => state.arpeggiator.start(),
+        Commands::Continue => state.arpeggiator.resume(),
+        Commands::Stop => state.arpeggiator.stop(),
+        Commands::Configure(SysEx::SetEncoderMode(mode)) => {
+            state.encoder_mode = mode;
+            info!("Encoder mode: {}", state.encoder_mode);
+        }
+        // Answered by the USB task, key maps are changed by the scan loop
+        Commands::Configure(SysEx::IdentityRequest)
+        | Commands::Configure(SysEx::SetKeyMap(_))
+        | Commands::Configure(SysEx::ResetKeyMap) => {}
+        Commands::ProgramChange(channel, program) => {
+            if channel.index() != state.channel {
+                return;
+            }
+            let Some(&(curve, mode)) = PROGRAMS.get(usize::from(program)) else {
+                warn!("Unknown program: {}", program);
+                return;
+            };
+            state.velocity_curve = curve;
+            info!("Velocity curve: {}", state.velocity_curve);
+            set_pressure_mode(state, mode).await;
+        }
+    }
+}
+
+/// Sends the depth of a held key when it changes, at most once every `PRESSURE_INTERVAL`.
+/// Pressure is dropped rather than waited for if the channel is full, a later scan will catch up.
+fn send_pressure(state: &mut State, key: KeyCode, travel: u16) {
+    let index = key as usize;
+    let Some(sent) = state.notes_sent[index] else {
+        return;
+    };
+    let value = pressure::pressure(travel, PRESS_THRESHOLD);
+    if value == state.pressures[index]
+        || state.pressure_sent[index]
+            .is_some_and(|time| now().saturating_sub(time) < pressure::PRESSURE_INTERVAL)
+    {
+        return;
+    }
+
+    let channel = midi_channel(sent.channel);
+    let event = match state.pressure_mode {
+        PressureMode::Off => return,
+        PressureMode::PolyAftertouch => {
+            Events::KeyPressure(channel, Note::from_u8_lossy(sent.note), value)
+        }
+        PressureMode::Mpe => Events::ChannelPressure(channel, value),
+    };
+    if EVENT_CHANNEL.try_send(event).is_ok() {
+        state.pressures[index] = value;
+        state.pressure_sent[index] = Some(now());
+    }
+}
+
+/// Sends the MPE Configuration Message (RPN 6) for the lower zone with the given number of member
+/// channels, 0 turns MPE off.
+async fn send_mpe_configuration(members: u8) {
+    let manager = midi_channel(pressure::MPE_MANAGER_CHANNEL);
+    for (function, value) in [
+        (ControlFunction::REGISTERED_PARAMETER_NUMBER_MSB, 0),
+        (ControlFunction::REGISTERED_PARAMETER_NUMBER_LSB, 6),
+        (ControlFunction::DATA_ENTRY_MSB, members),
+    ] {
+        EVENT_CHANNEL
+            .send(Events::ControlChange(manager, function, value))
+            .await;
+    }
+}
+
+async fn send_arpeggiator(event: ArpEvent) {
+    let event = match event {
+        ArpEvent::NoteOn(sent, velocity) => Events::NoteOn(
+            midi_channel(sent.channel),
+            Note::from_u8_lossy(sent.note),
+            velocity,
+        ),
+        ArpEvent::NoteOff(sent) => Events::NoteOff(
+            midi_channel(sent.channel),
+            Note::from_u8_lossy(sent.note),
+            RELEASE_VELOCITY,
+        ),
+    };
+    EVENT_CHANNEL.send(event).await;
+}
+
+/// Sends a new value from an analog controller, on the manager channel in MPE mode so it applies
+/// to every note.
+async fn send_controller(state: &State, kind: ControllerKind, value: u16) {
+    let channel = midi_channel(state.control_channel());
+    let event = match kind {
+        ControllerKind::PitchBend => Events::PitchBend(channel, value),
+        ControllerKind::ModWheel => {
+            Events::ControlChange(channel, ControlFunction::MODULATION_WHEEL, value as u8)
+        }
+        ControllerKind::Expression => {
+            Events::ControlChange(channel, ControlFunction::EXPRESSION_CONTROLLER, value as u8)
+        }
+    };
+    EVENT_CHANNEL.send(event).await;
+}
+
+/// Sends a turn of an encoder as a relative CC, or in NRPN mode selects the encoder's parameter
+/// and steps it with data increment or decrement.
+async fn send_encoder(state: &State, index: usize, delta: i8) {
+    let channel = midi_channel(state.channel);
+    if let Some(value) = state.encoder_mode.relative_value(delta) {
+        let function = ControlFunction::from(U7::from_u8_lossy(ENCODER_CONTROLS[index]));
+        EVENT_CHANNEL
+            .send(Events::ControlChange(channel, function, value))
+            .await;
+        return;
+    }
+
+    let step = if delta > 0 {
+        ControlFunction::DATA_INCREMENT
+    } else {
+        ControlFunction::DATA_DECREMENT
+    };
+    for (function, value) in [
+        (
+            ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_MSB,
+            ENCODER_NRPN_MSB,
+        ),
+        (
+            ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_LSB,
+            index as u8,
+        ),
+        (step, delta.unsigned_abs()),
+    ] {
+        EVENT_CHANNEL
+            .send(Events::ControlChange(channel, function, value))
+            .await;
+    }
+}
+
+fn midi_channel(index: u8) -> Channel {
+    Channel::from_index(index).unwrap_or(Channel::Ch1)
+}
+
+/// Sends a note off for the note that was sent when the key was pressed, not the note it would
+/// play now, so changing octave while a key is held doesn't leave a stuck note.
+async fn release(state: &mut State, key: KeyCode, velocity: u8) {
+    match key {
+        KeyCode::SHIFT => state.shift = false,
+        KeyCode::SUSTAIN => {
+            EVENT_CHANNEL
+                .send(Events::ControlChange(
+                    midi_channel(state.channel),
+                    ControlFunction::DAMPER_PEDAL,
+                    0,
+                ))
+                .await
+        }
+        _ => state.arpeggiator.release(key),
+    }
+    if let Some(sent) = state.notes_sent[key as usize].take() {
+        EVENT_CHANNEL
+            .send(Events::NoteOff(
+                midi_channel(sent.channel),
+                Note::from_u8_lossy(sent.note),
+                velocity,
+            ))
+            .await;
+    }
+}
+
 #[embassy_executor::task]
-async fn usb_midi(_s: Spawner, r: UsbResources) -> () {
+async fn usb_midi(_s: Spawner, r: UsbResources) {
     // Create embassy-usb Config
     let mut config = Config::new(0xc0de, 0xcafe);
     config.manufacturer = Some("paullj");
@@ -159,56 +768,99 @@ async fn usb_midi(_s: Spawner, r: UsbResources) -> () {
     );
 
     // Create classes on the builder.
-    let mut midi_class = MidiClass::new(&mut builder, 1, 1, 64);
+    let (sender, receiver) = MidiClass::new(&mut builder, 1, 1, 64).split();
     // Build the builder.
     let mut usb_device = builder.build();
     // Run the USB device.
     let usb_future = usb_device.run();
     // Use the Midi class.
-    let midi_future = async {
-        midi_class.wait_connection().await;
+    let midi_future = join::join(write_midi(sender), read_midi(receiver));
+
+    join::join(usb_future, midi_future).await;
+    usb_device.disable().await;
+}
+
+/// Writes events from the scan task to the host, events that are already waiting are sent
+/// together in one transfer.
+async fn write_midi<'d>(mut sender: Sender<'d, usb::Driver<'d, peripherals::USB>>) {
+    sender.wait_connection().await;
 
-        let receiver = EVENT_CHANNEL.receiver();
+    let receiver = EVENT_CHANNEL.receiver();
+    let mut encoder = Encoder::new();
+    loop {
+        let mut event = receiver.receive().await;
         loop {
-            let event = receiver.receive().await;
-            match event {
-                Events::KeyPressed(key, velocity) => {
-                    if let Some(note) = key.to_note(3) {
-                        let message = MidiMessage::NoteOn(
-                            wmidi::Channel::Ch1,
-                            note,
-                            U7::from_u8_lossy(velocity),
-                        );
-                        let (buffer, n) = midi_to_bytes(message);
-                        let _ = midi_class.write_packet(&buffer[..n]).await;
-                    }
-                }
-                Events::KeyReleased(key, velocity) => {
-                    if let Some(note) = key.to_note(3) {
-                        let message = MidiMessage::NoteOff(
-                            wmidi::Channel::Ch1,
-                            note,
-                            U7::from_u8_lossy(velocity),
-                        );
-                        let (buffer, n) = midi_to_bytes(message);
-                        let _ = midi_class.write_packet(&buffer[..n]).await;
-                    }
+            match event.encode(&mut encoder) {
+                Err(usb_midi::Error::Full) if !encoder.is_empty() => {
+                    flush(&mut sender, &mut encoder).await;
+                    continue;
                 }
+                Err(_) => warn!("Failed to encode event"),
+                Ok(_) => {}
+            }
+            match receiver.try_receive() {
+                Ok(next) => event = next,
+                Err(_) => break,
             }
         }
-    };
+        flush(&mut sender, &mut encoder).await;
+    }
+}
 
-    join::join(usb_future, midi_future).await;
-    usb_device.disable().await;
+async fn flush<'d>(
+    sender: &mut Sender<'d, usb::Driver<'d, peripherals::USB>>,
+    encoder: &mut Encoder,
+) {
+    if let Err(e) = sender.write_packet(encoder.as_bytes()).await {
+        warn!("USB write error: {:?}", e);
+    }
+    encoder.clear();
 }
 
-fn midi_to_bytes(message: wmidi::MidiMessage<'_>) -> ([u8; MAX_PACKET_SIZE], usize) {
-    // For some reason the first byte has to be 0x8 or 0x9, otherwise the message is not sent.
-    let mut buffer = [0x8; MAX_PACKET_SIZE];
-    let n = message.bytes_size();
-    if n > buffer.len() {
-        panic!("MIDI message too large");
+/// Reads messages from the host.
+async fn read_midi<'d>(mut receiver: Receiver<'d, usb::Driver<'d, peripherals::USB>>) {
+    let mut buffer = [0; usb_midi::MAX_TRANSFER_SIZE];
+    let mut decoder = Decoder::<{ sysex::MAX_SIZE }>::new();
+    loop {
+        receiver.wait_connection().await;
+        loop {
+            let n = match receiver.read_packet(&mut buffer).await {
+                Ok(n) => n,
+                Err(EndpointError::Disabled) => break,
+                Err(e) => {
+                    warn!("USB read error: {:?}", e);
+                    continue;
+                }
+            };
+
+            for packet in buffer[..n].chunks_exact(usb_midi::PACKET_SIZE) {
+                let Ok(packet) = packet.try_into() else {
+                    continue;
+                };
+                if let Some(event) = decoder.decode(packet) {
+                    handle_midi_message(event.bytes).await;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_midi_message(bytes: &[u8]) {
+    match MidiMessage::try_from(bytes) {
+        Ok(MidiMessage::ProgramChange(channel, program)) => {
+            COMMAND_CHANNEL
+                .send(Commands::ProgramChange(channel, program.into()))
+                .await
+        }
+        Ok(MidiMessage::TimingClock) => COMMAND_CHANNEL.send(Commands::TimingClock).await,
+        Ok(MidiMessage::Start) => COMMAND_CHANNEL.send(Commands::Start).await,
+        Ok(MidiMessage::Continue) => COMMAND_CHANNEL.send(Commands::Continue).await,
+        Ok(MidiMessage::Stop) => COMMAND_CHANNEL.send(Commands::Stop).await,
+        Ok(MidiMessage::SysEx(_)) => match SysEx::parse(bytes) {
+            Ok(SysEx::IdentityRequest) => EVENT_CHANNEL.send(Events::SysEx(&sysex::IDENTITY)).await,
+            Ok(sysex) => COMMAND_CHANNEL.send(Commands::Configure(sysex)).await,
+            Err(e) => warn!("Rejected SysEx {:x}: {}", bytes, e),
+        },
+        _ => {}
     }
-    message.copy_to_slice(&mut buffer[1..n + 1]).unwrap();
-    (buffer, n + 1)
 }
@@ -0,0 +1,34 @@
+use embassy_rp::flash::{Blocking, Error, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+
+/// Size of the flash on the board.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Settings are kept in the last sectors of flash, which `memory.x` leaves out of the firmware.
+/// Each setting gets its own sector so it can be erased and rewritten on its own.
+pub const CALIBRATION_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+pub const KEY_MAP_OFFSET: u32 = (FLASH_SIZE - 2 * ERASE_SIZE) as u32;
+
+/// Persists settings to the RP2040's flash.
+pub struct Storage<'d> {
+    flash: Flash<'d, FLASH, Blocking, FLASH_SIZE>,
+}
+
+impl<'d> Storage<'d> {
+    pub fn new(flash: FLASH) -> Self {
+        Self {
+            flash: Flash::new_blocking(flash),
+        }
+    }
+
+    pub fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        self.flash.blocking_read(offset, bytes)
+    }
+
+    /// Erases the sector at `offset` and writes `bytes` to the start of it.
+    pub fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+        self.flash.blocking_write(offset, bytes)
+    }
+}
@@ -0,0 +1,117 @@
+use defmt::Format;
+use synth_core::encoder::EncoderMode;
+use synth_core::key_map::{self, KeyMap, MAX_KEYS};
+use synth_core::velocity::VelocityCurve;
+
+const START: u8 = 0xF0;
+const END: u8 = 0xF7;
+/// Manufacturer ID reserved for non-commercial use.
+pub const MANUFACTURER_ID: u8 = 0x7D;
+const UNIVERSAL_NON_REALTIME: u8 = 0x7E;
+/// Device ID that every device responds to.
+const ALL_DEVICES: u8 = 0x7F;
+const GENERAL_INFORMATION: u8 = 0x06;
+const IDENTITY_REQUEST: u8 = 0x01;
+const IDENTITY_REPLY: u8 = 0x02;
+
+const SET_CHANNEL: u8 = 0x01;
+const SET_OCTAVE: u8 = 0x02;
+const SET_VELOCITY_CURVE: u8 = 0x03;
+const SET_KEY_MAP: u8 = 0x04;
+const RESET_KEY_MAP: u8 = 0x05;
+const SET_ENCODER_MODE: u8 = 0x06;
+const SET_TEMPO: u8 = 0x07;
+
+/// Longest message the keyboard understands, a key map with every channel in use.
+pub const MAX_SIZE: usize = 4 + MAX_KEYS * 3;
+
+/// Reply to a Universal SysEx Device Inquiry: manufacturer, family, model and firmware version.
+pub const IDENTITY: [u8; 15] = [
+    START,
+    UNIVERSAL_NON_REALTIME,
+    ALL_DEVICES,
+    GENERAL_INFORMATION,
+    IDENTITY_REPLY,
+    MANUFACTURER_ID,
+    0x00, // Family
+    0x00,
+    0x01, // Model
+    0x00,
+    0x00, // Version
+    0x01,
+    0x00,
+    0x00,
+    END,
+];
+
+/// SysEx messages the keyboard understands.
+///
+/// Configuration messages are `F0 7D <command> <data...> F7`:
+/// - `01 <channel 0-15>` sets the MIDI channel
+/// - `02 <octave 1-8>` sets the octave
+/// - `03 <curve>` sets the velocity curve, 0 linear, 1 soft, 2 hard, 3 fixed followed by the
+///   velocity
+/// - `04 [<bank> <channel> <key code>]...` replaces the key map
+/// - `05` restores the default key map
+/// - `06 <mode>` sets what the encoders send, 0 two's complement relative CC, 1 binary offset
+///   relative CC, 2 NRPN increment and decrement
+/// - `07 <tempo MSB> <tempo LSB>` sets the arpeggiator's internal tempo in BPM, sent as two 7 bit
+///   bytes
+pub enum SysEx {
+    IdentityRequest,
+    SetChannel(u8),
+    SetOctave(u8),
+    SetVelocityCurve(VelocityCurve),
+    SetKeyMap(KeyMap),
+    ResetKeyMap,
+    SetEncoderMode(EncoderMode),
+    SetTempo(u16),
+}
+
+#[derive(Format)]
+pub enum Error {
+    Unknown,
+    InvalidKeyMap(key_map::Error),
+}
+
+impl SysEx {
+    /// Parses a complete SysEx message, including the start and end bytes.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let sysex = match bytes {
+            [START, UNIVERSAL_NON_REALTIME, _, GENERAL_INFORMATION, IDENTITY_REQUEST, END] => {
+                Some(Self::IdentityRequest)
+            }
+            [START, MANUFACTURER_ID, SET_CHANNEL, channel, END] if *channel < 16 => {
+                Some(Self::SetChannel(*channel))
+            }
+            [START, MANUFACTURER_ID, SET_OCTAVE, octave, END] => Some(Self::SetOctave(*octave)),
+            [START, MANUFACTURER_ID, SET_VELOCITY_CURVE, 0, END] => {
+                Some(Self::SetVelocityCurve(VelocityCurve::Linear))
+            }
+            [START, MANUFACTURER_ID, SET_VELOCITY_CURVE, 1, END] => {
+                Some(Self::SetVelocityCurve(VelocityCurve::Soft))
+            }
+            [START, MANUFACTURER_ID, SET_VELOCITY_CURVE, 2, END] => {
+                Some(Self::SetVelocityCurve(VelocityCurve::Hard))
+            }
+            [START, MANUFACTURER_ID, SET_VELOCITY_CURVE, 3, velocity, END] => {
+                Some(Self::SetVelocityCurve(VelocityCurve::Fixed(*velocity)))
+            }
+            [START, MANUFACTURER_ID, SET_KEY_MAP, keys @ .., END] => Some(Self::SetKeyMap(
+                KeyMap::from_triplets(keys).map_err(Error::InvalidKeyMap)?,
+            )),
+            [START, MANUFACTURER_ID, RESET_KEY_MAP, END] => Some(Self::ResetKeyMap),
+            [START, MANUFACTURER_ID, SET_ENCODER_MODE, mode, END] => match mode {
+                0 => Some(Self::SetEncoderMode(EncoderMode::TwosComplement)),
+                1 => Some(Self::SetEncoderMode(EncoderMode::BinaryOffset)),
+                2 => Some(Self::SetEncoderMode(EncoderMode::Nrpn)),
+                _ => None,
+            },
+            [START, MANUFACTURER_ID, SET_TEMPO, msb, lsb, END] => {
+                Some(Self::SetTempo(u16::from(*msb) << 7 | u16::from(*lsb)))
+            }
+            _ => None,
+        };
+        sysex.ok_or(Error::Unknown)
+    }
+}